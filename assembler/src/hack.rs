@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use crate::sym_table::SymTable;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Ins {
     AIns {
         symbol: String,
@@ -79,6 +80,13 @@ lazy_static! {
             ("D&M".to_owned(), "1000000"),
             ("D|A".to_owned(), "0010101"),
             ("D|M".to_owned(), "1010101"),
+            // Commutative forms, as accepted by the assembler supplied with the course
+            ("A+D".to_owned(), "0000010"),
+            ("M+D".to_owned(), "1000010"),
+            ("A&D".to_owned(), "0000000"),
+            ("M&D".to_owned(), "1000000"),
+            ("A|D".to_owned(), "0010101"),
+            ("M|D".to_owned(), "1010101"),
         ].iter().cloned().collect()
     };
 
@@ -0,0 +1,33 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod hack;
+pub mod parser;
+pub mod sym_table;
+
+use hack::Ins;
+use parser::Parser;
+use std::io::Read;
+use sym_table::SymTable;
+
+// Assembles Hack assembly code into machine instructions. The symbol table is returned
+// along with them, for looking up the addresses given to variables.
+pub fn assemble<R: Read>(asm: R) -> parser::Result<(Vec<u16>, SymTable)> {
+    let mut parser = Parser::new(asm);
+    let mut instructions: Vec<Ins> = vec![];
+    while parser.has_more_ins() {
+        instructions.push(parser.get_next_ins()?);
+    }
+
+    let mut sym_table = SymTable::new();
+    sym_table.load_labels(&instructions);
+
+    let mut machine_code = vec![];
+    for ins in instructions {
+        if let Ins::LIns {..} = ins {
+            continue;
+        }
+        machine_code.push(u16::from_str_radix(&ins.to_bin_str(&mut sym_table), 2).unwrap());
+    }
+    Ok((machine_code, sym_table))
+}
@@ -1,6 +1,6 @@
 use std::io;
 use std::io::Lines;
-use std::fs::File;
+use std::io::Read;
 use std::io::BufReader;
 use std::io::BufRead;
 use std::fmt;
@@ -23,14 +23,14 @@ impl fmt::Display for ParseError {
 
 impl Error for ParseError {}
 
-pub struct Parser {
-    lines: Lines<BufReader<File>>,
+pub struct Parser<R: Read> {
+    lines: Lines<BufReader<R>>,
     current_line: Option<io::Result<String>>,
 }
 
-impl Parser {
-    pub fn new(file: File) -> Self {
-        let mut lines = BufReader::new(file).lines();
+impl<R: Read> Parser<R> {
+    pub fn new(asm: R) -> Self {
+        let mut lines = BufReader::new(asm).lines();
         let current_line = lines.next();
         Parser {
             lines,
@@ -60,10 +60,9 @@ impl Parser {
     pub fn get_next_ins(&mut self) -> Result<Ins> {
         let current_line = self.current_line.take().unwrap();
         self.current_line = self.lines.next();
-        if current_line.is_ok() {
-            Self::to_ins(current_line.unwrap())
-        } else {
-            Result::Err(ParseError {message: current_line.unwrap_err().to_string()})
+        match current_line {
+            Ok(line) => Self::to_ins(line),
+            Err(error) => Result::Err(ParseError {message: error.to_string()}),
         }
     }
 
@@ -80,14 +79,14 @@ impl Parser {
                     .to_string()
             })
 
-        } else if let Some(_) = ins.find("=") {
+        } else if ins.contains('=') {
             let mut tokens = ins.split("=");
             let dest = Some(tokens.nth(0).unwrap().to_string());
             let comp = tokens.nth(0).unwrap().to_string();
             let jump = None;
             Ok(Ins::CIns { dest, comp, jump })
 
-        } else if let Some(_) = ins.find(";") {
+        } else if ins.contains(';') {
             let mut tokens = ins.split(";");
             let dest = None;
             let comp = tokens.nth(0).unwrap().to_string();
@@ -6,6 +6,12 @@ pub struct SymTable {
     table: HashMap<String, i16>,
 }
 
+impl Default for SymTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymTable {
     pub fn new() -> Self {
         SymTable {
@@ -49,6 +55,10 @@ impl SymTable {
         }
     }
 
+    pub fn lookup(&self, symbol: &str) -> Option<i16> {
+        self.table.get(symbol).cloned()
+    }
+
     pub fn get_address(&mut self, variable: &str) -> i16 {
         if !self.table.contains_key(variable) {
             self.table.insert(variable.to_owned(), self.free_ram_address);
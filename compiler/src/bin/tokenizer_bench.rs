@@ -0,0 +1,113 @@
+extern crate compiler;
+
+use compiler::compile_engine::CompileEngine;
+use compiler::tokenizer::Tokens;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// The Jack sources of the repository, the OS of project 12 included
+const DEFAULT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../projects");
+
+// Times tokenizing and parsing every .jack file under the given directories, taking the
+// best of a number of runs
+fn main() -> io::Result<()> {
+    let mut iterations = 20;
+    let mut input_paths: Vec<PathBuf> = vec![];
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-n") | Some("--iterations") => {
+                iterations = args
+                    .next()
+                    .and_then(|arg| arg.to_str().and_then(|arg| arg.parse().ok()))
+                    .filter(|&iterations| iterations > 0)
+                    .unwrap_or_else(|| usage())
+            }
+            Some(flag) if flag.starts_with('-') => usage(),
+            _ => input_paths.push(arg.into()),
+        }
+    }
+    if input_paths.is_empty() {
+        input_paths.push(DEFAULT_DIR.into());
+    }
+
+    let mut jack_paths = vec![];
+    for input_path in &input_paths {
+        find_jack_files(input_path.clone(), &mut jack_paths)?;
+    }
+    let sources = jack_paths
+        .iter()
+        .map(|jack_path| {
+            fs::read(jack_path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        })
+        .collect::<io::Result<Vec<String>>>()?;
+    let bytes: usize = sources.iter().map(|source| source.len()).sum();
+
+    let mut token_count = 0;
+    let tokenize_time = best_time(iterations, || {
+        token_count = sources
+            .iter()
+            .map(|source| Tokens::tokenize(source).count())
+            .sum();
+    });
+    let mut parsed = 0;
+    let parse_time = best_time(iterations, || {
+        parsed = sources
+            .iter()
+            .filter(|source| CompileEngine::compile(Tokens::tokenize(source)).is_ok())
+            .count();
+    });
+
+    println!(
+        "{} files, {} bytes, {} tokens",
+        sources.len(),
+        bytes,
+        token_count
+    );
+    report("tokenize", tokenize_time, bytes);
+    report(&format!("parse ({} ok)", parsed), parse_time, bytes);
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: tokenizer_bench [-n|--iterations <count>] [<jack file or directory>...]");
+    std::process::exit(1);
+}
+
+fn find_jack_files(path: PathBuf, jack_paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_dir() {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            find_jack_files(path, jack_paths)?;
+        }
+    } else if path.extension().is_some_and(|ext| ext == "jack") {
+        jack_paths.push(path);
+    }
+    Ok(())
+}
+
+fn best_time<F: FnMut()>(iterations: usize, mut run: F) -> Duration {
+    (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, time: Duration, bytes: usize) {
+    println!(
+        "{:<16} {:>10.3} ms {:>10.1} MB/s",
+        name,
+        time.as_secs_f64() * 1000.0,
+        bytes as f64 / time.as_secs_f64() / 1_000_000.0
+    );
+}
@@ -1,54 +1,111 @@
+use crate::error::CompileError;
 use crate::grammar::*;
-use crate::tokenizer::Token;
+use crate::parse_xml::ParseXml;
+use crate::tokenizer::{Span, SpannedToken, Token};
+use std::collections::VecDeque;
 use std::fmt::Debug;
 
+const SUB_KINDS: [&str; 3] = ["constructor", "function", "method"];
+const STMT_KINDS: [&str; 5] = ["let", "if", "while", "do", "return"];
+
+type ParseResult<T> = Result<T, CompileError>;
+
 pub struct CompileEngine {}
 
 struct Engine<T>
 where
-    T: Iterator<Item = Token> + Debug,
+    T: Iterator<Item = Result<SpannedToken, CompileError>> + Debug,
 {
     tokens: T,
-    token_buf: Vec<Token>,
+    token_buf: VecDeque<SpannedToken>,
+    // Span of the last token read, where errors at the end of the file are reported
+    last_span: Span,
+    // Errors recovered from, to be reported along with any later ones
+    errors: Vec<CompileError>,
+    // Parse tree written as the tokens are read, if wanted
+    xml: Option<ParseXml>,
 }
 
 impl CompileEngine {
-    pub fn compile<T>(tokens: T) -> Class
+    pub fn compile<T>(tokens: T) -> Result<Class, Vec<CompileError>>
+    where
+        T: Iterator<Item = Result<SpannedToken, CompileError>> + Debug,
+    {
+        Self::run(tokens, None).map(|(class, _)| class)
+    }
+
+    // Also returns the parse tree in the XML format of project 10
+    pub fn compile_with_xml<T>(tokens: T) -> Result<(Class, String), Vec<CompileError>>
     where
-        T: Iterator<Item = Token> + Debug,
+        T: Iterator<Item = Result<SpannedToken, CompileError>> + Debug,
+    {
+        Self::run(tokens, Some(ParseXml::default()))
+            .map(|(class, xml)| (class, xml.unwrap().into_string()))
+    }
+
+    fn run<T>(
+        tokens: T,
+        xml: Option<ParseXml>,
+    ) -> Result<(Class, Option<ParseXml>), Vec<CompileError>>
+    where
+        T: Iterator<Item = Result<SpannedToken, CompileError>> + Debug,
     {
         let mut engine = Engine {
             tokens,
-            token_buf: vec![],
+            token_buf: VecDeque::new(),
+            last_span: Span { line: 1, column: 1 },
+            errors: vec![],
+            xml,
         };
-        engine.compile_class()
+        match engine.compile_class() {
+            Ok(class) if engine.errors.is_empty() => return Ok((class, engine.xml)),
+            Ok(_) => {}
+            Err(error) => {
+                // At the end of the file, the subroutine that was cut short already reported it
+                if engine
+                    .errors
+                    .last()
+                    .is_none_or(|last| last.span != error.span)
+                {
+                    engine.errors.push(error);
+                }
+            }
+        }
+        // Lexical errors are found ahead of the parser
+        let mut errors = engine.errors;
+        errors.sort_by_key(|error| (error.span.line, error.span.column));
+        Err(errors)
     }
 }
 
-fn unexpected_token(token: Token) -> ! {
-    panic!("Unexpected token {:?}!", token);
-}
-
 impl<T> Engine<T>
 where
-    T: Iterator<Item = Token> + Debug,
+    T: Iterator<Item = Result<SpannedToken, CompileError>> + Debug,
 {
-    fn compile_class(&mut self) -> Class {
-        self.read_keyword(Some("class"));
-        let name = self.read_identifier();
-        self.read_symbol('{');
-
-        let (static_vars, field_vars) = self.compile_class_vars();
-        let class = Class {
+    fn compile_class(&mut self) -> ParseResult<Class> {
+        self.start("class");
+        self.read_keyword(Some("class"))?;
+        let span = self.peek_span();
+        let name = self.read_identifier()?;
+        self.read_symbol('{')?;
+
+        let (static_vars, field_vars) = self.compile_class_vars()?;
+        let subroutines = self.compile_subroutines();
+        self.read_symbol('}')?;
+        self.end("class");
+        if self.peek_token(0).is_some() {
+            return Err(self.expected("end of file"));
+        }
+        Ok(Class {
             name,
-            static_vars: static_vars,
-            field_vars: field_vars,
-            subroutines: self.compile_subroutines(),
-        };
-        class
+            static_vars,
+            field_vars,
+            subroutines,
+            span,
+        })
     }
 
-    fn compile_class_vars(&mut self) -> (Vec<Var>, Vec<Var>) {
+    fn compile_class_vars(&mut self) -> ParseResult<(Vec<Var>, Vec<Var>)> {
         let mut static_vars = vec![];
         let mut field_vars = vec![];
         loop {
@@ -59,40 +116,33 @@ where
             if keyword.is_none() {
                 break;
             }
+            self.start("classVarDec");
             self.next_token().unwrap();
             let class_var_kind = keyword.unwrap();
-            let vtype = self.read_type();
-            loop {
-                let name = self.read_identifier();
-                let var = Var {
-                    vtype: vtype.clone(),
-                    name,
-                };
-                if class_var_kind == "static" {
-                    static_vars.push(var);
-                } else {
-                    field_vars.push(var);
-                }
-                let token = self
-                    .next_token()
-                    .expect("Unexpected end of class var declaration line");
-                if let Token::Symbol(';') = token {
-                    break;
-                } else if token != Token::Symbol(',') {
-                    panic!("Expected ',', Found {:?}", token);
-                }
+            let vars = self.compile_var_names()?;
+            self.end("classVarDec");
+            if class_var_kind == "static" {
+                static_vars.extend(vars);
+            } else {
+                field_vars.extend(vars);
             }
         }
-        (static_vars, field_vars)
+        Ok((static_vars, field_vars))
     }
 
+    // A subroutine with an error is skipped, parsing resuming at the next one
     fn compile_subroutines(&mut self) -> Vec<Subroutine> {
-        let sub_kinds = ["constructor", "function", "method"];
         let mut subroutines = vec![];
         loop {
             let kind = self.peek_keyword(0);
-            if kind.is_some() && sub_kinds.iter().any(|k| k == kind.unwrap()) {
-                subroutines.push(self.compile_subroutine());
+            if kind.is_some() && SUB_KINDS.iter().any(|k| k == kind.unwrap()) {
+                match self.compile_subroutine() {
+                    Ok(subroutine) => subroutines.push(subroutine),
+                    Err(error) => {
+                        self.errors.push(error);
+                        self.skip_subroutine();
+                    }
+                }
             } else {
                 break;
             }
@@ -100,43 +150,50 @@ where
         subroutines
     }
 
-    fn compile_subroutine(&mut self) -> Subroutine {
-        let kind = self.read_sub_kind();
-        let rtype = self.read_type();
-        let name = self.read_identifier();
-        let params = self.compile_param_list();
-        self.read_symbol('{');
-        let locals = self.compile_locals();
+    fn compile_subroutine(&mut self) -> ParseResult<Subroutine> {
+        self.start("subroutineDec");
+        let kind = self.read_sub_kind()?;
+        let rtype = self.read_type()?;
+        let span = self.peek_span();
+        let name = self.read_identifier()?;
+        let params = self.compile_param_list()?;
+        self.start("subroutineBody");
+        self.read_symbol('{')?;
+        let locals = self.compile_locals()?;
         let stmts = self.compile_stmts();
-        self.read_symbol('}');
+        self.read_symbol('}')?;
+        self.end("subroutineBody");
+        self.end("subroutineDec");
 
-        Subroutine {
+        Ok(Subroutine {
             kind,
             rtype,
             name,
             params,
             locals,
             stmts,
-        }
+            span,
+        })
     }
 
-    fn compile_param_list(&mut self) -> Vec<Var> {
+    fn compile_param_list(&mut self) -> ParseResult<Vec<Var>> {
         let mut params = vec![];
-        self.read_symbol('(');
-        loop {
-            if let Some(Token::Symbol(')')) = self.peek_token(0) {
-                break;
-            }
-            params.push(self.compile_var_dec());
-            if let Some(Token::Symbol(',')) = self.peek_token(0) {
-                self.read_symbol(',');
+        self.read_symbol('(')?;
+        self.start("parameterList");
+        if self.peek_token(0) != Some(&Token::Symbol(')')) {
+            loop {
+                params.push(self.compile_var_dec()?);
+                if self.read_separator(')')? {
+                    break;
+                }
             }
         }
-        self.read_symbol(')');
-        params
+        self.end("parameterList");
+        self.read_symbol(')')?;
+        Ok(params)
     }
 
-    fn compile_locals(&mut self) -> Vec<Var> {
+    fn compile_locals(&mut self) -> ParseResult<Vec<Var>> {
         let mut locals = vec![];
         loop {
             let keyword = self
@@ -146,238 +203,284 @@ where
             if keyword.is_none() {
                 break;
             }
+            self.start("varDec");
             self.next_token().unwrap();
-            let vtype = self.read_type();
-            loop {
-                let name = self.read_identifier();
-                let var = Var {
-                    vtype: vtype.clone(),
-                    name,
-                };
-                locals.push(var);
-                let token = self
-                    .next_token()
-                    .expect("Unexpected end of local var declaration line");
-                if let Token::Symbol(';') = token {
-                    break;
-                } else if token != Token::Symbol(',') {
-                    panic!("Expected ',', Found {:?}", token);
-                }
+            locals.extend(self.compile_var_names()?);
+            self.end("varDec");
+        }
+        Ok(locals)
+    }
+
+    // type name (',' name)* ';'
+    fn compile_var_names(&mut self) -> ParseResult<Vec<Var>> {
+        let vtype = self.read_type()?;
+        let mut vars = vec![];
+        loop {
+            let span = self.peek_span();
+            let name = self.read_identifier()?;
+            vars.push(Var {
+                vtype: vtype.clone(),
+                name,
+                span,
+            });
+            if self.read_separator(';')? {
+                self.read_symbol(';')?;
+                return Ok(vars);
             }
         }
-        locals
     }
 
-    fn compile_var_dec(&mut self) -> Var {
-        let vtype = self.read_type();
-        let name = self.read_identifier();
-        Var { vtype, name }
+    fn compile_var_dec(&mut self) -> ParseResult<Var> {
+        let vtype = self.read_type()?;
+        let span = self.peek_span();
+        let name = self.read_identifier()?;
+        Ok(Var { vtype, name, span })
     }
 
-    fn compile_stmts(&mut self) -> Vec<Box<dyn Stmt>> {
-        let stmt_kinds = ["let", "if", "while", "do", "return"];
+    // A statement with an error is skipped, parsing resuming at the next one
+    fn compile_stmts(&mut self) -> Vec<Stmt> {
+        self.start("statements");
         let mut stmts = vec![];
         loop {
-            let kind = self.peek_keyword(0);
-            if kind.is_some() && stmt_kinds.iter().any(|k| k == kind.unwrap()) {
-                stmts.push(self.compile_stmt());
-            } else {
-                break;
+            let result = match self.peek_token(0) {
+                None | Some(Token::Symbol('}')) => break,
+                Some(Token::Keyword(keyword)) if SUB_KINDS.contains(&keyword.as_str()) => break,
+                Some(Token::Keyword(keyword)) if STMT_KINDS.contains(&keyword.as_str()) => {
+                    self.compile_stmt()
+                }
+                _ => Err(self.expected("statement")),
+            };
+            match result {
+                Ok(stmt) => stmts.push(stmt),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.skip_stmt();
+                }
             }
         }
+        self.end("statements");
         stmts
     }
 
-    fn compile_stmt(&mut self) -> Box<dyn Stmt> {
-        let stmt_kind = self.peek_keyword(0);
-        let stmt: Box<dyn Stmt> = match stmt_kind.as_ref().unwrap().as_str() {
-            "do" => Box::new(self.compile_do()),
-            "return" => Box::new(self.compile_return()),
-            "let" => Box::new(self.compile_let()),
-            "if" => Box::new(self.compile_if()),
-            "while" => Box::new(self.compile_while()),
-            _ => panic!("Unexpected keyword {}", stmt_kind.as_ref().unwrap()),
+    fn compile_stmt(&mut self) -> ParseResult<Stmt> {
+        let stmt_kind = self.peek_keyword(0).cloned().unwrap();
+        let tag = format!("{}Statement", stmt_kind);
+        self.start(&tag);
+        let stmt = match stmt_kind.as_str() {
+            "do" => Stmt::Do(self.compile_do()?),
+            "return" => Stmt::Return(self.compile_return()?),
+            "let" => Stmt::Let(self.compile_let()?),
+            "if" => Stmt::If(self.compile_if()?),
+            "while" => Stmt::While(self.compile_while()?),
+            _ => panic!("Unexpected keyword {}", stmt_kind),
         };
-        if let Some(Token::Symbol(';')) = self.peek_token(0) {
-            self.read_symbol(';');
+        if let Stmt::Do(_) | Stmt::Return(_) | Stmt::Let(_) = stmt {
+            self.read_symbol(';')?;
         }
-        stmt
+        self.end(&tag);
+        Ok(stmt)
     }
 
-    fn compile_do(&mut self) -> Do {
-        self.read_keyword(Some("do"));
-        let sub_call = self.compile_sub_call();
-        Do(sub_call)
+    fn compile_do(&mut self) -> ParseResult<Do> {
+        let span = self.peek_span();
+        self.read_keyword(Some("do"))?;
+        let sub_call = self.compile_sub_call()?;
+        Ok(Do { sub_call, span })
     }
 
-    fn compile_return(&mut self) -> Return {
-        self.read_keyword(Some("return"));
-        let token = self.peek_token(0).unwrap();
-        let expr = if token != &Token::Symbol(';') {
-            Some(self.compile_expr())
+    fn compile_return(&mut self) -> ParseResult<Return> {
+        let span = self.peek_span();
+        self.read_keyword(Some("return"))?;
+        let expr = if let Some(Token::Symbol(';')) = self.peek_token(0) {
+            None
+        } else {
+            Some(self.compile_expr()?)
+        };
+        Ok(Return { expr, span })
+    }
+
+    fn compile_let(&mut self) -> ParseResult<Let> {
+        let span = self.peek_span();
+        self.read_keyword(Some("let"))?;
+        let lvalue = self.compile_var_expr()?;
+        self.read_symbol('=')?;
+        let rvalue = self.compile_expr()?;
+        Ok(Let {
+            lvalue,
+            rvalue,
+            span,
+        })
+    }
+
+    fn compile_if(&mut self) -> ParseResult<If> {
+        let span = self.peek_span();
+        self.read_keyword(Some("if"))?;
+        let cond = self.compile_cond()?;
+        let true_stmts = self.compile_block()?;
+        let false_stmts = if let Some("else") = self.peek_keyword(0).map(String::as_str) {
+            self.next_token().unwrap();
+            Some(self.compile_block()?)
         } else {
             None
         };
-        Return(expr)
-    }
-
-    fn compile_let(&mut self) -> Let {
-        self.read_keyword(Some("let"));
-        let lvalue = self.compile_var_expr();
-        self.read_symbol('=');
-        let rvalue = self.compile_expr();
-        Let { lvalue, rvalue }
-    }
-
-    fn compile_if(&mut self) -> If {
-        self.read_keyword(Some("if"));
-        self.read_symbol('(');
-        let cond = self.compile_expr();
-        self.read_symbol(')');
-        self.read_symbol('{');
-        let true_stmts = self.compile_stmts();
-        self.read_symbol('}');
-        let false_stmts = self
-            .peek_keyword(0)
-            .cloned()
-            .filter(|keyword| keyword == "else")
-            .and_then(|_| {
-                self.next_token().unwrap();
-                self.read_symbol('{');
-                let stmts = Some(self.compile_stmts());
-                self.read_symbol('}');
-                stmts
-            });
-        If {
+        Ok(If {
             cond,
             true_stmts,
             false_stmts,
-        }
+            span,
+        })
     }
 
-    fn compile_while(&mut self) -> While {
-        self.read_keyword(Some("while"));
-        self.read_symbol('(');
-        let cond = self.compile_expr();
-        self.read_symbol(')');
-        self.read_symbol('{');
+    fn compile_while(&mut self) -> ParseResult<While> {
+        let span = self.peek_span();
+        self.read_keyword(Some("while"))?;
+        let cond = self.compile_cond()?;
+        let stmts = self.compile_block()?;
+        Ok(While { cond, stmts, span })
+    }
+
+    // '(' expression ')'
+    fn compile_cond(&mut self) -> ParseResult<Expr> {
+        self.read_symbol('(')?;
+        let cond = self.compile_expr()?;
+        self.read_symbol(')')?;
+        Ok(cond)
+    }
+
+    // '{' statements '}'
+    fn compile_block(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.read_symbol('{')?;
         let stmts = self.compile_stmts();
-        self.read_symbol('}');
-        While { cond, stmts }
+        self.read_symbol('}')?;
+        Ok(stmts)
     }
 
-    fn compile_var_expr(&mut self) -> VarExpr {
-        let name = self.read_identifier();
+    fn compile_var_expr(&mut self) -> ParseResult<VarExpr> {
+        let span = self.peek_span();
+        let name = self.read_identifier()?;
         let expr = if let Some(Token::Symbol('[')) = self.peek_token(0) {
-            self.read_symbol('[');
-            let expr = Some(self.compile_expr());
-            self.read_symbol(']');
+            self.read_symbol('[')?;
+            let expr = Some(self.compile_expr()?);
+            self.read_symbol(']')?;
             expr
         } else {
             None
         };
-        VarExpr { name, index: expr }
-    }
-
-    fn compile_sub_call(&mut self) -> SubCall {
-        let recv_name = self
-            .peek_token(1)
-            .cloned()
-            .filter(|token| token == &Token::Symbol('.'))
-            .and_then(|_| {
-                let name = self.next_token().map(|token| {
-                    if let Token::Identifier(identifier) = token {
-                        identifier
-                    } else {
-                        panic!("Expected identifier, found {:?}", token);
-                    }
-                });
-                self.read_symbol('.');
-                name
-            });
-        let sub_name = self.read_identifier();
-        let args = self.compile_expr_list();
+        Ok(VarExpr {
+            name,
+            index: expr,
+            span,
+        })
+    }
+
+    fn compile_sub_call(&mut self) -> ParseResult<SubCall> {
+        let span = self.peek_span();
+        let recv_name = if let Some(Token::Symbol('.')) = self.peek_token(1) {
+            let name = self.read_identifier()?;
+            self.read_symbol('.')?;
+            Some(name)
+        } else {
+            None
+        };
+        let sub_name = self.read_identifier()?;
+        let args = self.compile_expr_list()?;
 
-        SubCall {
+        Ok(SubCall {
             recv_name,
             sub_name,
             args,
-        }
+            span,
+        })
     }
 
-    fn compile_expr_list(&mut self) -> Vec<Expr> {
+    fn compile_expr_list(&mut self) -> ParseResult<Vec<Expr>> {
         let mut expr_list = vec![];
-        self.read_symbol('(');
-        loop {
-            if let Some(Token::Symbol(')')) = self.peek_token(0) {
-                break;
-            }
-            expr_list.push(self.compile_expr());
-            if let Some(Token::Symbol(',')) = self.peek_token(0) {
-                self.read_symbol(',');
+        self.read_symbol('(')?;
+        self.start("expressionList");
+        if self.peek_token(0) != Some(&Token::Symbol(')')) {
+            loop {
+                expr_list.push(self.compile_expr()?);
+                if self.read_separator(')')? {
+                    break;
+                }
             }
         }
-        self.read_symbol(')');
-        expr_list
+        self.end("expressionList");
+        self.read_symbol(')')?;
+        Ok(expr_list)
     }
 
-    fn compile_expr(&mut self) -> Expr {
-        let lterm = Box::new(self.compile_term());
-        let op = self.peek_binary_op(0);
-        let rterm = if op.is_some() {
+    fn compile_expr(&mut self) -> ParseResult<Expr> {
+        self.start("expression");
+        let term = Box::new(self.compile_term()?);
+        let span = term.span();
+        let mut rest = vec![];
+        while let Some(op) = self.peek_binary_op(0) {
             self.next_token().unwrap();
-            Some(Box::new(self.compile_term()))
-        } else {
-            None
-        };
-        Expr { lterm, op, rterm }
+            rest.push((op, self.compile_term()?));
+        }
+        self.end("expression");
+        Ok(Expr { term, rest, span })
     }
 
-    fn compile_term(&mut self) -> Term {
-        let token = self.peek_token(0).cloned().unwrap();
-        if let Token::Symbol('(') = token {
-            self.read_symbol('(');
-            let expr = self.compile_expr();
-            self.read_symbol(')');
-            Term::Expr(expr)
-        } else if let Token::Int(int_const) = token {
-            self.next_token().unwrap();
-            Term::Int(int_const)
-        } else if let Token::Str(str_const) = token {
-            self.next_token().unwrap();
-            Term::Str(str_const)
-        } else if let Token::Keyword(keyword_const) = token {
-            self.next_token().unwrap();
-            match keyword_const.as_str() {
-                "true" => Term::Keyword(KeywordConst::True),
-                "false" => Term::Keyword(KeywordConst::False),
-                "null" => Term::Keyword(KeywordConst::Null),
-                "this" => Term::Keyword(KeywordConst::This),
-                _ => panic!("Unexpected keyword term: {}", keyword_const),
+    fn compile_term(&mut self) -> ParseResult<Term> {
+        let token = match self.peek_token(0) {
+            Some(token) => token.clone(),
+            None => return Err(self.expected("term")),
+        };
+        let span = self.peek_span();
+        self.start("term");
+        let term = match token {
+            Token::Symbol('(') => {
+                self.read_symbol('(')?;
+                let expr = self.compile_expr()?;
+                self.read_symbol(')')?;
+                Term::Expr(expr, span)
             }
-        } else if token == Token::Symbol('-') || token == Token::Symbol('~') {
-            self.next_token().unwrap();
-            let term = Box::new(self.compile_term());
-            if token == Token::Symbol('-') {
-                Term::Unary(UnaryOp::Neg, term)
-            } else {
-                Term::Unary(UnaryOp::Not, term)
+            Token::Int(int_const) => {
+                self.next_token().unwrap();
+                Term::Int(int_const, span)
             }
-        } else if let Token::Identifier(_) = token {
-            self.compile_call_or_var_expr()
-        } else {
-            panic!("Unexpected Term: {:#?}", token);
-        }
+            Token::Str(str_const) => {
+                self.next_token().unwrap();
+                Term::Str(str_const, span)
+            }
+            Token::Keyword(keyword_const) => {
+                let keyword_const = match keyword_const.as_str() {
+                    "true" => KeywordConst::True,
+                    "false" => KeywordConst::False,
+                    "null" => KeywordConst::Null,
+                    "this" => KeywordConst::This,
+                    _ => return Err(self.expected("term")),
+                };
+                self.next_token().unwrap();
+                Term::Keyword(keyword_const, span)
+            }
+            Token::Symbol(symbol @ '-') | Token::Symbol(symbol @ '~') => {
+                self.next_token().unwrap();
+                let term = Box::new(self.compile_term()?);
+                if symbol == '-' {
+                    Term::Unary(UnaryOp::Neg, term, span)
+                } else {
+                    Term::Unary(UnaryOp::Not, term, span)
+                }
+            }
+            Token::Identifier(_) => self.compile_call_or_var_expr()?,
+            _ => return Err(self.expected("term")),
+        };
+        self.end("term");
+        Ok(term)
     }
 
-    fn compile_call_or_var_expr(&mut self) -> Term {
-        let token = self.peek_token(1).unwrap();
-        if token == &Token::Symbol('.') || token == &Token::Symbol('(') {
-            Term::Call(self.compile_sub_call())
-        } else {
-            Term::Var(self.compile_var_expr())
+    fn compile_call_or_var_expr(&mut self) -> ParseResult<Term> {
+        match self.peek_token(1) {
+            Some(Token::Symbol('.')) | Some(Token::Symbol('(')) => {
+                Ok(Term::Call(self.compile_sub_call()?))
+            }
+            _ => Ok(Term::Var(self.compile_var_expr()?)),
         }
     }
+
     fn peek_binary_op(&mut self, index: usize) -> Option<BinaryOp> {
         let token = self.peek_token(index);
         if let Some(Token::Symbol(symbol)) = token {
@@ -397,46 +500,55 @@ where
             None
         }
     }
-    fn read_sub_kind(&mut self) -> SubKind {
-        let kind = self.read_keyword(None);
-        match kind.as_str() {
-            "constructor" => SubKind::Constructor,
-            "function" => SubKind::Function,
-            "method" => SubKind::Method,
-            _ => panic!("Unexpected keyword {}", kind),
-        }
+
+    fn read_sub_kind(&mut self) -> ParseResult<SubKind> {
+        let kind = match self.peek_keyword(0).map(String::as_str) {
+            Some("constructor") => SubKind::Constructor,
+            Some("function") => SubKind::Function,
+            Some("method") => SubKind::Method,
+            _ => return Err(self.expected("subroutine declaration")),
+        };
+        self.next_token().unwrap();
+        Ok(kind)
     }
 
-    fn read_type(&mut self) -> Type {
-        let rtype = self
-            .peek_keyword(0)
-            .and_then(|keyword| match keyword.as_str() {
-                "int" => Some(Type::Int),
-                "char" => Some(Type::Char),
-                "boolean" => Some(Type::Bool),
-                "void" => Some(Type::Void),
-                _ => panic!("Unexpected type"),
-            });
+    fn read_type(&mut self) -> ParseResult<Type> {
+        let rtype = match self.peek_token(0) {
+            Some(Token::Keyword(keyword)) => match keyword.as_str() {
+                "int" => Type::Int,
+                "char" => Type::Char,
+                "boolean" => Type::Bool,
+                "void" => Type::Void,
+                _ => return Err(self.expected("type")),
+            },
+            Some(Token::Identifier(identifier)) => Type::Class(identifier.clone()),
+            _ => return Err(self.expected("type")),
+        };
+        self.next_token().unwrap();
+        Ok(rtype)
+    }
 
-        let rtype = rtype
-            .or_else(|| {
-                self.peek_identifier(0)
-                    .map(|identifier| Type::Class(identifier.clone()))
-            })
-            .expect("Expected function return type");
-        self.next_token();
-        rtype
-    }
-
-    fn read_keyword(&mut self, expected: Option<&str>) -> String {
-        let token = self.next_token().expect("Expected keyword");
-        if let Token::Keyword(keyword) = token {
-            if expected.is_some() && &keyword != expected.as_ref().unwrap() {
-                panic!("Expected '{}', Found '{}'", expected.unwrap(), keyword);
+    // Reads a ',' continuing a list, or returns whether the list ends with the terminator,
+    // which isn't read
+    fn read_separator(&mut self, terminator: char) -> ParseResult<bool> {
+        match self.peek_token(0) {
+            Some(Token::Symbol(',')) => {
+                self.next_token().unwrap();
+                Ok(false)
             }
-            keyword
-        } else {
-            unexpected_token(token);
+            Some(Token::Symbol(symbol)) if *symbol == terminator => Ok(true),
+            _ => Err(self.expected(&format!("',' or '{}'", terminator))),
+        }
+    }
+
+    fn read_keyword(&mut self, expected: Option<&str>) -> ParseResult<String> {
+        match self.peek_keyword(0) {
+            Some(keyword) if expected.is_none_or(|expected| keyword == expected) => {
+                Ok(self.read_token_text())
+            }
+            _ => Err(self.expected(
+                &expected.map_or("keyword".to_owned(), |expected| format!("'{}'", expected)),
+            )),
         }
     }
 
@@ -451,51 +563,119 @@ where
         })
     }
 
-    fn read_identifier(&mut self) -> String {
-        let token = self.next_token().expect("Expected identifier");
-        if let Token::Identifier(name) = token {
-            name
+    fn read_identifier(&mut self) -> ParseResult<String> {
+        if let Some(Token::Identifier(_)) = self.peek_token(0) {
+            Ok(self.read_token_text())
         } else {
-            unexpected_token(token);
+            Err(self.expected("identifier"))
         }
     }
 
-    fn peek_identifier(&mut self, index: usize) -> Option<&String> {
-        let token = self.peek_token(index);
-        token.and_then(|token| {
-            if let Token::Identifier(identifier) = token {
-                Some(identifier)
-            } else {
-                None
+    // The name of a keyword or identifier token that has been peeked
+    fn read_token_text(&mut self) -> String {
+        match self.next_token() {
+            Some(Token::Keyword(text)) | Some(Token::Identifier(text)) => text,
+            token => panic!("Unexpected token {:?}", token),
+        }
+    }
+
+    fn read_symbol(&mut self, expected: char) -> ParseResult<()> {
+        match self.peek_token(0) {
+            Some(Token::Symbol(symbol)) if *symbol == expected => {
+                self.next_token().unwrap();
+                Ok(())
             }
-        })
+            _ => Err(self.expected(&format!("'{}'", expected))),
+        }
     }
 
-    fn read_symbol(&mut self, expected: char) {
-        let token = self
-            .next_token()
-            .expect(format!("Expected symbol {}", expected).as_str());
-        if let Token::Symbol(symbol) = token {
-            if symbol != expected {
-                panic!("Expected '{}', Found '{}'", expected, symbol);
+    // An error at the next token, which isn't read
+    fn expected(&mut self, expected: &str) -> CompileError {
+        let span = self.peek_span();
+        let found = self
+            .peek_token(0)
+            .map_or("end of file".to_owned(), |token| token.to_string());
+        CompileError::new(span, format!("expected {}, found {}", expected, found))
+    }
+
+    // Skips to the end of the statement, the start of the next one or the end of the
+    // enclosing block
+    fn skip_stmt(&mut self) {
+        loop {
+            match self.peek_token(0) {
+                None | Some(Token::Symbol('}')) => return,
+                Some(Token::Keyword(keyword))
+                    if STMT_KINDS.contains(&keyword.as_str())
+                        || SUB_KINDS.contains(&keyword.as_str()) =>
+                {
+                    return
+                }
+                Some(Token::Symbol(';')) => {
+                    self.next_token().unwrap();
+                    return;
+                }
+                _ => {
+                    self.next_token().unwrap();
+                }
+            }
+        }
+    }
+
+    // Skips to the start of the next subroutine, or the '}' ending the class
+    fn skip_subroutine(&mut self) {
+        loop {
+            match (self.peek_token(0).cloned(), self.peek_token(1).is_none()) {
+                (None, _) | (Some(Token::Symbol('}')), true) => return,
+                (Some(Token::Keyword(keyword)), _) if SUB_KINDS.contains(&keyword.as_str()) => {
+                    return
+                }
+                _ => {
+                    self.next_token().unwrap();
+                }
             }
-        } else {
-            unexpected_token(token);
         }
     }
 
     fn next_token(&mut self) -> Option<Token> {
-        if self.token_buf.len() > 0 {
-            Some(self.token_buf.remove(0))
-        } else {
-            self.tokens.next()
+        let spanned_token = self
+            .token_buf
+            .pop_front()
+            .or_else(|| self.next_valid_token());
+        spanned_token.map(|SpannedToken { token, span }| {
+            self.last_span = span;
+            if let Some(xml) = &mut self.xml {
+                xml.token(&token);
+            }
+            token
+        })
+    }
+
+    fn start(&mut self, tag: &str) {
+        if let Some(xml) = &mut self.xml {
+            xml.start(tag);
         }
     }
 
-    fn peek_token(&mut self, index: usize) -> Option<&Token> {
-        while self.token_buf.is_empty() || index > (self.token_buf.len() - 1) {
-            if let Some(token) = self.tokens.next() {
-                self.token_buf.push(token);
+    fn end(&mut self, tag: &str) {
+        if let Some(xml) = &mut self.xml {
+            xml.end(tag);
+        }
+    }
+
+    // Lexical errors are recorded, parsing going on without the characters in error
+    fn next_valid_token(&mut self) -> Option<SpannedToken> {
+        loop {
+            match self.tokens.next()? {
+                Ok(spanned_token) => return Some(spanned_token),
+                Err(error) => self.errors.push(error),
+            }
+        }
+    }
+
+    fn peek_spanned_token(&mut self, index: usize) -> Option<&SpannedToken> {
+        while index >= self.token_buf.len() {
+            if let Some(token) = self.next_valid_token() {
+                self.token_buf.push_back(token);
             } else {
                 break;
             }
@@ -503,4 +683,15 @@ where
 
         self.token_buf.get(index)
     }
+
+    fn peek_token(&mut self, index: usize) -> Option<&Token> {
+        self.peek_spanned_token(index)
+            .map(|spanned_token| &spanned_token.token)
+    }
+
+    fn peek_span(&mut self) -> Span {
+        let last_span = self.last_span;
+        self.peek_spanned_token(0)
+            .map_or(last_span, |spanned_token| spanned_token.span)
+    }
 }
@@ -0,0 +1,70 @@
+use crate::tokenizer::Span;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl CompileError {
+    pub fn new(span: Span, message: String) -> Self {
+        CompileError { span, message }
+    }
+
+    // The error as `File.jack:12:5: message`, followed by the source line with a caret
+    // under the column
+    pub fn report(&self, jack_path: &Path, source: &str) -> String {
+        let file_name = jack_path.file_name().unwrap_or_default().to_string_lossy();
+        let mut report = format!(
+            "{}:{}:{}: {}",
+            file_name, self.span.line, self.span.column, self.message
+        );
+        if let Some(line) = source.lines().nth(self.span.line.saturating_sub(1)) {
+            // Tabs are kept so that the caret lines up however they are displayed
+            let indent: String = line
+                .chars()
+                .take(self.span.column.saturating_sub(1))
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect();
+            report.push_str(&format!("\n{}\n{}^", line, indent));
+        }
+        report
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+impl Error for CompileError {}
+
+// The errors in a Jack source, displayed as the compiler reports them
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    // Name of the file the errors are reported in, such as Main.jack
+    pub name: String,
+    pub source: String,
+    pub errors: Vec<CompileError>,
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reports: Vec<String> = self
+            .errors
+            .iter()
+            .map(|error| error.report(Path::new(&self.name), &self.source))
+            .collect();
+        write!(f, "{}", reports.join("\n"))
+    }
+}
+
+impl Error for Diagnostics {}
@@ -1,5 +1,4 @@
-use std::any::Any;
-use std::fmt::Debug;
+use crate::tokenizer::Span;
 
 #[derive(Debug)]
 pub struct Class {
@@ -7,15 +6,18 @@ pub struct Class {
     pub static_vars: Vec<Var>,
     pub field_vars: Vec<Var>,
     pub subroutines: Vec<Subroutine>,
+    // The span is that of the name
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct Var {
     pub vtype: Type,
     pub name: String,
+    pub span: Span,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Int,
     Char,
@@ -31,115 +33,88 @@ pub struct Subroutine {
     pub name: String,
     pub params: Vec<Var>,
     pub locals: Vec<Var>,
-    pub stmts: Vec<Box<dyn Stmt>>,
+    pub stmts: Vec<Stmt>,
+    // The span is that of the name
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SubKind {
     Constructor,
     Function,
     Method,
 }
 
-pub trait Stmt: Debug {
-    fn kind(&self) -> StmtKind;
-    fn as_any(&self) -> &dyn Any;
+#[derive(Debug)]
+pub enum Stmt {
+    Let(Let),
+    If(If),
+    While(While),
+    Do(Do),
+    Return(Return),
 }
 
-pub enum StmtKind {
-    Let,
-    If,
-    While,
-    Do,
-    Return,
+impl Stmt {
+    // The span of the statement's keyword
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Let(stmt) => stmt.span,
+            Stmt::If(stmt) => stmt.span,
+            Stmt::While(stmt) => stmt.span,
+            Stmt::Do(stmt) => stmt.span,
+            Stmt::Return(stmt) => stmt.span,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Let {
     pub lvalue: VarExpr,
     pub rvalue: Expr,
-}
-
-impl Stmt for Let {
-    fn kind(&self) -> StmtKind {
-        StmtKind::Let
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct VarExpr {
     pub name: String,
     pub index: Option<Expr>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct If {
     pub cond: Expr,
-    pub true_stmts: Vec<Box<dyn Stmt>>,
-    pub false_stmts: Option<Vec<Box<dyn Stmt>>>,
-}
-
-impl Stmt for If {
-    fn kind(&self) -> StmtKind {
-        StmtKind::If
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
+    pub true_stmts: Vec<Stmt>,
+    pub false_stmts: Option<Vec<Stmt>>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct While {
     pub cond: Expr,
-    pub stmts: Vec<Box<dyn Stmt>>,
-}
-
-impl Stmt for While {
-    fn kind(&self) -> StmtKind {
-        StmtKind::While
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct Do(pub SubCall);
-
-impl Stmt for Do {
-    fn kind(&self) -> StmtKind {
-        StmtKind::Do
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
+pub struct Do {
+    pub sub_call: SubCall,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct Return(pub Option<Expr>);
-
-impl Stmt for Return {
-    fn kind(&self) -> StmtKind {
-        StmtKind::Return
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
+pub struct Return {
+    pub expr: Option<Expr>,
+    pub span: Span,
 }
 
+// term (op term)*, evaluated left to right as Jack has no operator precedence
 #[derive(Debug)]
 pub struct Expr {
-    pub lterm: Box<Term>,
-    pub op: Option<BinaryOp>,
-    pub rterm: Option<Box<Term>>,
+    pub term: Box<Term>,
+    pub rest: Vec<(BinaryOp, Term)>,
+    // The span is that of the first term
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -155,15 +130,30 @@ pub enum BinaryOp {
     Eq,
 }
 
+// Terms other than variables and calls carry the span of their first token
 #[derive(Debug)]
 pub enum Term {
-    Int(i16),
-    Str(String),
-    Keyword(KeywordConst),
+    Int(i16, Span),
+    Str(String, Span),
+    Keyword(KeywordConst, Span),
     Var(VarExpr),
     Call(SubCall),
-    Expr(Expr),
-    Unary(UnaryOp, Box<Term>),
+    Expr(Expr, Span),
+    Unary(UnaryOp, Box<Term>, Span),
+}
+
+impl Term {
+    pub fn span(&self) -> Span {
+        match self {
+            Term::Int(_, span)
+            | Term::Str(_, span)
+            | Term::Keyword(_, span)
+            | Term::Expr(_, span)
+            | Term::Unary(_, _, span) => *span,
+            Term::Var(var_expr) => var_expr.span,
+            Term::Call(sub_call) => sub_call.span,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -185,4 +175,5 @@ pub struct SubCall {
     pub recv_name: Option<String>,
     pub sub_name: String,
     pub args: Vec<Expr>,
+    pub span: Span,
 }
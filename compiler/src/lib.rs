@@ -0,0 +1,102 @@
+extern crate quick_xml;
+
+pub mod compile_engine;
+pub mod error;
+pub mod grammar;
+mod parse_xml;
+pub mod program;
+pub mod semantic;
+mod symtable;
+pub mod token_xml;
+pub mod tokenizer;
+pub mod visitor;
+pub mod vm_writer;
+
+use crate::compile_engine::CompileEngine;
+use crate::error::{CompileError, Diagnostics};
+use crate::grammar::Class;
+use crate::program::Program;
+use crate::tokenizer::Tokens;
+
+// Parses the class in a Jack source, errors being reported in the file of the given name
+pub fn parse_str(name: &str, source: &str) -> Result<Class, Diagnostics> {
+    CompileEngine::compile(Tokens::tokenize(source))
+        .map_err(|errors| diagnostics(name, source, errors))
+}
+
+// Compiles a class to VM code on its own, so it may only call itself and the OS
+pub fn compile_str(name: &str, source: &str) -> Result<String, Diagnostics> {
+    let class = parse_str(name, source)?;
+    let program = Program::new(std::slice::from_ref(&class));
+    semantic::check_class(&class, &program).map_err(|errors| diagnostics(name, source, errors))?;
+    let mut vm_code = vec![];
+    vm_writer::generate_vm_code(class, &program, &mut vm_code, false).unwrap();
+    // Identifiers are ASCII, and string constants are written as character codes
+    Ok(String::from_utf8(vm_code).unwrap())
+}
+
+fn diagnostics(name: &str, source: &str, errors: Vec<CompileError>) -> Diagnostics {
+    Diagnostics {
+        name: name.to_owned(),
+        source: source.to_owned(),
+        errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_class_to_vm_code() {
+        let source = "
+class Main {
+    function int twice(int x) { return x + x; }
+    function void main() {
+        do Output.printInt(Main.twice(21));
+        return;
+    }
+}";
+        let vm_code = compile_str("Main.jack", source).unwrap();
+        let expected = "function Main.twice 0
+push argument 0
+push argument 0
+add
+return
+function Main.main 0
+push constant 21
+call Main.twice 1
+call Output.printInt 1
+pop temp 0
+push constant 0
+return
+";
+        assert_eq!(vm_code, expected);
+    }
+
+    #[test]
+    fn parses_class() {
+        let class = parse_str("Main.jack", "class Main { field int x; }").unwrap();
+        assert_eq!(class.name, "Main");
+        assert_eq!(class.field_vars.len(), 1);
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let diagnostics = parse_str("Main.jack", "class Main {\n  field int 1;\n}").unwrap_err();
+        assert_eq!(diagnostics.name, "Main.jack");
+        assert_eq!(diagnostics.errors.len(), 1);
+        assert_eq!(diagnostics.errors[0].span.line, 2);
+    }
+
+    #[test]
+    fn reports_semantic_errors_in_file() {
+        let source =
+            "class Main {\n  function void main() {\n    do Main.missing();\n    return;\n  }\n}";
+        let diagnostics = compile_str("Main.jack", source).unwrap_err();
+        assert_eq!(
+            diagnostics.to_string(),
+            "Main.jack:3:8: unknown subroutine 'Main.missing'\n    do Main.missing();\n       ^"
+        );
+    }
+}
@@ -1,72 +1,228 @@
-extern crate itertools;
-extern crate quick_xml;
-
-mod compile_engine;
-mod grammar;
-mod symtable;
-mod token_xml;
-mod tokenizer;
-mod vm_writer;
-
-use compile_engine::CompileEngine;
-use itertools::Itertools;
-use std::ffi::OsString;
+extern crate compiler;
+
+use compiler::compile_engine::CompileEngine;
+use compiler::error::CompileError;
+use compiler::grammar::Class;
+use compiler::program::Program;
+use compiler::token_xml::TokenXml;
+use compiler::tokenizer::Tokens;
+use compiler::{semantic, vm_writer};
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
-use std::path::Path;
-use token_xml::TokenXml;
-use tokenizer::Tokens;
+use std::path::{Path, PathBuf};
+
+struct Config {
+    extended: bool,
+    write_token_xml: bool,
+    write_parse_xml: bool,
+    to_stdout: bool,
+    out_dir: Option<PathBuf>,
+    input_paths: Vec<PathBuf>,
+}
 
 fn main() -> io::Result<()> {
-    let args: Vec<OsString> = std::env::args_os().collect();
-    if args.len() != 2 {
-        writeln!(io::stderr(), "Usage: compiler <path/.jack file>")?;
-    }
-    let input_path = Path::new(&args[1]);
-    let meta = fs::metadata(input_path).unwrap();
-    let jack_files = if meta.is_dir() {
-        fs::read_dir(input_path)
-            .unwrap()
-            .filter_map(Result::ok)
-            .filter_map(|entry| {
-                if let Some(ext) = entry.path().extension() {
-                    if ext == "jack" {
-                        return Some((entry.path(), File::open(entry.path()).unwrap()));
-                    }
-                }
-                None
-            })
-            .collect()
-    } else {
-        vec![(input_path.to_owned(), File::open(input_path).unwrap())]
+    let mut config = Config {
+        extended: false,
+        write_token_xml: false,
+        write_parse_xml: false,
+        to_stdout: false,
+        out_dir: None,
+        input_paths: vec![],
     };
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--extended") => config.extended = true,
+            Some("--tokens") => config.write_token_xml = true,
+            Some("--parse-xml") => config.write_parse_xml = true,
+            Some("--stdout") => config.to_stdout = true,
+            Some("-d") | Some("--out-dir") => {
+                config.out_dir = Some(args.next().unwrap_or_else(|| usage()).into())
+            }
+            Some(flag) if flag.starts_with('-') => usage(),
+            _ => config.input_paths.push(arg.into()),
+        }
+    }
+    if config.input_paths.is_empty() {
+        usage();
+    }
 
-    for (jack_path, jack_file) in jack_files {
-        println!("Compiling {:?}...", jack_path);
-        let (tokens1, tokens2) = Tokens::tokenize(jack_file).tee();
+    match compile(&config) {
+        Ok(true) => Ok(()),
+        Ok(false) => std::process::exit(1),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
 
-        let jack_file_stem = jack_path.file_stem().unwrap().to_owned();
-        let mut tokens_out_path = OsString::from(jack_file_stem.clone());
-        tokens_out_path.push("T.xml");
-        let tokens_out_path = jack_path.parent().unwrap().join(tokens_out_path);
+fn usage() -> ! {
+    eprintln!(
+        "Usage: compiler [--extended] [--tokens] [--parse-xml] [--stdout] [-d|--out-dir <dir>] \
+         <jack file or directory>...\n\n\
+         --extended compiles * and / to the mul and div VM instructions. Division by zero \
+         then gives 0, where Math.divide calls Sys.error 3."
+    );
+    std::process::exit(1);
+}
+
+// Compiles the input files as one program, reporting errors in the Jack code. Returns
+// whether there were none.
+fn compile(config: &Config) -> io::Result<bool> {
+    if let Some(out_dir) = &config.out_dir {
+        fs::create_dir_all(out_dir)?;
+    }
+
+    // All classes are parsed first, so that each can be checked against the others
+    let mut jack_paths: Vec<PathBuf> = vec![];
+    let mut classes: Vec<Class> = vec![];
+    let mut failed = false;
+    for jack_path in jack_file_paths(&config.input_paths)? {
+        if !config.to_stdout {
+            println!("Compiling {:?}...", jack_path);
+        }
+        let source = read_source(&jack_path)?;
+        let tokens: Vec<_> = Tokens::tokenize(&source).collect();
 
-        let tokens_file = File::create(tokens_out_path).unwrap();
+        if config.write_token_xml {
+            let tokens_file = File::create(output_path(config, &jack_path, "T.xml"))?;
+            let mut token_xml = TokenXml::new(tokens_file);
+            for spanned_token in tokens.iter().flatten() {
+                token_xml.add_token(spanned_token.token.clone()).unwrap();
+            }
+            token_xml.end();
+        }
 
-        let mut token_xml = TokenXml::new(tokens_file);
-        for token in tokens1 {
-            token_xml.add_token(token).unwrap();
+        let result = if config.write_parse_xml {
+            CompileEngine::compile_with_xml(tokens.into_iter())
+                .map(|(class, xml)| (class, Some(xml)))
+        } else {
+            CompileEngine::compile(tokens.into_iter()).map(|class| (class, None))
+        };
+        match result {
+            Ok((class, xml)) => {
+                if let Some(xml) = xml {
+                    fs::write(output_path(config, &jack_path, ".xml"), xml)?;
+                }
+                if let Some(index) = classes.iter().position(|other| other.name == class.name) {
+                    let message = format!(
+                        "class '{}' is also defined in {}",
+                        class.name,
+                        jack_paths[index].display()
+                    );
+                    report_errors(&jack_path, &[CompileError::new(class.span, message)])?;
+                    failed = true;
+                }
+                jack_paths.push(jack_path);
+                classes.push(class);
+            }
+            Err(errors) => {
+                report_errors(&jack_path, &errors)?;
+                failed = true;
+            }
         }
-        token_xml.end();
+    }
+    // Classes that failed to parse would make the others' calls to them look wrong
+    if failed {
+        return Ok(false);
+    }
 
-        let mut compile_out_path = OsString::from(jack_file_stem);
-        compile_out_path.push(".vm");
-        let compile_out_path = jack_path.parent().unwrap().join(compile_out_path);
+    // The other classes in the directories of the files given are part of the program too,
+    // but aren't compiled. Being zipped with the paths compiled, the loop leaves them out.
+    let siblings = sibling_classes(&config.input_paths, &classes)?;
+    classes.extend(siblings);
+    let program = Program::new(&classes);
+    for (jack_path, class) in jack_paths.into_iter().zip(classes) {
+        if let Err(errors) = semantic::check_class(&class, &program) {
+            report_errors(&jack_path, &errors)?;
+            failed = true;
+            continue;
+        }
+        if config.to_stdout {
+            vm_writer::generate_vm_code(class, &program, io::stdout().lock(), config.extended)?;
+        } else {
+            let vm_file = File::create(output_path(config, &jack_path, ".vm"))?;
+            vm_writer::generate_vm_code(class, &program, vm_file, config.extended)?;
+        }
+    }
+    Ok(!failed)
+}
+
+// The .jack files of the inputs, those of directories in name order
+fn jack_file_paths(input_paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut jack_file_paths: Vec<PathBuf> = vec![];
+    for input_path in input_paths.iter() {
+        let metadata = fs::metadata(input_path).map_err(|error| located(input_path, error))?;
+        let mut paths = if metadata.is_dir() {
+            let mut paths: Vec<PathBuf> = fs::read_dir(input_path)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "jack"))
+                .collect();
+            paths.sort();
+            paths
+        } else {
+            vec![input_path.clone()]
+        };
+        paths.retain(|path| !jack_file_paths.contains(path));
+        jack_file_paths.extend(paths);
+    }
+    Ok(jack_file_paths)
+}
+
+// The classes of the other .jack files next to the input files, skipping those that
+// don't parse, whose errors are left to when they are compiled
+fn sibling_classes(input_paths: &[PathBuf], classes: &[Class]) -> io::Result<Vec<Class>> {
+    let mut dir_paths: Vec<PathBuf> = vec![];
+    for input_path in input_paths {
+        if !fs::metadata(input_path)?.is_dir() {
+            let dir_path = match input_path.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_owned(),
+                _ => PathBuf::from("."),
+            };
+            dir_paths.push(dir_path);
+        }
+    }
+    let mut siblings: Vec<Class> = vec![];
+    for jack_path in jack_file_paths(&dir_paths)? {
+        let source = read_source(&jack_path)?;
+        if let Ok(class) = CompileEngine::compile(Tokens::tokenize(&source)) {
+            let is_known = |other: &Class| other.name == class.name;
+            if !classes.iter().any(is_known) && !siblings.iter().any(is_known) {
+                siblings.push(class);
+            }
+        }
+    }
+    Ok(siblings)
+}
+
+// Foo.jack's output file with the given suffix, such as FooT.xml, next to it or in the
+// output directory
+fn output_path(config: &Config, jack_path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = jack_path.file_stem().unwrap().to_owned();
+    file_name.push(suffix);
+    match &config.out_dir {
+        Some(out_dir) => out_dir.join(file_name),
+        None => jack_path.with_file_name(file_name),
+    }
+}
+
+fn located(path: &Path, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+// Invalid UTF-8 is replaced, to be reported as unexpected characters outside of comments
+fn read_source(jack_path: &Path) -> io::Result<String> {
+    let bytes = fs::read(jack_path).map_err(|error| located(jack_path, error))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
 
-        let compile_file = File::create(compile_out_path).unwrap();
-        let class = CompileEngine::compile(tokens2);
-        vm_writer::generate_vm_code(class, compile_file);
+fn report_errors(jack_path: &Path, errors: &[CompileError]) -> io::Result<()> {
+    let source = read_source(jack_path)?;
+    for error in errors {
+        eprintln!("{}", error.report(jack_path, &source));
     }
     Ok(())
 }
@@ -0,0 +1,46 @@
+use crate::token_xml::{tag_name, token_value};
+use crate::tokenizer::Token;
+
+// The parse tree in the XML format of project 10, whose compare files have CRLF line
+// endings, an indent of two spaces and spaces around token values
+#[derive(Debug, Default)]
+pub struct ParseXml {
+    xml: String,
+    depth: usize,
+}
+
+impl ParseXml {
+    pub fn start(&mut self, tag: &str) {
+        self.line(&format!("<{}>", tag));
+        self.depth += 1;
+    }
+
+    pub fn end(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.line(&format!("</{}>", tag));
+    }
+
+    pub fn token(&mut self, token: &Token) {
+        let tag = tag_name(token);
+        let value = escape(&token_value(token));
+        self.line(&format!("<{}> {} </{}>", tag, value, tag));
+    }
+
+    pub fn into_string(self) -> String {
+        self.xml
+    }
+
+    fn line(&mut self, text: &str) {
+        self.xml.push_str(&"  ".repeat(self.depth));
+        self.xml.push_str(text);
+        self.xml.push_str("\r\n");
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
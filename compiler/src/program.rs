@@ -0,0 +1,187 @@
+use crate::grammar::*;
+use crate::symtable::{SymTable, VarEntry};
+use std::collections::HashMap;
+
+// The Jack OS API, one class per line followed by its subroutine declarations
+const OS_API: &str = "
+class Array
+function Array new(int size)
+method void dispose()
+
+class Keyboard
+function void init()
+function char keyPressed()
+function char readChar()
+function String readLine(String message)
+function int readInt(String message)
+
+class Math
+function void init()
+function int abs(int x)
+function int multiply(int x, int y)
+function int divide(int x, int y)
+function int min(int x, int y)
+function int max(int x, int y)
+function int sqrt(int x)
+
+class Memory
+function void init()
+function int peek(int address)
+function void poke(int address, int value)
+function Array alloc(int size)
+function void deAlloc(Array o)
+
+class Output
+function void init()
+function void moveCursor(int i, int j)
+function void printChar(char c)
+function void printString(String s)
+function void printInt(int i)
+function void println()
+function void backSpace()
+
+class Screen
+function void init()
+function void clearScreen()
+function void setColor(boolean b)
+function void drawPixel(int x, int y)
+function void drawLine(int x1, int y1, int x2, int y2)
+function void drawRectangle(int x1, int y1, int x2, int y2)
+function void drawCircle(int x, int y, int r)
+
+class String
+constructor String new(int maxLength)
+method void dispose()
+method int length()
+method char charAt(int j)
+method void setCharAt(int j, char c)
+method String appendChar(char c)
+method void eraseLastChar()
+method int intValue()
+method void setInt(int j)
+function char backSpace()
+function char doubleQuote()
+function char newLine()
+
+class Sys
+function void init()
+function void halt()
+function void error(int errorCode)
+function void wait(int duration)
+";
+
+// What a subroutine call is made on
+pub enum Receiver {
+    // No receiver, so the call is to a method of the current class
+    This,
+    // A variable, whose type is the class of the method called
+    Var(VarEntry),
+    // A class, whose function or constructor is called
+    Class(String),
+}
+
+#[derive(Debug)]
+pub struct Signature {
+    pub kind: SubKind,
+    pub rtype: Type,
+    pub params: Vec<Type>,
+}
+
+// The subroutines of every class of a program, those of the OS included
+#[derive(Debug)]
+pub struct Program {
+    classes: HashMap<String, HashMap<String, Signature>>,
+}
+
+impl Program {
+    // The classes compiled replace the OS classes of the same name
+    pub fn new(classes: &[Class]) -> Self {
+        let mut program = Program { classes: os_api() };
+        for class in classes {
+            let signatures = class
+                .subroutines
+                .iter()
+                .map(|sub| {
+                    let signature = Signature {
+                        kind: sub.kind.clone(),
+                        rtype: sub.rtype.clone(),
+                        params: sub.params.iter().map(|param| param.vtype.clone()).collect(),
+                    };
+                    (sub.name.clone(), signature)
+                })
+                .collect();
+            program.classes.insert(class.name.clone(), signatures);
+        }
+        program
+    }
+
+    pub fn is_class(&self, class_name: &str) -> bool {
+        self.classes.contains_key(class_name)
+    }
+
+    pub fn signature(&self, class_name: &str, sub_name: &str) -> Option<&Signature> {
+        self.classes.get(class_name)?.get(sub_name)
+    }
+
+    // Resolves the receiver of a call, a variable first, then a class. The semantic checks
+    // and the code generation both go by it, so that they agree on what is called.
+    pub fn receiver(&self, recv_name: Option<&str>, symtable: &SymTable) -> Option<Receiver> {
+        let recv_name = match recv_name {
+            Some(recv_name) => recv_name,
+            None => return Some(Receiver::This),
+        };
+        match symtable.lookup(recv_name) {
+            Some(var_entry) => Some(Receiver::Var(var_entry.clone())),
+            None if self.is_class(recv_name) => Some(Receiver::Class(recv_name.to_owned())),
+            None => None,
+        }
+    }
+}
+
+fn os_api() -> HashMap<String, HashMap<String, Signature>> {
+    let mut classes: HashMap<String, HashMap<String, Signature>> = HashMap::new();
+    let mut class_name = "";
+    for line in OS_API.lines().filter(|line| !line.is_empty()) {
+        let (head, params) = line
+            .trim_end_matches(')')
+            .split_once('(')
+            .unwrap_or((line, ""));
+        let words: Vec<&str> = head.split(' ').collect();
+        if let ["class", name] = words.as_slice() {
+            class_name = name;
+            continue;
+        }
+        let (kind, rtype, name) = match words.as_slice() {
+            [kind, rtype, name] => (*kind, *rtype, *name),
+            _ => panic!("Invalid OS API declaration {}", line),
+        };
+        let signature = Signature {
+            kind: match kind {
+                "constructor" => SubKind::Constructor,
+                "function" => SubKind::Function,
+                _ => SubKind::Method,
+            },
+            rtype: parse_type(rtype),
+            params: params
+                .split(", ")
+                .filter(|param| !param.is_empty())
+                .map(|param| parse_type(param.split(' ').next().unwrap()))
+                .collect(),
+        };
+        classes
+            .entry(class_name.to_owned())
+            .or_default()
+            .insert(name.to_owned(), signature);
+    }
+    classes
+}
+
+fn parse_type(name: &str) -> Type {
+    match name {
+        "int" => Type::Int,
+        "char" => Type::Char,
+        "boolean" => Type::Bool,
+        "void" => Type::Void,
+        _ => Type::Class(name.to_owned()),
+    }
+}
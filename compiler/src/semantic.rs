@@ -0,0 +1,354 @@
+use crate::error::CompileError;
+use crate::grammar::*;
+use crate::program::{Program, Receiver};
+use crate::symtable::{SymTable, VarEntry, VarKind};
+use crate::tokenizer::Span;
+use crate::visitor::*;
+use std::collections::HashSet;
+
+struct Checker<'a> {
+    class: &'a Class,
+    program: &'a Program,
+    symtable: SymTable,
+    sub_kind: &'a SubKind,
+    rtype: &'a Type,
+    // Variables declared so far in the class while its variables are checked, then in the
+    // subroutine being checked, since the set is cleared at each subroutine
+    var_names: HashSet<&'a str>,
+    errors: Vec<CompileError>,
+}
+
+// Resolves the names of a class and checks its calls and returns against the signatures
+// of the program, before any code is generated
+pub fn check_class(class: &Class, program: &Program) -> Result<(), Vec<CompileError>> {
+    let mut checker = Checker {
+        class,
+        program,
+        symtable: SymTable::new(&class.name),
+        sub_kind: &SubKind::Function,
+        rtype: &Type::Void,
+        var_names: HashSet::new(),
+        errors: vec![],
+    };
+    checker.visit_class(class);
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        let mut errors = checker.errors;
+        errors.sort_by_key(|error| (error.span.line, error.span.column));
+        Err(errors)
+    }
+}
+
+impl<'a> Visitor<'a> for Checker<'a> {
+    fn visit_class(&mut self, class: &'a Class) {
+        self.symtable.load_class_symbols(class);
+        walk_class(self, class);
+    }
+
+    fn visit_subroutine(&mut self, sub: &'a Subroutine) {
+        self.sub_kind = &sub.kind;
+        self.rtype = &sub.rtype;
+        self.var_names.clear();
+        self.symtable.load_sub_symbols(sub);
+        if let Type::Class(class_name) = &sub.rtype {
+            if !self.is_class(class_name) {
+                self.error(
+                    sub.span,
+                    format!("unknown class '{}' returned by '{}'", class_name, sub.name),
+                );
+            }
+        }
+        walk_subroutine(self, sub);
+    }
+
+    // Checks the type of a variable, and that its name is unique among those declared with it
+    fn visit_var(&mut self, var: &'a Var) {
+        match &var.vtype {
+            Type::Void => self.error(var.span, format!("variable '{}' is void", var.name)),
+            Type::Class(class_name) if !self.is_class(class_name) => {
+                self.error(var.span, format!("unknown class '{}'", class_name))
+            }
+            _ => {}
+        }
+        if !self.var_names.insert(&var.name) {
+            self.error(var.span, format!("'{}' is already declared", var.name));
+        }
+    }
+
+    fn visit_stmt(&mut self, stmt: &'a Stmt) {
+        let Return { expr, span } = match stmt {
+            Stmt::Return(stmt) => stmt,
+            _ => return walk_stmt(self, stmt),
+        };
+        match (expr, self.rtype) {
+            (Some(_), Type::Void) => {
+                self.error(*span, "a void subroutine can't return a value".to_owned())
+            }
+            (None, Type::Void) => {}
+            (None, _) => self.error(*span, "expected a return value".to_owned()),
+            (Some(expr), _) => self.visit_expr(expr),
+        }
+    }
+
+    fn visit_term(&mut self, term: &'a Term) {
+        let sub_call = match term {
+            Term::Call(sub_call) => sub_call,
+            _ => return walk_term(self, term),
+        };
+        if let Some(Type::Void) = self.check_sub_call(sub_call) {
+            self.error(
+                sub_call.span,
+                format!(
+                    "'{}' is void and can't be used in an expression",
+                    sub_call.sub_name
+                ),
+            );
+        }
+    }
+
+    fn visit_var_expr(&mut self, var_expr: &'a VarExpr) {
+        if self.resolve(&var_expr.name, var_expr.span).is_none() {
+            self.error(
+                var_expr.span,
+                format!("unknown variable '{}'", var_expr.name),
+            );
+        }
+        walk_var_expr(self, var_expr);
+    }
+
+    fn visit_sub_call(&mut self, sub_call: &'a SubCall) {
+        self.check_sub_call(sub_call);
+    }
+}
+
+impl<'a> Checker<'a> {
+    // Looks up a variable, reporting fields used in functions
+    fn resolve(&mut self, name: &str, span: Span) -> Option<VarEntry> {
+        let var_entry = self.symtable.lookup(name).cloned()?;
+        self.check_field_use(&var_entry, name, span);
+        Some(var_entry)
+    }
+
+    fn check_field_use(&mut self, var_entry: &VarEntry, name: &str, span: Span) {
+        if let (VarKind::Field, SubKind::Function) = (&var_entry.kind, self.sub_kind) {
+            self.error(
+                span,
+                format!("field '{}' can't be used in a function", name),
+            );
+        }
+    }
+
+    // Returns the type returned by the subroutine called, if it is known
+    fn check_sub_call(&mut self, sub_call: &'a SubCall) -> Option<Type> {
+        walk_sub_call(self, sub_call);
+        // The class of the subroutine, and whether it is called as a method
+        let program = self.program;
+        let (class_name, is_method_call) =
+            match program.receiver(sub_call.recv_name.as_deref(), &self.symtable) {
+                Some(Receiver::This) => (self.class.name.clone(), true),
+                Some(Receiver::Var(var_entry)) => {
+                    let recv_name = sub_call.recv_name.as_ref().unwrap();
+                    self.check_field_use(&var_entry, recv_name, sub_call.span);
+                    match var_entry.vtype {
+                        // The unknown class is reported where the variable is declared
+                        Type::Class(class_name) if !self.is_class(&class_name) => return None,
+                        Type::Class(class_name) => (class_name, true),
+                        _ => {
+                            self.error(
+                                sub_call.span,
+                                format!("'{}' isn't an object, so has no methods", recv_name),
+                            );
+                            return None;
+                        }
+                    }
+                }
+                Some(Receiver::Class(class_name)) => (class_name, false),
+                None => {
+                    self.error(
+                        sub_call.span,
+                        format!(
+                            "unknown variable or class '{}'",
+                            sub_call.recv_name.as_ref().unwrap()
+                        ),
+                    );
+                    return None;
+                }
+            };
+        let callee = match program.signature(&class_name, &sub_call.sub_name) {
+            Some(callee) => callee,
+            None => {
+                self.error(
+                    sub_call.span,
+                    format!("unknown subroutine '{}.{}'", class_name, sub_call.sub_name),
+                );
+                return None;
+            }
+        };
+        match (&callee.kind, is_method_call) {
+            (SubKind::Method, true)
+                if sub_call.recv_name.is_none() && *self.sub_kind == SubKind::Function =>
+            {
+                self.error(
+                    sub_call.span,
+                    format!(
+                    "method '{}.{}' is called from a function, which has no object to call it on",
+                    class_name, sub_call.sub_name
+                ),
+                )
+            }
+            (SubKind::Method, false) => self.error(
+                sub_call.span,
+                format!(
+                    "method '{}.{}' is called without an object",
+                    class_name, sub_call.sub_name
+                ),
+            ),
+            (SubKind::Function, true) | (SubKind::Constructor, true) => self.error(
+                sub_call.span,
+                format!(
+                    "'{}.{}' isn't a method, so is called as {}.{}",
+                    class_name, sub_call.sub_name, class_name, sub_call.sub_name
+                ),
+            ),
+            _ => {}
+        }
+        if callee.params.len() != sub_call.args.len() {
+            self.error(
+                sub_call.span,
+                format!(
+                    "expected {} argument{} to '{}.{}', found {}",
+                    callee.params.len(),
+                    if callee.params.len() == 1 { "" } else { "s" },
+                    class_name,
+                    sub_call.sub_name,
+                    sub_call.args.len()
+                ),
+            );
+        }
+        Some(callee.rtype.clone())
+    }
+
+    fn is_class(&self, name: &str) -> bool {
+        self.program.is_class(name)
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(CompileError::new(span, message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_engine::CompileEngine;
+    use crate::tokenizer::Tokens;
+
+    fn check(source: &str) -> Vec<String> {
+        let class = CompileEngine::compile(Tokens::tokenize(source)).unwrap();
+        let program = Program::new(std::slice::from_ref(&class));
+        match check_class(&class, &program) {
+            Ok(()) => vec![],
+            Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_class() {
+        let source = "
+class Counter {
+    field int count;
+    constructor Counter new() { let count = 0; return this; }
+    method void add(int n) { let count = count + n; return; }
+    method int get() { return count; }
+    function void main() {
+        var Counter counter;
+        let counter = Counter.new();
+        do counter.add(Math.max(1, 2));
+        do Output.printInt(counter.get());
+        return;
+    }
+}";
+        assert!(check(source).is_empty());
+    }
+
+    #[test]
+    fn reports_unknown_class_only_where_declared() {
+        let source = "
+class Main {
+    function void main() {
+        var Foo foo;
+        do foo.run();
+        let foo = foo.get();
+        return;
+    }
+}";
+        assert_eq!(check(source), ["4:17: unknown class 'Foo'"]);
+    }
+
+    #[test]
+    fn reports_variable_errors() {
+        let source = "
+class Main {
+    field int x;
+    function void main(int a) {
+        var int a, b;
+        let x = c;
+        return;
+    }
+}";
+        assert_eq!(
+            check(source),
+            [
+                "5:17: 'a' is already declared",
+                "6:13: field 'x' can't be used in a function",
+                "6:17: unknown variable 'c'",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_return_errors() {
+        let source = "
+class Main {
+    function void f() { return 1; }
+    function int g() { return; }
+}";
+        assert_eq!(
+            check(source),
+            [
+                "3:25: a void subroutine can't return a value",
+                "4:24: expected a return value",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_call_errors() {
+        let source = "
+class Main {
+    method void m() { return; }
+    function void main() {
+        var int n;
+        do m();
+        do Main.m();
+        do n.m();
+        do Math.abs();
+        do Main.missing();
+        let n = Main.main();
+        return;
+    }
+}";
+        assert_eq!(
+            check(source),
+            [
+                "6:12: method 'Main.m' is called from a function, which has no object to call it on",
+                "7:12: method 'Main.m' is called without an object",
+                "8:12: 'n' isn't an object, so has no methods",
+                "9:12: expected 1 argument to 'Math.abs', found 0",
+                "10:12: unknown subroutine 'Main.missing'",
+                "11:17: 'main' is void and can't be used in an expression",
+            ]
+        );
+    }
+}
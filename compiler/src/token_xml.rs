@@ -1,5 +1,4 @@
 use crate::tokenizer::Token;
-use quick_xml;
 use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
 use std::fs::File;
 
@@ -19,13 +18,8 @@ impl TokenXml {
     }
 
     pub fn add_token(&mut self, token: Token) -> quick_xml::Result<usize> {
-        let (tag_name, value) = match token {
-            Token::Identifier(variable) => ("identifier", variable),
-            Token::Int(int_const) => ("integerConstant", int_const.to_string()),
-            Token::Keyword(keyword) => ("keyword", keyword),
-            Token::Str(str_constant) => ("stringConstant", str_constant),
-            Token::Symbol(symbol) => ("symbol", symbol.to_string()),
-        };
+        let tag_name = tag_name(&token);
+        let value = token_value(&token);
 
         self.writer
             .write_event(Event::Start(BytesStart::borrowed_name(tag_name.as_bytes())))?;
@@ -41,3 +35,23 @@ impl TokenXml {
             .unwrap();
     }
 }
+
+pub fn tag_name(token: &Token) -> &'static str {
+    match token {
+        Token::Identifier(_) => "identifier",
+        Token::Int(_) => "integerConstant",
+        Token::Keyword(_) => "keyword",
+        Token::Str(_) => "stringConstant",
+        Token::Symbol(_) => "symbol",
+    }
+}
+
+pub fn token_value(token: &Token) -> String {
+    match token {
+        Token::Identifier(variable) => variable.clone(),
+        Token::Int(int_const) => int_const.to_string(),
+        Token::Keyword(keyword) => keyword.clone(),
+        Token::Str(str_constant) => str_constant.clone(),
+        Token::Symbol(symbol) => symbol.to_string(),
+    }
+}
@@ -1,9 +1,7 @@
-use std::fmt::Debug;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
-use std::vec;
+use crate::error::CompileError;
+use std::fmt;
 
-const KEYWORDS: [&'static str; 21] = [
+const KEYWORDS: [&str; 21] = [
     "class",
     "constructor",
     "function",
@@ -31,6 +29,11 @@ const SYMBOLS: [char; 19] = [
     '{', '}', '(', ')', '[', ']', '.', ',', ';', '+', '~', '-', '*', '/', '&', '|', '<', '>', '=',
 ];
 
+// String constants are made of the printable ASCII characters of the Jack character set
+fn is_jack_char(ch: char) -> bool {
+    (' '..='~').contains(&ch)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Keyword(String),
@@ -40,160 +43,431 @@ pub enum Token {
     Str(String),
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Keyword(keyword) => write!(f, "'{}'", keyword),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+            Token::Identifier(name) => write!(f, "identifier '{}'", name),
+            Token::Int(int_const) => write!(f, "integer {}", int_const),
+            Token::Str(str_const) => write!(f, "string \"{}\"", str_const),
+        }
+    }
+}
+
+// Line and column of the first character of a token, both counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+// The characters of a source, looked at byte by byte. Anything that isn't ASCII is only
+// ever part of a comment or a string constant, or an unexpected character.
 #[derive(Debug)]
-struct FileCharIter {
-    lines: Lines<BufReader<File>>,
-    curr_chars: Vec<char>,
+struct SourceChars<'a> {
+    source: &'a str,
+    index: usize,
+    // Position of the next character
+    position: Span,
 }
 
-impl FileCharIter {
-    fn new(jack_file: File) -> Self {
-        let lines = BufReader::new(jack_file).lines();
-        FileCharIter {
-            lines,
-            curr_chars: vec![],
+impl<'a> SourceChars<'a> {
+    fn new(source: &'a str) -> Self {
+        SourceChars {
+            source,
+            index: 0,
+            position: Span { line: 1, column: 1 },
         }
     }
 
-    fn peek(&mut self, index: usize) -> Option<char> {
-        while index >= self.curr_chars.len() {
-            let next_line = self.lines.next();
-            if next_line.is_none() {
-                break;
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.as_bytes().get(self.index + offset).copied()
+    }
+
+    fn move_forward(&mut self, count: usize) {
+        let end = (self.index + count).min(self.source.len());
+        for &byte in &self.source.as_bytes()[self.index..end] {
+            if byte == b'\n' {
+                self.position.line += 1;
+                self.position.column = 1;
+            } else if byte & 0xc0 != 0x80 {
+                // Columns count characters, so the rest of a UTF-8 sequence is skipped over
+                self.position.column += 1;
             }
-            let mut next_chars = next_line.unwrap().unwrap().chars().collect::<Vec<char>>();
-            next_chars.push('\n');
-            self.curr_chars.reverse();
-            self.curr_chars.append(&mut next_chars);
-            self.curr_chars.reverse();
-        }
-        if index < self.curr_chars.len() {
-            self.curr_chars
-                .get(self.curr_chars.len() - 1 - index)
-                .map(|&ch| ch)
-        } else {
-            None
         }
+        self.index = end;
     }
 
-    fn collect_while<F>(&mut self, pred: F) -> String
+    fn take_while<F>(&mut self, pred: F) -> &'a str
     where
-        F: Fn(char) -> bool,
+        F: Fn(u8) -> bool,
     {
-        let mut peek_char = self.peek(0);
-        let mut result = String::new();
-        while peek_char.is_some() && pred(peek_char.unwrap()) {
-            result.push(self.next().unwrap());
-            peek_char = self.peek(0);
-        }
-        result
+        let start = self.index;
+        let len = self.source.as_bytes()[start..]
+            .iter()
+            .take_while(|&&byte| pred(byte))
+            .count();
+        self.move_forward(len);
+        // Predicates stop at ASCII bytes, so the slice ends on a character boundary
+        &self.source[start..self.index]
     }
 
-    fn move_forward(&mut self, count: usize) {
-        for _ in 0..count {
-            self.next();
-        }
+    // The character at the current position, moving past it
+    fn next_char(&mut self) -> Option<char> {
+        let ch = self.source[self.index..].chars().next()?;
+        self.move_forward(ch.len_utf8());
+        Some(ch)
     }
 
-    fn skip_multiline_comment(&mut self) {
-        let mut tok1 = self.peek(0);
-        let mut tok2 = self.peek(1);
-        while (tok1.is_some() && tok1.unwrap() != '*') || (tok2.is_some() && tok2.unwrap() != '/') {
-            self.next();
-            tok1 = tok2;
-            tok2 = self.peek(1);
+    // Skips a comment starting with /*, returning whether it ends before the end of the source
+    fn skip_multiline_comment(&mut self) -> bool {
+        match self.source[self.index + 2..].find("*/") {
+            Some(offset) => {
+                self.move_forward(offset + 4);
+                true
+            }
+            None => {
+                self.move_forward(self.source.len() - self.index);
+                false
+            }
         }
-        self.move_forward(2);
-    }
-}
-
-impl Iterator for FileCharIter {
-    type Item = char;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.curr_chars.pop().or_else(|| {
-            self.lines.next().and_then(|result| {
-                self.curr_chars = result.unwrap().chars().collect::<Vec<char>>();
-                self.curr_chars.push('\n');
-                self.curr_chars.reverse();
-                self.curr_chars.pop()
-            })
-        })
     }
 }
 
 #[derive(Debug)]
-pub struct Tokens {
-    chars: FileCharIter,
+pub struct Tokens<'a> {
+    chars: SourceChars<'a>,
+    // Returned after the token or error before it: the token standing in for an invalid
+    // constant after its error, or an error in a string constant after the string
+    pending: Option<Result<SpannedToken, CompileError>>,
 }
 
-impl Tokens {
-    pub fn tokenize(jack_file: File) -> Self {
+impl<'a> Tokens<'a> {
+    pub fn tokenize(source: &'a str) -> Self {
         Tokens {
-            chars: FileCharIter::new(jack_file),
+            chars: SourceChars::new(source),
+            pending: None,
         }
     }
+
+    fn error_with_token(&mut self, message: String, token: Token, span: Span) -> CompileError {
+        self.pending = Some(Ok(SpannedToken { token, span }));
+        CompileError::new(span, message)
+    }
 }
 
-impl Iterator for Tokens {
-    type Item = Token;
+// Lexical errors are returned in place of tokens. Invalid constants are followed by a
+// constant of the same kind, so that parsing goes on as usual, and other characters in
+// error are skipped.
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<SpannedToken, CompileError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(result) = self.pending.take() {
+            return Some(result);
+        }
         loop {
             // skip the leading white spaces
-            self.chars.collect_while(|ch| ch.is_whitespace());
-
-            let ch = self.chars.peek(0);
+            self.chars.take_while(|byte| byte.is_ascii_whitespace());
 
-            if ch.is_none() {
-                return None;
-            }
-            let ch = ch.unwrap();
+            let byte = self.chars.peek(0)?;
+            let span = self.chars.position;
 
-            if ch == '/' {
-                let ch2 = self.chars.peek(1);
-                if ch2.is_some() && ch2.unwrap() == '/' {
-                    self.chars.move_forward(2);
-                    self.chars.collect_while(|ch| ch != '\n');
-                    continue;
-                } else if ch2.is_some() && ch2.unwrap() == '*' {
-                    self.chars.skip_multiline_comment();
+            if byte == b'/' {
+                let byte2 = self.chars.peek(1);
+                if byte2 == Some(b'/') {
+                    self.chars.take_while(|byte| byte != b'\n');
                     continue;
+                } else if byte2 == Some(b'*') {
+                    if self.chars.skip_multiline_comment() {
+                        continue;
+                    }
+                    return Some(Err(CompileError::new(
+                        span,
+                        "unterminated comment".to_owned(),
+                    )));
                 }
             }
 
-            if SYMBOLS.contains(&ch) {
+            let token = if SYMBOLS.contains(&(byte as char)) {
                 self.chars.move_forward(1);
-                return Some(Token::Symbol(ch));
-            }
-
-            if ch.is_digit(10) {
-                let int_const = self
+                Token::Symbol(byte as char)
+            } else if byte.is_ascii_digit() {
+                let digits = self
                     .chars
-                    .collect_while(|ch| ch.is_digit(10))
-                    .parse::<i16>()
-                    .unwrap();
-                return Some(Token::Int(int_const));
-            }
-
-            if ch.is_ascii_alphabetic() {
+                    .take_while(|byte| byte.is_ascii_alphanumeric() || byte == b'_');
+                match digits.parse::<i16>() {
+                    Ok(int_const) => Token::Int(int_const),
+                    Err(_) => {
+                        let message = if digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                            format!("integer {} is out of range 0..{}", digits, i16::MAX)
+                        } else {
+                            format!("invalid integer {}", digits)
+                        };
+                        return Some(Err(self.error_with_token(message, Token::Int(0), span)));
+                    }
+                }
+            } else if byte.is_ascii_alphabetic() || byte == b'_' {
                 let token = self
                     .chars
-                    .collect_while(|ch| ch.is_ascii_alphabetic() || ch.is_digit(10) || ch == '_');
+                    .take_while(|byte| byte.is_ascii_alphanumeric() || byte == b'_');
 
-                if KEYWORDS.contains(&token.as_str()) {
-                    return Some(Token::Keyword(token));
+                if KEYWORDS.contains(&token) {
+                    Token::Keyword(token.to_owned())
                 } else {
-                    return Some(Token::Identifier(token));
+                    Token::Identifier(token.to_owned())
                 }
-            }
-
-            if ch == '"' {
+            } else if byte == b'"' {
+                // String constants can't span lines
                 self.chars.move_forward(1);
-                let str_const = self.chars.collect_while(|ch| ch != '"');
+                let str_const = self.chars.take_while(|byte| byte != b'"' && byte != b'\n');
+                let jack_chars = || str_const.chars().filter(|&ch| is_jack_char(ch));
+                if self.chars.peek(0) != Some(b'"') {
+                    let message = "unterminated string".to_owned();
+                    let token = Token::Str(jack_chars().collect());
+                    return Some(Err(self.error_with_token(message, token, span)));
+                }
                 self.chars.move_forward(1);
-                return Some(Token::Str(str_const));
+                // The first character that isn't Jack is reported, and all of them left out
+                let invalid = str_const
+                    .chars()
+                    .enumerate()
+                    .find(|&(_, ch)| !is_jack_char(ch));
+                if let Some((offset, ch)) = invalid {
+                    let message = format!("unexpected character {:?} in string", ch);
+                    let column = span.column + 1 + offset;
+                    let error_span = Span { column, ..span };
+                    self.pending = Some(Err(CompileError::new(error_span, message)));
+                }
+                Token::Str(jack_chars().collect())
+            } else {
+                let ch = self.chars.next_char()?;
+                let message = format!("unexpected character {:?}", ch);
+                return Some(Err(CompileError::new(span, message)));
+            };
+            return Some(Ok(SpannedToken { token, span }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Longest time the tokenizer may take on an input before it's deemed not to terminate
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_PIECES: usize = 60;
+    const FUZZ_SEEDS: std::ops::Range<u64> = 0..5000;
+
+    // Inputs are made of these, along with random bytes that may not be valid UTF-8. They
+    // include unfinished comments and strings, integers out of range and characters that
+    // aren't Jack.
+    const PIECES: [&str; 36] = [
+        "class",
+        "let",
+        "x",
+        "_y1",
+        "0",
+        "32767",
+        "32768",
+        "99999999999999999999",
+        "12ab",
+        "\"",
+        "\"str\"",
+        "/*",
+        "*/",
+        "/**/",
+        "/*/",
+        "//",
+        "/",
+        "*",
+        "\n",
+        "\r\n",
+        " ",
+        "\t",
+        "{",
+        "}",
+        ";",
+        "-",
+        "<",
+        "#",
+        "@",
+        "$",
+        "`",
+        "\\",
+        "'",
+        "é",
+        "\u{0}",
+        "\u{feff}",
+    ];
+
+    // Xorshift generator, so that an input can be generated again from its seed
+    struct Random(u64);
+
+    impl Random {
+        fn new(seed: u64) -> Self {
+            Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn generate(seed: u64) -> Vec<u8> {
+        let mut random = Random::new(seed);
+        let mut input = vec![];
+        for _ in 0..random.below(MAX_PIECES) {
+            if random.below(8) == 0 {
+                input.push(random.below(256) as u8);
+            } else {
+                input.extend(PIECES[random.below(PIECES.len())].as_bytes());
+            }
+        }
+        input
+    }
+
+    // Tokenizes in another thread, so that an endless loop is caught
+    fn check(input: String) -> Result<(), String> {
+        let input_len = input.len();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let spans: Vec<(Span, Option<Token>)> = Tokens::tokenize(&input)
+                .take(2 * input_len + 1)
+                .map(|result| match result {
+                    Ok(spanned_token) => (spanned_token.span, Some(spanned_token.token)),
+                    Err(error) => (error.span, None),
+                })
+                .collect();
+            // The receiver is gone if the check timed out
+            sender.send(spans).ok();
+        });
+        let items = receiver
+            .recv_timeout(TIMEOUT)
+            .map_err(|_| format!("tokenizing took more than {:?}", TIMEOUT))?;
+
+        // Every token or error takes at least a byte, invalid constants giving both
+        if items.len() > 2 * input_len {
+            return Err(format!(
+                "{} tokens and errors from {} bytes",
+                items.len(),
+                input_len
+            ));
+        }
+        for pair in items.windows(2) {
+            let (first, second) = (pair[0].0, pair[1].0);
+            if (second.line, second.column) < (first.line, first.column) {
+                return Err(format!(
+                    "token at {}:{} follows one at {}:{}",
+                    second.line, second.column, first.line, first.column
+                ));
             }
         }
+        for (span, token) in items.iter() {
+            if let Some(Token::Int(int_const)) = token {
+                if *int_const < 0 {
+                    return Err(format!(
+                        "integer {} at {}:{} is out of range",
+                        int_const, span.line, span.column
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Errors as `line:column: message`
+    fn tokens(source: &str) -> Vec<Result<Token, String>> {
+        Tokens::tokenize(source)
+            .map(|result| {
+                result
+                    .map(|spanned_token| spanned_token.token)
+                    .map_err(|error| error.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn terminates_on_random_inputs() {
+        for seed in FUZZ_SEEDS {
+            // Decoded as the compiler does its input files
+            let input = String::from_utf8_lossy(&generate(seed)).into_owned();
+            if let Err(error) = check(input.clone()) {
+                panic!("seed {}: {}\ninput: {:?}", seed, error, input);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_integers_out_of_range() {
+        assert_eq!(
+            tokens("x 40000;"),
+            [
+                Ok(Token::Identifier("x".to_owned())),
+                Err("1:3: integer 40000 is out of range 0..32767".to_owned()),
+                Ok(Token::Int(0)),
+                Ok(Token::Symbol(';')),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_strings() {
+        assert_eq!(
+            tokens("\"abc\r\nx"),
+            [
+                Err("1:1: unterminated string".to_owned()),
+                Ok(Token::Str("abc".to_owned())),
+                Ok(Token::Identifier("x".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_comments() {
+        assert_eq!(
+            tokens("x\n  /* comment */ /* no end"),
+            [
+                Ok(Token::Identifier("x".to_owned())),
+                Err("2:17: unterminated comment".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_characters_outside_jack_in_strings() {
+        assert_eq!(
+            tokens("x \"a\u{fffd}b\té\";"),
+            [
+                Ok(Token::Identifier("x".to_owned())),
+                Ok(Token::Str("ab".to_owned())),
+                Err("1:5: unexpected character '\u{fffd}' in string".to_owned()),
+                Ok(Token::Symbol(';')),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unexpected_characters() {
+        assert_eq!(
+            tokens("#x"),
+            [
+                Err("1:1: unexpected character '#'".to_owned()),
+                Ok(Token::Identifier("x".to_owned())),
+            ]
+        );
     }
 }
@@ -0,0 +1,344 @@
+use crate::grammar::*;
+
+// Passes over the syntax tree implement the methods for the nodes they look at, calling
+// the walk function of a node to go on to its children. The default methods just walk.
+pub trait Visitor<'ast> {
+    fn visit_class(&mut self, class: &'ast Class) {
+        walk_class(self, class);
+    }
+
+    fn visit_var(&mut self, _var: &'ast Var) {}
+
+    fn visit_subroutine(&mut self, sub: &'ast Subroutine) {
+        walk_subroutine(self, sub);
+    }
+
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_term(&mut self, term: &'ast Term) {
+        walk_term(self, term);
+    }
+
+    fn visit_var_expr(&mut self, var_expr: &'ast VarExpr) {
+        walk_var_expr(self, var_expr);
+    }
+
+    fn visit_sub_call(&mut self, sub_call: &'ast SubCall) {
+        walk_sub_call(self, sub_call);
+    }
+}
+
+pub fn walk_class<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, class: &'ast Class) {
+    for var in class.static_vars.iter().chain(&class.field_vars) {
+        visitor.visit_var(var);
+    }
+    for sub in &class.subroutines {
+        visitor.visit_subroutine(sub);
+    }
+}
+
+pub fn walk_subroutine<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, sub: &'ast Subroutine) {
+    for var in sub.params.iter().chain(&sub.locals) {
+        visitor.visit_var(var);
+    }
+    for stmt in &sub.stmts {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, stmt: &'ast Stmt) {
+    match stmt {
+        Stmt::Let(stmt) => {
+            visitor.visit_var_expr(&stmt.lvalue);
+            visitor.visit_expr(&stmt.rvalue);
+        }
+        Stmt::If(stmt) => {
+            visitor.visit_expr(&stmt.cond);
+            let false_stmts = stmt.false_stmts.iter().flatten();
+            for stmt in stmt.true_stmts.iter().chain(false_stmts) {
+                visitor.visit_stmt(stmt);
+            }
+        }
+        Stmt::While(stmt) => {
+            visitor.visit_expr(&stmt.cond);
+            for stmt in &stmt.stmts {
+                visitor.visit_stmt(stmt);
+            }
+        }
+        Stmt::Do(stmt) => visitor.visit_sub_call(&stmt.sub_call),
+        Stmt::Return(stmt) => {
+            if let Some(expr) = &stmt.expr {
+                visitor.visit_expr(expr);
+            }
+        }
+    }
+}
+
+pub fn walk_expr<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, expr: &'ast Expr) {
+    visitor.visit_term(&expr.term);
+    for (_, term) in &expr.rest {
+        visitor.visit_term(term);
+    }
+}
+
+pub fn walk_term<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, term: &'ast Term) {
+    match term {
+        Term::Var(var_expr) => visitor.visit_var_expr(var_expr),
+        Term::Call(sub_call) => visitor.visit_sub_call(sub_call),
+        Term::Expr(expr, _) => visitor.visit_expr(expr),
+        Term::Unary(_, term, _) => visitor.visit_term(term),
+        Term::Int(..) | Term::Str(..) | Term::Keyword(..) => {}
+    }
+}
+
+pub fn walk_var_expr<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, var_expr: &'ast VarExpr) {
+    if let Some(index) = &var_expr.index {
+        visitor.visit_expr(index);
+    }
+}
+
+pub fn walk_sub_call<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, sub_call: &'ast SubCall) {
+    for arg in &sub_call.args {
+        visitor.visit_expr(arg);
+    }
+}
+
+// The same as Visitor, for passes that rewrite the tree in place
+pub trait VisitorMut {
+    fn visit_class_mut(&mut self, class: &mut Class) {
+        walk_class_mut(self, class);
+    }
+
+    fn visit_var_mut(&mut self, _var: &mut Var) {}
+
+    fn visit_subroutine_mut(&mut self, sub: &mut Subroutine) {
+        walk_subroutine_mut(self, sub);
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(self, stmt);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+
+    fn visit_term_mut(&mut self, term: &mut Term) {
+        walk_term_mut(self, term);
+    }
+
+    fn visit_var_expr_mut(&mut self, var_expr: &mut VarExpr) {
+        walk_var_expr_mut(self, var_expr);
+    }
+
+    fn visit_sub_call_mut(&mut self, sub_call: &mut SubCall) {
+        walk_sub_call_mut(self, sub_call);
+    }
+}
+
+pub fn walk_class_mut<V: VisitorMut + ?Sized>(visitor: &mut V, class: &mut Class) {
+    for var in class.static_vars.iter_mut().chain(&mut class.field_vars) {
+        visitor.visit_var_mut(var);
+    }
+    for sub in &mut class.subroutines {
+        visitor.visit_subroutine_mut(sub);
+    }
+}
+
+pub fn walk_subroutine_mut<V: VisitorMut + ?Sized>(visitor: &mut V, sub: &mut Subroutine) {
+    for var in sub.params.iter_mut().chain(&mut sub.locals) {
+        visitor.visit_var_mut(var);
+    }
+    for stmt in &mut sub.stmts {
+        visitor.visit_stmt_mut(stmt);
+    }
+}
+
+pub fn walk_stmt_mut<V: VisitorMut + ?Sized>(visitor: &mut V, stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Let(stmt) => {
+            visitor.visit_var_expr_mut(&mut stmt.lvalue);
+            visitor.visit_expr_mut(&mut stmt.rvalue);
+        }
+        Stmt::If(stmt) => {
+            visitor.visit_expr_mut(&mut stmt.cond);
+            let false_stmts = stmt.false_stmts.iter_mut().flatten();
+            for stmt in stmt.true_stmts.iter_mut().chain(false_stmts) {
+                visitor.visit_stmt_mut(stmt);
+            }
+        }
+        Stmt::While(stmt) => {
+            visitor.visit_expr_mut(&mut stmt.cond);
+            for stmt in &mut stmt.stmts {
+                visitor.visit_stmt_mut(stmt);
+            }
+        }
+        Stmt::Do(stmt) => visitor.visit_sub_call_mut(&mut stmt.sub_call),
+        Stmt::Return(stmt) => {
+            if let Some(expr) = &mut stmt.expr {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    visitor.visit_term_mut(&mut expr.term);
+    for (_, term) in &mut expr.rest {
+        visitor.visit_term_mut(term);
+    }
+}
+
+pub fn walk_term_mut<V: VisitorMut + ?Sized>(visitor: &mut V, term: &mut Term) {
+    match term {
+        Term::Var(var_expr) => visitor.visit_var_expr_mut(var_expr),
+        Term::Call(sub_call) => visitor.visit_sub_call_mut(sub_call),
+        Term::Expr(expr, _) => visitor.visit_expr_mut(expr),
+        Term::Unary(_, term, _) => visitor.visit_term_mut(term),
+        Term::Int(..) | Term::Str(..) | Term::Keyword(..) => {}
+    }
+}
+
+pub fn walk_var_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, var_expr: &mut VarExpr) {
+    if let Some(index) = &mut var_expr.index {
+        visitor.visit_expr_mut(index);
+    }
+}
+
+pub fn walk_sub_call_mut<V: VisitorMut + ?Sized>(visitor: &mut V, sub_call: &mut SubCall) {
+    for arg in &mut sub_call.args {
+        visitor.visit_expr_mut(arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_engine::CompileEngine;
+    use crate::tokenizer::{Span, Tokens};
+
+    // Calls and constants in every place a term can be
+    const SOURCE: &str = "
+class Main {
+    field int x;
+    method int f(int a) {
+        var Array v;
+        let v[f(1)] = -(f(2) + 3);
+        if (~f(4)) {
+            while (f(5) < 6) { do f(7); }
+        } else {
+            do Output.printInt(f(8));
+        }
+        return f(9);
+    }
+}";
+
+    fn parse() -> Class {
+        CompileEngine::compile(Tokens::tokenize(SOURCE)).unwrap()
+    }
+
+    // Finds the calls, along with the span of every integer constant
+    #[derive(Default)]
+    struct Counter {
+        calls: usize,
+        int_spans: Vec<Span>,
+    }
+
+    impl<'ast> Visitor<'ast> for Counter {
+        fn visit_term(&mut self, term: &'ast Term) {
+            if let Term::Int(_, span) = term {
+                self.int_spans.push(*span);
+            }
+            walk_term(self, term);
+        }
+
+        fn visit_sub_call(&mut self, sub_call: &'ast SubCall) {
+            self.calls += 1;
+            walk_sub_call(self, sub_call);
+        }
+    }
+
+    // Doubles the integer constants
+    struct Doubler;
+
+    impl VisitorMut for Doubler {
+        fn visit_term_mut(&mut self, term: &mut Term) {
+            if let Term::Int(int_const, _) = term {
+                *int_const *= 2;
+            }
+            walk_term_mut(self, term);
+        }
+    }
+
+    fn int_consts(class: &Class) -> Vec<i16> {
+        struct Collector(Vec<i16>);
+
+        impl<'ast> Visitor<'ast> for Collector {
+            fn visit_term(&mut self, term: &'ast Term) {
+                if let Term::Int(int_const, _) = term {
+                    self.0.push(*int_const);
+                }
+                walk_term(self, term);
+            }
+        }
+
+        let mut collector = Collector(vec![]);
+        collector.visit_class(class);
+        collector.0
+    }
+
+    #[test]
+    fn visits_every_call_and_term() {
+        let mut counter = Counter::default();
+        counter.visit_class(&parse());
+        assert_eq!(counter.calls, 8);
+        let positions: Vec<(usize, usize)> = counter
+            .int_spans
+            .iter()
+            .map(|span| (span.line, span.column))
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (6, 17),
+                (6, 27),
+                (6, 32),
+                (7, 16),
+                (8, 22),
+                (8, 27),
+                (8, 37),
+                (10, 34),
+                (12, 18)
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_every_term() {
+        let mut class = parse();
+        Doubler.visit_class_mut(&mut class);
+        assert_eq!(int_consts(&class), [2, 4, 6, 8, 10, 12, 14, 16, 18]);
+    }
+
+    #[test]
+    fn gives_terms_and_expressions_their_first_token_span() {
+        let class = parse();
+        let expr = match &class.subroutines[0].stmts[0] {
+            Stmt::Let(stmt) => &stmt.rvalue,
+            _ => unreachable!(),
+        };
+        assert_eq!((expr.span.line, expr.span.column), (6, 23));
+        let paren_expr = match &*expr.term {
+            Term::Unary(UnaryOp::Neg, term, _) => term,
+            _ => unreachable!(),
+        };
+        let span = paren_expr.span();
+        assert_eq!((span.line, span.column), (6, 24));
+    }
+}
@@ -1,204 +1,206 @@
 use crate::grammar::*;
+use crate::program::{Program, Receiver};
 use crate::symtable::{SymTable, VarEntry};
-use std::fs::File;
+use std::io;
 use std::io::{BufWriter, Write};
 
-struct VmWriter {
-    writer: BufWriter<File>,
+struct VmWriter<'a, W: Write> {
+    writer: BufWriter<W>,
+    program: &'a Program,
     symtable: SymTable,
     label_count: usize,
+    // Use the extended VM instructions (mul, div) instead of calling Math
+    extended: bool,
 }
 
-pub fn generate_vm_code(class: Class, vm_file: File) {
-    let writer = BufWriter::new(vm_file);
+// The class must have passed the semantic checks against the program
+pub fn generate_vm_code<W: Write>(
+    class: Class,
+    program: &Program,
+    out: W,
+    extended: bool,
+) -> io::Result<()> {
+    let writer = BufWriter::new(out);
     let mut symtable = SymTable::new(class.name.as_str());
     symtable.load_class_symbols(&class);
     let mut vm_writer = VmWriter {
         writer,
+        program,
         symtable,
         label_count: 0,
+        extended,
     };
-    vm_writer.gen_class(class);
+    vm_writer.gen_class(class)?;
+    vm_writer.writer.flush()
 }
 
-impl VmWriter {
-    fn gen_class(&mut self, class: Class) {
+impl<'a, W: Write> VmWriter<'a, W> {
+    fn gen_class(&mut self, class: Class) -> io::Result<()> {
         for sub in class.subroutines {
-            self.gen_sub(sub, class.field_vars.len());
+            self.gen_sub(sub, class.field_vars.len())?;
         }
+        Ok(())
     }
 
-    fn gen_sub(&mut self, sub: Subroutine, fields_count: usize) {
+    fn gen_sub(&mut self, sub: Subroutine, fields_count: usize) -> io::Result<()> {
         self.label_count = 0;
         self.symtable.load_sub_symbols(&sub);
-        self.function(sub.name, sub.locals.len());
+        self.function(sub.name, sub.locals.len())?;
         if let SubKind::Method = sub.kind {
-            self.push("argument", 0);
-            self.pop("pointer", 0);
+            self.push("argument", 0)?;
+            self.pop("pointer", 0)?;
         } else if let SubKind::Constructor = sub.kind {
-            self.push("constant", fields_count as i16);
-            self.call("Memory.alloc", 1);
-            self.pop("pointer", 0);
+            self.push("constant", fields_count as i16)?;
+            self.call("Memory.alloc", 1)?;
+            self.pop("pointer", 0)?;
         }
-        self.gen_stmts(&sub.stmts);
+        self.gen_stmts(&sub.stmts)
     }
 
-    fn gen_stmts(&mut self, stmts: &Vec<Box<dyn Stmt>>) {
+    fn gen_stmts(&mut self, stmts: &[Stmt]) -> io::Result<()> {
         for stmt in stmts {
-            self.gen_stmt(stmt);
+            self.gen_stmt(stmt)?;
         }
+        Ok(())
     }
 
-    fn gen_stmt(&mut self, stmt: &Box<dyn Stmt>) {
-        let stmt_kind = stmt.kind();
-        let any_stmt = stmt.as_any();
-        match stmt_kind {
-            StmtKind::Let => self.gen_let(any_stmt.downcast_ref::<Let>().unwrap()),
-            StmtKind::If => self.gen_if(any_stmt.downcast_ref::<If>().unwrap()),
-            StmtKind::While => self.gen_while(any_stmt.downcast_ref::<While>().unwrap()),
-            StmtKind::Do => self.gen_do(any_stmt.downcast_ref::<Do>().unwrap()),
-            StmtKind::Return => self.gen_return(any_stmt.downcast_ref::<Return>().unwrap()),
+    fn gen_stmt(&mut self, stmt: &Stmt) -> io::Result<()> {
+        match stmt {
+            Stmt::Let(stmt) => self.gen_let(stmt),
+            Stmt::If(stmt) => self.gen_if(stmt),
+            Stmt::While(stmt) => self.gen_while(stmt),
+            Stmt::Do(stmt) => self.gen_do(stmt),
+            Stmt::Return(stmt) => self.gen_return(stmt),
         }
     }
 
-    fn gen_let(&mut self, stmt: &Let) {
-        self.gen_expr(&stmt.rvalue);
+    fn gen_let(&mut self, stmt: &Let) -> io::Result<()> {
+        self.gen_expr(&stmt.rvalue)?;
 
-        let var_entry = self
-            .symtable
-            .lookup(&stmt.lvalue.name)
-            .cloned()
-            .expect("Unknown lvalue in let statement");
+        let var_entry = self.lookup(&stmt.lvalue);
 
         let (dest_seg, dest_index) = if stmt.lvalue.index.is_some() {
-            self.gen_that(&stmt.lvalue, var_entry);
+            self.gen_that(&stmt.lvalue, var_entry)?;
             ("that", 0)
         } else {
             (var_entry.kind.seg_name(), var_entry.index)
         };
-        self.pop(dest_seg, dest_index);
+        self.pop(dest_seg, dest_index)
     }
 
-    fn gen_if(&mut self, stmt: &If) {
+    fn gen_if(&mut self, stmt: &If) -> io::Result<()> {
         let if_true = self.get_label();
         let if_false = self.get_label();
         let if_end = self.get_label();
 
-        self.gen_expr(&stmt.cond);
-        self.ifgoto(&if_true);
-        self.goto(&if_false);
-        self.label(&if_true);
-        self.gen_stmts(&stmt.true_stmts);
-        self.goto(&if_end);
-        self.label(&if_false);
+        self.gen_expr(&stmt.cond)?;
+        self.ifgoto(&if_true)?;
+        self.goto(&if_false)?;
+        self.label(&if_true)?;
+        self.gen_stmts(&stmt.true_stmts)?;
+        self.goto(&if_end)?;
+        self.label(&if_false)?;
         if let Some(false_stmts) = stmt.false_stmts.as_ref() {
-            self.gen_stmts(false_stmts);
+            self.gen_stmts(false_stmts)?;
         }
-        self.label(&if_end);
+        self.label(&if_end)
     }
 
-    fn gen_while(&mut self, stmt: &While) {
+    fn gen_while(&mut self, stmt: &While) -> io::Result<()> {
         let loop_start = self.get_label();
         let loop_end = self.get_label();
         let if_true = self.get_label();
 
-        self.label(&loop_start);
-        self.gen_expr(&stmt.cond);
-        self.ifgoto(&if_true);
-        self.goto(&loop_end);
-        self.label(&if_true);
-        self.gen_stmts(&stmt.stmts);
-        self.goto(&loop_start);
-        self.label(&loop_end);
+        self.label(&loop_start)?;
+        self.gen_expr(&stmt.cond)?;
+        self.ifgoto(&if_true)?;
+        self.goto(&loop_end)?;
+        self.label(&if_true)?;
+        self.gen_stmts(&stmt.stmts)?;
+        self.goto(&loop_start)?;
+        self.label(&loop_end)
     }
 
-    fn gen_do(&mut self, stmt: &Do) {
-        self.gen_sub_call(&stmt.0);
-        self.pop("temp", 0);
+    fn gen_do(&mut self, stmt: &Do) -> io::Result<()> {
+        self.gen_sub_call(&stmt.sub_call)?;
+        self.pop("temp", 0)
     }
 
-    fn gen_return(&mut self, stmt: &Return) {
-        if let Some(expr) = &stmt.0 {
-            self.gen_expr(expr);
+    fn gen_return(&mut self, stmt: &Return) -> io::Result<()> {
+        if let Some(expr) = &stmt.expr {
+            self.gen_expr(expr)?;
         } else {
-            self.push("constant", 0);
+            self.push("constant", 0)?;
         }
-        writeln!(self.writer, "return").unwrap();
+        writeln!(self.writer, "return")
     }
 
-    fn gen_sub_call(&mut self, sub_call: &SubCall) {
-        let recv_name = sub_call.recv_name.clone().unwrap_or("this".to_owned());
-        let recv_symbol_entry = self.symtable.lookup(&recv_name).cloned();
-
-        let recv_class_name = recv_symbol_entry
-            .as_ref()
-            .and_then(|var_entry| {
-                if let Type::Class(class_name) = &var_entry.vtype {
-                    Some(class_name)
-                } else {
-                    None
-                }
-            })
-            .or_else(|| {
-                if recv_name == "this" {
-                    Some(&self.symtable.class_name)
-                } else {
-                    None
+    fn gen_sub_call(&mut self, sub_call: &SubCall) -> io::Result<()> {
+        let receiver = self
+            .program
+            .receiver(sub_call.recv_name.as_deref(), &self.symtable);
+        // Methods get the object they are called on as their first argument
+        let (class_name, object) = match receiver {
+            Some(Receiver::This) => {
+                // `this` is argument 0 in methods, and pointer 0 in constructors
+                let object = match self.symtable.lookup("this") {
+                    Some(var_entry) => (var_entry.kind.seg_name().to_owned(), var_entry.index),
+                    None => ("pointer".to_owned(), 0),
+                };
+                (self.symtable.class_name.clone(), Some(object))
+            }
+            Some(Receiver::Var(var_entry)) => match var_entry.vtype {
+                Type::Class(class_name) => {
+                    let object = (var_entry.kind.seg_name().to_owned(), var_entry.index);
+                    (class_name, Some(object))
                 }
-            });
-
-        let fn_name = if let Some(class_name) = recv_class_name {
-            format!("{}.{}", class_name, sub_call.sub_name)
-        } else {
-            format!("{}.{}", recv_name, sub_call.sub_name)
+                _ => unreachable!("method call on a variable that isn't an object"),
+            },
+            Some(Receiver::Class(class_name)) => (class_name, None),
+            None => unreachable!("call on an unknown variable or class"),
         };
 
         let mut args_count = sub_call.args.len();
-        if let Some(recv_entry) = recv_symbol_entry {
-            self.push(recv_entry.kind.seg_name(), recv_entry.index as i16);
-            args_count += 1;
-        } else if recv_name == "this" {
-            self.push("pointer", 0);
+        if let Some((segment, index)) = object {
+            self.push(&segment, index as i16)?;
             args_count += 1;
         }
 
         for expr in &sub_call.args {
-            self.gen_expr(expr);
+            self.gen_expr(expr)?;
         }
 
-        self.call(&fn_name, args_count);
+        let fn_name = format!("{}.{}", class_name, sub_call.sub_name);
+        self.call(&fn_name, args_count)
     }
 
-    fn gen_expr(&mut self, expr: &Expr) {
-        self.gen_term(&expr.lterm);
-        if let Some(rterm) = expr.rterm.as_ref() {
-            self.gen_term(rterm);
-            self.gen_binary_op(expr.op.as_ref().unwrap());
+    fn gen_expr(&mut self, expr: &Expr) -> io::Result<()> {
+        self.gen_term(&expr.term)?;
+        for (op, term) in &expr.rest {
+            self.gen_term(term)?;
+            self.gen_binary_op(op)?;
         }
+        Ok(())
     }
 
-    fn gen_term(&mut self, term: &Box<Term>) {
-        match &**term {
-            Term::Int(int_const) => self.push("constant", *int_const),
-            Term::Str(str_const) => self.gen_str_const(str_const),
-            Term::Keyword(keyword_const) => self.gen_keyword_const(keyword_const),
+    fn gen_term(&mut self, term: &Term) -> io::Result<()> {
+        match term {
+            Term::Int(int_const, _) => self.push("constant", *int_const),
+            Term::Str(str_const, _) => self.gen_str_const(str_const),
+            Term::Keyword(keyword_const, _) => self.gen_keyword_const(keyword_const),
             Term::Var(var_expr) => {
-                let var_entry = self
-                    .symtable
-                    .lookup(&var_expr.name)
-                    .cloned()
-                    .expect("Unknown lvalue in let statement");
+                let var_entry = self.lookup(var_expr);
                 if var_expr.index.is_some() {
-                    self.gen_that(var_expr, var_entry);
-                    self.push("that", 0);
+                    self.gen_that(var_expr, var_entry)?;
+                    self.push("that", 0)
                 } else {
-                    self.push(var_entry.kind.seg_name(), var_entry.index as i16);
+                    self.push(var_entry.kind.seg_name(), var_entry.index as i16)
                 }
             }
             Term::Call(sub_call) => self.gen_sub_call(sub_call),
-            Term::Expr(expr) => self.gen_expr(expr),
-            Term::Unary(unary_op, term) => {
-                self.gen_term(term);
+            Term::Expr(expr, _) => self.gen_expr(expr),
+            Term::Unary(unary_op, term, _) => {
+                self.gen_term(term)?;
                 match unary_op {
                     UnaryOp::Neg => self.command("neg"),
                     UnaryOp::Not => self.command("not"),
@@ -207,27 +209,35 @@ impl VmWriter {
         }
     }
 
-    fn gen_str_const(&mut self, str_const: &String) {
-        self.push("constant", str_const.len() as i16);
-        self.call("String.new", 1);
+    fn lookup(&self, var_expr: &VarExpr) -> VarEntry {
+        self.symtable
+            .lookup(&var_expr.name)
+            .cloned()
+            .unwrap_or_else(|| panic!("Unknown variable {}", var_expr.name))
+    }
+
+    fn gen_str_const(&mut self, str_const: &str) -> io::Result<()> {
+        self.push("constant", str_const.chars().count() as i16)?;
+        self.call("String.new", 1)?;
         for ch in str_const.chars() {
-            self.push("constant", ch as i16);
-            self.call("String.appendChar", 2);
+            self.push("constant", ch as i16)?;
+            self.call("String.appendChar", 2)?;
         }
+        Ok(())
     }
 
-    fn gen_that(&mut self, var_expr: &VarExpr, var_entry: VarEntry) {
-        self.push(var_entry.kind.seg_name(), var_entry.index as i16);
-        self.gen_expr(var_expr.index.as_ref().unwrap());
-        self.command("add");
-        self.pop("pointer", 1);
+    fn gen_that(&mut self, var_expr: &VarExpr, var_entry: VarEntry) -> io::Result<()> {
+        self.push(var_entry.kind.seg_name(), var_entry.index as i16)?;
+        self.gen_expr(var_expr.index.as_ref().unwrap())?;
+        self.command("add")?;
+        self.pop("pointer", 1)
     }
 
-    fn gen_keyword_const(&mut self, keyword_const: &KeywordConst) {
+    fn gen_keyword_const(&mut self, keyword_const: &KeywordConst) -> io::Result<()> {
         match keyword_const {
             KeywordConst::True => {
-                self.push("constant", 1);
-                self.command("neg");
+                self.push("constant", 1)?;
+                self.command("neg")
             }
             KeywordConst::False => self.push("constant", 0),
             KeywordConst::Null => self.push("constant", 0),
@@ -235,10 +245,12 @@ impl VmWriter {
         }
     }
 
-    fn gen_binary_op(&mut self, op: &BinaryOp) {
+    fn gen_binary_op(&mut self, op: &BinaryOp) -> io::Result<()> {
         let vm_op = match op {
             BinaryOp::Add => "add",
             BinaryOp::Sub => "sub",
+            BinaryOp::Mul if self.extended => "mul",
+            BinaryOp::Div if self.extended => "div",
             BinaryOp::Mul => "call Math.multiply 2",
             BinaryOp::Div => "call Math.divide 2",
             BinaryOp::And => "and",
@@ -247,7 +259,7 @@ impl VmWriter {
             BinaryOp::Gt => "gt",
             BinaryOp::Eq => "eq",
         };
-        writeln!(self.writer, "{}", vm_op).unwrap();
+        writeln!(self.writer, "{}", vm_op)
     }
 
     fn get_label(&mut self) -> String {
@@ -256,40 +268,39 @@ impl VmWriter {
         label
     }
 
-    fn function(&mut self, name: String, local_count: usize) {
+    fn function(&mut self, name: String, local_count: usize) -> io::Result<()> {
         writeln!(
             self.writer,
             "function {}.{} {}",
             self.symtable.class_name, name, local_count
         )
-        .unwrap();
     }
 
-    fn push(&mut self, seg: &str, index: i16) {
-        writeln!(self.writer, "push {} {}", seg, index).unwrap();
+    fn push(&mut self, seg: &str, index: i16) -> io::Result<()> {
+        writeln!(self.writer, "push {} {}", seg, index)
     }
 
-    fn pop(&mut self, seg: &str, index: usize) {
-        writeln!(self.writer, "pop {} {}", seg, index).unwrap();
+    fn pop(&mut self, seg: &str, index: usize) -> io::Result<()> {
+        writeln!(self.writer, "pop {} {}", seg, index)
     }
 
-    fn call(&mut self, fn_name: &str, args_count: usize) {
-        writeln!(self.writer, "call {} {}", fn_name, args_count).unwrap();
+    fn call(&mut self, fn_name: &str, args_count: usize) -> io::Result<()> {
+        writeln!(self.writer, "call {} {}", fn_name, args_count)
     }
 
-    fn ifgoto(&mut self, label: &str) {
-        writeln!(self.writer, "if-goto {}", label).unwrap();
+    fn ifgoto(&mut self, label: &str) -> io::Result<()> {
+        writeln!(self.writer, "if-goto {}", label)
     }
 
-    fn label(&mut self, label: &str) {
-        writeln!(self.writer, "label {}", label).unwrap();
+    fn label(&mut self, label: &str) -> io::Result<()> {
+        writeln!(self.writer, "label {}", label)
     }
 
-    fn goto(&mut self, label: &str) {
-        writeln!(self.writer, "goto {}", label).unwrap();
+    fn goto(&mut self, label: &str) -> io::Result<()> {
+        writeln!(self.writer, "goto {}", label)
     }
 
-    fn command(&mut self, command: &str) {
-        writeln!(self.writer, "{}", command).unwrap();
+    fn command(&mut self, command: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", command)
     }
 }
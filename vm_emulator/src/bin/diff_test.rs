@@ -0,0 +1,89 @@
+extern crate vm_emulator;
+extern crate vm_translator;
+
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vm_emulator::{differential, generator};
+use vm_translator::parser;
+
+// VM commands run per program before giving up on it
+const MAX_STEPS: usize = 1_000_000;
+
+// Checks that translated programs behave the same as interpreted ones, either for the given
+// VM files or for randomly generated programs
+fn main() {
+    let mut fuzz_count: Option<u64> = None;
+    let mut seed: Option<u64> = None;
+    let mut extended = false;
+    let mut input_paths: Vec<PathBuf> = vec![];
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--fuzz") => fuzz_count = Some(number_arg(args.next())),
+            Some("--seed") => seed = Some(number_arg(args.next())),
+            Some("--extended") => extended = true,
+            Some(flag) if flag.starts_with('-') => usage(),
+            _ => input_paths.push(arg.into()),
+        }
+    }
+
+    match fuzz_count {
+        Some(count) if input_paths.is_empty() => fuzz(count, seed, extended),
+        None if !input_paths.is_empty() => compare_files(&input_paths),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: diff_test <vm file or directory>...");
+    eprintln!("       diff_test --fuzz <program count> [--seed <seed>] [--extended]");
+    std::process::exit(1);
+}
+
+fn number_arg(arg: Option<std::ffi::OsString>) -> u64 {
+    arg.and_then(|arg| arg.to_str().and_then(|arg| arg.parse().ok()))
+        .unwrap_or_else(|| usage())
+}
+
+fn compare_files(input_paths: &[PathBuf]) {
+    let mut vm_file_paths = vec![];
+    for input_path in input_paths {
+        vm_file_paths.extend(vm_translator::vm_file_paths_in(input_path).unwrap());
+    }
+    let programs = vm_translator::parse_vm_files(vm_file_paths).unwrap();
+    match differential::compare(programs, MAX_STEPS) {
+        Ok(count) => println!("{} function boundaries match", count),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn fuzz(count: u64, seed: Option<u64>, extended: bool) {
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    });
+    for seed in seed..seed + count {
+        let files = generator::generate(seed, extended);
+        let mut programs = vec![];
+        for (file_name, vm_code) in files.iter() {
+            let commands = parser::commands(vm_code.as_bytes())
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+            programs.push((PathBuf::from(file_name), commands));
+        }
+        if let Err(error) = differential::compare(programs, MAX_STEPS) {
+            eprintln!("error: seed {}: {}", seed, error);
+            for (file_name, vm_code) in files {
+                eprintln!("// {}\n{}", file_name, vm_code);
+            }
+            std::process::exit(1);
+        }
+    }
+    println!("{} random programs match, from seed {}", count, seed);
+}
@@ -0,0 +1,251 @@
+use crate::vm::RAM_SIZE;
+use crate::{EmulatorError, Result};
+
+// The Hack CPU, running machine code as produced by the assembler
+pub struct Cpu {
+    pub ram: Vec<i16>,
+    rom: Vec<u16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Cpu {
+            ram: vec![0; RAM_SIZE],
+            rom,
+            a: 0,
+            d: 0,
+            pc: 0,
+        }
+    }
+
+    pub fn step(&mut self) -> Result<()> {
+        let ins = *self.rom.get(self.pc).ok_or_else(|| {
+            EmulatorError::new(format!("ROM address {} is out of range", self.pc))
+        })?;
+        // A-instruction
+        if ins & 0x8000 == 0 {
+            self.a = ins as i16;
+            self.pc += 1;
+            return Ok(());
+        }
+
+        // C-instruction: 111a cccc ccdd djjj
+        let a = self.a;
+        let m_address = a as u16 as usize;
+        let m = || {
+            if m_address < RAM_SIZE {
+                Ok(m_address)
+            } else {
+                Err(EmulatorError::new(format!(
+                    "RAM address {} is out of range",
+                    m_address
+                )))
+            }
+        };
+        let y = if ins & 0x1000 != 0 { self.ram[m()?] } else { a };
+        let out = alu(self.d, y, (ins >> 6) & 0x3f);
+        if ins & 0x08 != 0 {
+            self.ram[m()?] = out;
+        }
+        if ins & 0x20 != 0 {
+            self.a = out;
+        }
+        if ins & 0x10 != 0 {
+            self.d = out;
+        }
+        let jump = match ins & 0x7 {
+            0 => false,
+            1 => out > 0,
+            2 => out == 0,
+            3 => out >= 0,
+            4 => out < 0,
+            5 => out != 0,
+            6 => out <= 0,
+            _ => true,
+        };
+        self.pc = if jump { a as u16 as usize } else { self.pc + 1 };
+        Ok(())
+    }
+}
+
+// The control bits are zx, nx, zy, ny, f, no from the most significant bit down
+fn alu(x: i16, y: i16, control: u16) -> i16 {
+    let x = if control & 0x20 != 0 { 0 } else { x };
+    let x = if control & 0x10 != 0 { !x } else { x };
+    let y = if control & 0x08 != 0 { 0 } else { y };
+    let y = if control & 0x04 != 0 { !y } else { y };
+    let out = if control & 0x02 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if control & 0x01 != 0 {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::PathBuf;
+    use vm_translator::call_stack;
+    use vm_translator::code_writer::Options;
+    use vm_translator::parser;
+
+    // Translates and assembles VM code given as (file name, code) pairs, returning a CPU
+    // loaded with it along with the names of the functions by id
+    fn translate(inputs: &[(&str, &str)], options: Options) -> (Cpu, Vec<String>) {
+        let programs = inputs
+            .iter()
+            .map(|(name, vm_code)| {
+                let commands = parser::commands(vm_code.as_bytes())
+                    .collect::<io::Result<Vec<_>>>()
+                    .unwrap();
+                (PathBuf::from(name), commands)
+            })
+            .collect();
+        let mut asm = vec![];
+        let function_names = vm_translator::translate_programs(programs, false, options, &mut asm)
+            .unwrap()
+            .function_names()
+            .to_vec();
+        let (rom, _) = assembler::assemble(&asm[..]).unwrap();
+        (Cpu::new(rom), function_names)
+    }
+
+    fn run(cpu: &mut Cpu, steps: usize) {
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn recovers_traced_call_stack() {
+        let sys = "
+function Sys.init 1
+push constant 7
+call Main.f 1
+return";
+        let main = "
+function Main.f 2
+push constant 1
+push argument 0
+call Main.g 2
+return
+function Main.g 0
+label STOP
+goto STOP";
+        let options = Options {
+            trace: true,
+            ..Options::default()
+        };
+        let (mut cpu, function_names) = translate(&[("Sys.vm", sys), ("Main.vm", main)], options);
+        // Long enough to get to the loop in Main.g
+        run(&mut cpu, 10_000);
+        let frames: Vec<String> = call_stack::call_stack(&cpu.ram, &function_names)
+            .iter()
+            .map(|frame| frame.to_string())
+            .collect();
+        assert_eq!(frames, ["Main.g(1, 7)", "Main.f(7)", "Sys.init()"]);
+    }
+
+    // Runs each piece of VM code, which leaves a value on the stack, in Sys.init and returns
+    // the values. Statics are placed from address 16 on, in the order they are used.
+    fn evaluate(cases: &[&str], options: Options, extra_code: &str) -> Vec<i16> {
+        let mut sys = "function Sys.init 0\n".to_owned();
+        for (index, case) in cases.iter().enumerate() {
+            sys += &format!("{}\npop static {}\n", case, index);
+        }
+        sys += "label END\ngoto END\n";
+        sys += extra_code;
+        let (mut cpu, _) = translate(&[("Sys.vm", &sys)], options);
+        run(&mut cpu, 100_000);
+        cpu.ram[16..16 + cases.len()].to_vec()
+    }
+
+    fn extended() -> Options {
+        Options {
+            extended: true,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn divides_at_the_edges() {
+        let cases = [
+            // -32768 / -1 wraps around, as -32768 has no positive counterpart
+            "push constant 32767\nnot\npush constant 1\nneg\ndiv",
+            "push constant 32767\nnot\npush constant 1\nneg\nmod",
+            // Truncated towards zero
+            "push constant 7\nneg\npush constant 2\ndiv",
+            "push constant 7\nneg\npush constant 2\nmod",
+            "push constant 32767\nnot\npush constant 32767\ndiv",
+            "push constant 300\npush constant 0\ndiv",
+            "push constant 300\npush constant 0\nmod",
+        ];
+        assert_eq!(
+            evaluate(&cases, extended(), ""),
+            [-32768, 0, -3, -1, -1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn divides_by_zero_without_overflowing_when_checked() {
+        let cases = [
+            "push constant 300\npush constant 0\ndiv",
+            "push constant 300\npush constant 0\nmod",
+            // Set to -1 by the overflow handler, if it is called
+            "push constant 0",
+        ];
+        let handler =
+            "function Sys.error 0\npush constant 1\nneg\npop static 2\nlabel HALT\ngoto HALT\n";
+        let options = Options {
+            checked: true,
+            ..extended()
+        };
+        assert_eq!(evaluate(&cases, options, handler), [0, 0, 0]);
+    }
+
+    #[test]
+    fn multiplies_and_shifts_at_the_edges() {
+        let cases = [
+            "push constant 300\npush constant 300\nmul",
+            "push constant 181\nneg\npush constant 181\nmul",
+            "push constant 32767\nnot\npush constant 1\nneg\nmul",
+            "push constant 1\nneg\npush constant 15\nshr",
+            "push constant 1\npush constant 15\nshl",
+            "push constant 1\nneg\npush constant 16\nshr",
+            "push constant 1\npush constant 16\nshl",
+            "push constant 1\npush constant 1\nneg\nshl",
+        ];
+        assert_eq!(
+            evaluate(&cases, extended(), ""),
+            [24464, -32761, -32768, 1, -32768, 0, 0, 0]
+        );
+    }
+
+    // The difference of the operands overflows at the ends of the range
+    #[test]
+    fn compares_at_the_edges() {
+        let cases = [
+            "push constant 32767\npush constant 32767\nneg\ngt",
+            "push constant 32767\npush constant 32767\nneg\nlt",
+            "push constant 32767\nneg\npush constant 32767\nlt",
+            "push constant 32767\nneg\npush constant 32767\ngt",
+            "push constant 32767\nnot\npush constant 1\ngt",
+            "push constant 32767\nnot\npush constant 32767\nle",
+            "push constant 32767\npush constant 32767\nnot\nge",
+            "push constant 32767\npush constant 32767\nnot\nne",
+        ];
+        assert_eq!(
+            evaluate(&cases, extended(), ""),
+            [-1, 0, -1, 0, 0, -1, -1, -1]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use crate::cpu::Cpu;
+use crate::vm::{VirtualMachine, LCL, SP};
+use crate::{EmulatorError, Result};
+use assembler::sym_table::SymTable;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use vm_translator::code_writer::Options;
+use vm_translator::parser::{Command, Segment, SourceCommand};
+
+// Runs a program both by interpreting it and by translating it with vm_translator and
+// running the machine code on the Hack CPU, comparing the two before every call and return.
+
+// RAM compared at each boundary, besides the stack up to SP and the statics, which are
+// compared by name as the assembler places them differently. R13-R15 are left out, being
+// scratch registers of the translated code.
+const POINTERS_AND_TEMP: Range<usize> = 0..13;
+const STACK_BASE: usize = 256;
+const HEAP_AND_SCREEN: Range<usize> = 2048..24577;
+// Hack instructions allowed per VM command, on average
+const INSTRUCTIONS_PER_COMMAND: usize = 100;
+
+type Location = (String, usize);
+
+struct Translation {
+    cpu: Cpu,
+    sym_table: SymTable,
+    commands: HashMap<Location, Command>,
+    // ROM addresses of the first instruction of each command
+    rom_addresses: HashMap<Location, usize>,
+    // Commands at which the two are compared, by the ROM address they start at
+    boundaries: HashMap<usize, Location>,
+    max_instructions: usize,
+    instruction_count: usize,
+}
+
+// Returns the number of boundaries compared, stopping at the first difference with an error.
+// Programs that run for more than `max_steps` VM commands are compared up to that point.
+pub fn compare(programs: Vec<(PathBuf, Vec<SourceCommand>)>, max_steps: usize) -> Result<usize> {
+    let mut translation = Translation::new(&programs, max_steps)?;
+    let statics = static_variables(&programs);
+    let mut vm = VirtualMachine::load(programs)?;
+    vm.bootstrap()?;
+
+    let mut count = 0;
+    loop {
+        // Run the interpreter up to the next boundary
+        let location = loop {
+            let location = match vm.next_location() {
+                Some((path, line_no)) => (file_name(path), line_no),
+                // Returned from Sys.init
+                None => return Ok(count),
+            };
+            if vm.is_halted() || translation.is_boundary(&location) {
+                break location;
+            }
+            if vm.step_count() >= max_steps {
+                return Ok(count);
+            }
+            vm.step()?;
+        };
+
+        let reached = if vm.is_halted() {
+            // Both should end up in the same halting loop
+            let halt_address = translation.rom_addresses[&location];
+            translation.run_until(|cpu| cpu.pc == halt_address)?;
+            translation.cpu.pc == halt_address
+        } else {
+            translation.run_until(|_| false)?;
+            translation.boundaries.get(&translation.cpu.pc) == Some(&location)
+        };
+        let context = |message: String| {
+            EmulatorError::new(format!("{}:{}: {}", location.0, location.1, message))
+        };
+        if !reached {
+            return Err(context(
+                match translation.boundaries.get(&translation.cpu.pc) {
+                    Some((vm_file, line_no)) => {
+                        format!("the translated code got to {}:{} instead", vm_file, line_no)
+                    }
+                    None => "the translated code didn't get here".to_owned(),
+                },
+            ));
+        }
+        compare_ram(&vm, &translation, &statics).map_err(context)?;
+        count += 1;
+        if vm.is_halted() {
+            return Ok(count);
+        }
+        vm.step()?;
+        translation.cpu.step()?;
+    }
+}
+
+impl Translation {
+    fn new(programs: &[(PathBuf, Vec<SourceCommand>)], max_steps: usize) -> Result<Self> {
+        let mut asm = vec![];
+        let source_map = vm_translator::translate_programs(
+            programs.to_vec(),
+            false,
+            Options {
+                extended: true,
+                ..Options::default()
+            },
+            &mut asm,
+        )
+        .map_err(|error| EmulatorError::new(error.to_string()))?
+        .into_source_map();
+        let (rom, sym_table) =
+            assembler::assemble(&asm[..]).map_err(|error| EmulatorError::new(error.to_string()))?;
+
+        let mut commands = HashMap::new();
+        for (path, source_commands) in programs {
+            for source_command in source_commands {
+                commands.insert(
+                    (file_name(path), source_command.line_no),
+                    source_command.command.clone(),
+                );
+            }
+        }
+        let mut rom_addresses = HashMap::new();
+        let mut boundaries = HashMap::new();
+        for entry in source_map {
+            let location = (entry.vm_file, entry.vm_line_no);
+            if let Some(Command::Call { .. }) | Some(Command::Return) = commands.get(&location) {
+                boundaries.insert(entry.rom_addresses.start, location.clone());
+            }
+            rom_addresses.insert(location, entry.rom_addresses.start);
+        }
+
+        Ok(Translation {
+            cpu: Cpu::new(rom),
+            sym_table,
+            commands,
+            rom_addresses,
+            boundaries,
+            max_instructions: max_steps.saturating_mul(INSTRUCTIONS_PER_COMMAND),
+            instruction_count: 0,
+        })
+    }
+
+    fn is_boundary(&self, location: &Location) -> bool {
+        matches!(
+            self.commands.get(location),
+            Some(Command::Call { .. }) | Some(Command::Return)
+        )
+    }
+
+    // Runs the CPU up to the next boundary, or until `stop`
+    fn run_until<F: Fn(&Cpu) -> bool>(&mut self, stop: F) -> Result<()> {
+        while !stop(&self.cpu) && !self.boundaries.contains_key(&self.cpu.pc) {
+            if self.instruction_count >= self.max_instructions {
+                return Err(EmulatorError::new(
+                    "the translated code ran out of steps".to_owned(),
+                ));
+            }
+            self.cpu.step()?;
+            self.instruction_count += 1;
+        }
+        Ok(())
+    }
+}
+
+fn compare_ram(
+    vm: &VirtualMachine,
+    translation: &Translation,
+    statics: &[(String, i16)],
+) -> std::result::Result<(), String> {
+    let cpu = &translation.cpu;
+    let differ = |name: String, interpreted: i16, translated: i16| {
+        format!(
+            "{} is {} when interpreted, but {} when translated",
+            name, interpreted, translated
+        )
+    };
+    for address in POINTERS_AND_TEMP.chain(HEAP_AND_SCREEN) {
+        if vm.ram[address] != cpu.ram[address] {
+            return Err(differ(
+                format!("RAM[{}]", address),
+                vm.ram[address],
+                cpu.ram[address],
+            ));
+        }
+    }
+
+    // Return addresses are instruction indexes on one side and ROM addresses on the other
+    let mut return_addresses = HashSet::new();
+    let mut frame = vm.ram[LCL] as u16 as usize;
+    while (STACK_BASE + 5..HEAP_AND_SCREEN.start).contains(&frame) {
+        return_addresses.insert(frame - 5);
+        let caller_frame = vm.ram[frame - 4] as u16 as usize;
+        if caller_frame >= frame {
+            break;
+        }
+        frame = caller_frame;
+    }
+    let sp = (vm.ram[SP] as u16 as usize).min(HEAP_AND_SCREEN.start);
+    for address in STACK_BASE..sp {
+        if !return_addresses.contains(&address) && vm.ram[address] != cpu.ram[address] {
+            return Err(differ(
+                format!("RAM[{}]", address),
+                vm.ram[address],
+                cpu.ram[address],
+            ));
+        }
+    }
+
+    for (vm_file_stem, index) in statics {
+        let name = format!("{}.{}", vm_file_stem, index);
+        let interpreted = vm.ram[vm.static_address(vm_file_stem, *index).unwrap()];
+        let translated = match translation.sym_table.lookup(&name) {
+            Some(address) => cpu.ram[address as usize],
+            None => continue,
+        };
+        if interpreted != translated {
+            return Err(differ(name, interpreted, translated));
+        }
+    }
+    Ok(())
+}
+
+// The static variables used by the program, as (file name without extension, index)
+fn static_variables(programs: &[(PathBuf, Vec<SourceCommand>)]) -> Vec<(String, i16)> {
+    let mut statics = HashSet::new();
+    for (path, source_commands) in programs {
+        let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+        for source_command in source_commands {
+            match source_command.command {
+                Command::Push {
+                    segment: Segment::Static,
+                    index,
+                }
+                | Command::Pop {
+                    segment: Segment::Static,
+                    index,
+                } => {
+                    statics.insert((stem.clone(), index));
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut statics: Vec<(String, i16)> = statics.into_iter().collect();
+    statics.sort();
+    statics
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator;
+    use std::io;
+    use vm_translator::parser;
+
+    const MAX_STEPS: usize = 1_000_000;
+    const FUZZ_SEEDS: Range<u64> = 0..100;
+    const FUNCTION_CALLS_DIR: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/../projects/08/FunctionCalls");
+
+    fn compare_dir(name: &str) -> Result<usize> {
+        let dir_path = Path::new(FUNCTION_CALLS_DIR).join(name);
+        let vm_file_paths = vm_translator::vm_file_paths_in(&dir_path)?;
+        compare(vm_translator::parse_vm_files(vm_file_paths)?, MAX_STEPS)
+    }
+
+    fn compare_generated(extended: bool) {
+        for seed in FUZZ_SEEDS {
+            let programs = generator::generate(seed, extended)
+                .into_iter()
+                .map(|(file_name, vm_code)| {
+                    let commands = parser::commands(vm_code.as_bytes())
+                        .collect::<io::Result<Vec<_>>>()
+                        .unwrap();
+                    (PathBuf::from(file_name), commands)
+                })
+                .collect();
+            if let Err(error) = compare(programs, MAX_STEPS) {
+                panic!("seed {}, extended {}: {}", seed, extended, error);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_function_calls_programs() {
+        assert_eq!(compare_dir("FibonacciElement").unwrap(), 19);
+        assert_eq!(compare_dir("NestedCall").unwrap(), 5);
+        assert_eq!(compare_dir("StaticsTest").unwrap(), 9);
+    }
+
+    #[test]
+    fn matches_generated_programs() {
+        compare_generated(false);
+    }
+
+    #[test]
+    fn matches_generated_extended_programs() {
+        compare_generated(true);
+    }
+}
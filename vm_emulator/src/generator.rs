@@ -0,0 +1,298 @@
+// Generates random, well-formed VM programs for fuzzing the translator. Programs always
+// terminate: functions only call functions generated before them and jumps only go forward.
+// Sys.init calls every function and then halts. The extended instruction set is used
+// optionally.
+
+const FILE_COUNT: usize = 2;
+const MAX_FUNCTIONS_PER_FILE: usize = 4;
+const MAX_ARGS: i16 = 3;
+const MAX_LOCALS: i16 = 3;
+const MAX_STATEMENTS: usize = 24;
+const MAX_NESTING: usize = 2;
+// Segment indexes used for static, temp, this and that
+const SEGMENT_SIZE: i16 = 8;
+// THIS and THAT are pointed into this area, so that `this` and `that` stay off the stack
+const OBJECT_AREA: i16 = 3000;
+
+// Xorshift generator, so that a program can be generated again from its seed
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Random number in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn index(&mut self, n: i16) -> i16 {
+        self.below(n as usize) as i16
+    }
+}
+
+struct Function {
+    name: String,
+    args_count: i16,
+    local_count: i16,
+}
+
+struct Generator<'a> {
+    random: &'a mut Random,
+    // Functions that can be called from the function being generated
+    callable: &'a [Function],
+    function: &'a Function,
+    extended: bool,
+    label_count: usize,
+    code: String,
+}
+
+// Returns the generated program as (file name, VM code) pairs
+pub fn generate(seed: u64, extended: bool) -> Vec<(String, String)> {
+    let mut random = Random::new(seed);
+    let mut functions: Vec<Function> = vec![];
+    let mut files = vec![];
+    for file_index in 0..FILE_COUNT {
+        let class = format!("Fuzz{}", file_index);
+        let mut code = String::new();
+        for _ in 0..=random.below(MAX_FUNCTIONS_PER_FILE) {
+            let function = Function {
+                name: format!("{}.f{}", class, functions.len()),
+                args_count: random.index(MAX_ARGS + 1),
+                local_count: random.index(MAX_LOCALS + 1),
+            };
+            let mut generator = Generator {
+                random: &mut random,
+                callable: &functions,
+                function: &function,
+                extended,
+                label_count: 0,
+                code: String::new(),
+            };
+            generator.function_body();
+            code += &generator.code;
+            functions.push(function);
+        }
+        files.push((format!("{}.vm", class), code));
+    }
+
+    let sys_init = Function {
+        name: "Sys.init".to_owned(),
+        args_count: 0,
+        local_count: 0,
+    };
+    let mut generator = Generator {
+        random: &mut random,
+        callable: &functions,
+        function: &sys_init,
+        extended,
+        label_count: 0,
+        code: String::new(),
+    };
+    generator.sys_init();
+    files.push(("Sys.vm".to_owned(), generator.code));
+    files
+}
+
+impl<'a> Generator<'a> {
+    fn emit(&mut self, command: &str) {
+        self.code += command;
+        self.code.push('\n');
+    }
+
+    fn sys_init(&mut self) {
+        self.emit("function Sys.init 0");
+        self.point_this_and_that();
+        for function in self.callable.iter() {
+            for _ in 0..function.args_count {
+                self.push_constant();
+            }
+            let call = format!("call {} {}", function.name, function.args_count);
+            self.emit(&call);
+            let pop = format!("pop static {}", self.random.index(SEGMENT_SIZE));
+            self.emit(&pop);
+        }
+        self.emit("label HALT");
+        self.emit("goto HALT");
+    }
+
+    fn function_body(&mut self) {
+        let declaration = format!(
+            "function {} {}",
+            self.function.name, self.function.local_count
+        );
+        self.emit(&declaration);
+        self.point_this_and_that();
+        let depth = self.block(0, 0, 0);
+        if depth == 0 {
+            self.push_constant();
+        }
+        self.emit("return");
+    }
+
+    fn point_this_and_that(&mut self) {
+        for pointer in 0..2 {
+            let push = format!(
+                "push constant {}",
+                OBJECT_AREA + self.random.index(100) * SEGMENT_SIZE
+            );
+            self.emit(&push);
+            let pop = format!("pop pointer {}", pointer);
+            self.emit(&pop);
+        }
+    }
+
+    // Small constants are as likely as any other, to make for shift counts and divisors
+    fn push_constant(&mut self) {
+        let value = if self.random.below(2) == 0 {
+            self.random.below(20)
+        } else {
+            self.random.below(32768)
+        };
+        let push = format!("push constant {}", value);
+        self.emit(&push);
+    }
+
+    // A segment that can be pushed from or popped to, with a valid index
+    fn segment(&mut self) -> (&'static str, i16) {
+        loop {
+            let (segment, size) = match self.random.below(6) {
+                0 => ("local", self.function.local_count),
+                1 => ("argument", self.function.args_count),
+                2 => ("static", SEGMENT_SIZE),
+                3 => ("temp", SEGMENT_SIZE),
+                4 => ("this", SEGMENT_SIZE),
+                _ => ("that", SEGMENT_SIZE),
+            };
+            if size > 0 {
+                return (segment, self.random.index(size));
+            }
+        }
+    }
+
+    // Generates statements starting at the given stack depth, returning the depth after them.
+    // The statements don't pop below `floor`.
+    fn block(&mut self, mut depth: usize, floor: usize, nesting: usize) -> usize {
+        for _ in 0..self.random.below(MAX_STATEMENTS) {
+            match self.random.below(10) {
+                0 | 1 => {
+                    self.push_constant();
+                    depth += 1;
+                }
+                2 => {
+                    let (segment, index) = self.segment();
+                    let push = format!("push {} {}", segment, index);
+                    self.emit(&push);
+                    depth += 1;
+                }
+                3 if depth > floor => {
+                    let (segment, index) = self.segment();
+                    let pop = format!("pop {} {}", segment, index);
+                    self.emit(&pop);
+                    depth -= 1;
+                }
+                4 if depth > floor => {
+                    let operation = self.operation(&["neg", "not"], &["inc", "dec"]);
+                    self.emit(operation);
+                }
+                5 | 6 if depth >= floor + 2 => {
+                    let operation = self.operation(
+                        &["add", "sub", "and", "or", "eq", "gt", "lt"],
+                        &["mul", "div", "mod", "shl", "shr", "le", "ge", "ne"],
+                    );
+                    self.emit(operation);
+                    depth -= 1;
+                }
+                7 if !self.callable.is_empty() => {
+                    let callee = &self.callable[self.random.below(self.callable.len())];
+                    for _ in 0..callee.args_count {
+                        self.push_constant();
+                    }
+                    let call = format!("call {} {}", callee.name, callee.args_count);
+                    self.emit(&call);
+                    depth += 1;
+                }
+                8 if depth > floor && nesting < MAX_NESTING => {
+                    let label = self.next_label();
+                    let if_goto = format!("if-goto {}", label);
+                    self.emit(&if_goto);
+                    depth -= 1;
+                    self.neutral_block(depth, nesting + 1);
+                    let label = format!("label {}", label);
+                    self.emit(&label);
+                }
+                9 if nesting < MAX_NESTING => {
+                    let label = self.next_label();
+                    let goto = format!("goto {}", label);
+                    self.emit(&goto);
+                    self.neutral_block(depth, nesting + 1);
+                    let label = format!("label {}", label);
+                    self.emit(&label);
+                }
+                _ => {}
+            }
+        }
+        depth
+    }
+
+    // A block that leaves the stack as deep as it found it, as jumps past it require
+    fn neutral_block(&mut self, depth: usize, nesting: usize) {
+        for _ in depth..self.block(depth, depth, nesting) {
+            let pop = format!("pop temp {}", self.random.index(SEGMENT_SIZE));
+            self.emit(&pop);
+        }
+    }
+
+    fn operation(&mut self, standard: &[&'static str], extended: &[&'static str]) -> &'static str {
+        if self.extended && self.random.below(2) == 0 {
+            extended[self.random.below(extended.len())]
+        } else {
+            standard[self.random.below(standard.len())]
+        }
+    }
+
+    fn next_label(&mut self) -> String {
+        self.label_count += 1;
+        format!("L{}", self.label_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_translator::parser::{self, Command};
+
+    fn commands(files: &[(String, String)]) -> Vec<Command> {
+        files
+            .iter()
+            .flat_map(|(_, vm_code)| parser::commands(vm_code.as_bytes()))
+            .map(|result| result.unwrap().command)
+            .collect()
+    }
+
+    #[test]
+    fn generates_the_same_program_from_a_seed() {
+        assert_eq!(generate(7, true), generate(7, true));
+        assert_ne!(generate(7, true), generate(8, true));
+    }
+
+    #[test]
+    fn uses_extended_instructions_only_when_enabled() {
+        let is_extended = |command: &Command| match command {
+            Command::Alu(operation) => operation.is_extended(),
+            _ => false,
+        };
+        for seed in 0..50 {
+            assert!(!commands(&generate(seed, false)).iter().any(is_extended));
+        }
+        assert!((0..50).any(|seed| commands(&generate(seed, true)).iter().any(is_extended)));
+    }
+}
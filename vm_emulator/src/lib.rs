@@ -0,0 +1,51 @@
+extern crate vm_translator;
+
+pub mod cpu;
+pub mod differential;
+mod font;
+pub mod generator;
+pub mod os;
+pub mod test_script;
+pub mod vm;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use vm::VirtualMachine;
+
+pub type Result<T> = std::result::Result<T, EmulatorError>;
+
+#[derive(Debug)]
+pub struct EmulatorError {
+    pub message: String,
+}
+
+impl EmulatorError {
+    pub fn new(message: String) -> Self {
+        EmulatorError { message }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.message)
+    }
+}
+
+impl Error for EmulatorError {}
+
+impl From<io::Error> for EmulatorError {
+    fn from(error: io::Error) -> Self {
+        EmulatorError::new(error.to_string())
+    }
+}
+
+// Loads the given .vm files and directories of .vm files into a new machine
+pub fn load(input_paths: &[PathBuf]) -> Result<VirtualMachine> {
+    let mut vm_file_paths = vec![];
+    for input_path in input_paths {
+        vm_file_paths.extend(vm_translator::vm_file_paths_in(input_path)?);
+    }
+    VirtualMachine::load(vm_translator::parse_vm_files(vm_file_paths)?)
+}
@@ -0,0 +1,87 @@
+extern crate vm_emulator;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use vm_emulator::test_script::TestScript;
+
+const DEFAULT_MAX_STEPS: usize = 100_000_000;
+
+fn main() {
+    let mut max_steps = DEFAULT_MAX_STEPS;
+    let mut keyboard_path: Option<PathBuf> = None;
+    let mut input_paths: Vec<PathBuf> = vec![];
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--steps") => {
+                max_steps = args
+                    .next()
+                    .and_then(|steps| steps.to_str().and_then(|steps| steps.parse().ok()))
+                    .unwrap_or_else(|| usage())
+            }
+            Some("--keyboard") => {
+                keyboard_path = Some(args.next().unwrap_or_else(|| usage()).into())
+            }
+            Some(flag) if flag.starts_with('-') => usage(),
+            _ => input_paths.push(arg.into()),
+        }
+    }
+    if input_paths.is_empty() {
+        usage();
+    }
+
+    let result = if input_paths.len() == 1 && is_test_script(&input_paths[0]) {
+        run_test_script(&input_paths[0])
+    } else {
+        run_program(&input_paths, keyboard_path.as_deref(), max_steps)
+    };
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: vm_emulator <test script .tst>");
+    eprintln!(
+        "       vm_emulator [--steps <max steps>] [--keyboard <input file>] <vm file or directory>..."
+    );
+    std::process::exit(1);
+}
+
+fn is_test_script(path: &Path) -> bool {
+    if let Some(ext) = path.extension() {
+        ext == "tst"
+    } else {
+        false
+    }
+}
+
+fn run_test_script(script_path: &Path) -> vm_emulator::Result<()> {
+    for echo in TestScript::run(script_path)? {
+        println!("{}", echo);
+    }
+    println!("End of script - Comparison ended successfully");
+    Ok(())
+}
+
+// Runs the program from Sys.init, the same way the translated code would be started.
+// OS functions without a VM definition run natively, with keys typed in from `keyboard_path`.
+fn run_program(
+    input_paths: &[PathBuf],
+    keyboard_path: Option<&Path>,
+    max_steps: usize,
+) -> vm_emulator::Result<()> {
+    let mut vm = vm_emulator::load(input_paths)?;
+    if let Some(keyboard_path) = keyboard_path {
+        vm.os.type_text(&fs::read_to_string(keyboard_path)?);
+    }
+    vm.bootstrap()?;
+    vm.run(max_steps)?;
+    if vm.is_halted() {
+        println!("Halted after {} steps", vm.step_count());
+    } else {
+        println!("Stopped after {} steps", vm.step_count());
+    }
+    Ok(())
+}
@@ -0,0 +1,623 @@
+use crate::font::CHAR_MAPS;
+use crate::vm::VirtualMachine;
+use crate::{EmulatorError, Result};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use vm_translator::code_writer::STACK_OVERFLOW_ERROR;
+
+// Native implementations of the Jack OS functions, called by the VM for functions that
+// aren't defined in VM code, the same way the VMEmulator falls back on tools/builtInVMCode.
+// Memory layouts, such as that of String objects, match the compiled OS in tools/OS, so
+// native classes can be mixed with classes that are implemented in VM code.
+
+const HEAP: Range<usize> = 2048..16384;
+const SCREEN: usize = 16384;
+const KBD: usize = 24576;
+const SCREEN_WIDTH: i16 = 512;
+const SCREEN_HEIGHT: i16 = 256;
+const WORDS_PER_ROW: usize = 32;
+const TEXT_ROWS: i16 = 23;
+const TEXT_COLUMNS: i16 = 64;
+const CHAR_HEIGHT: usize = 11;
+// Capacity of the strings returned by Keyboard.readLine
+const MAX_LINE_LENGTH: i16 = 80;
+
+const NEW_LINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+const DOUBLE_QUOTE: i16 = 34;
+
+// Error codes of projects/09/OSErrors.txt
+const SYS_WAIT_DURATION: i16 = 1;
+const ARRAY_NEW_SIZE: i16 = 2;
+const MATH_DIVIDE_BY_ZERO: i16 = 3;
+const MATH_SQRT_NEGATIVE: i16 = 4;
+const MEMORY_ALLOC_SIZE: i16 = 5;
+const MEMORY_HEAP_OVERFLOW: i16 = 6;
+const SCREEN_PIXEL: i16 = 7;
+const SCREEN_LINE: i16 = 8;
+const SCREEN_RECTANGLE: i16 = 9;
+const SCREEN_CIRCLE_CENTER: i16 = 12;
+const SCREEN_CIRCLE_RADIUS: i16 = 13;
+const STRING_NEW_LENGTH: i16 = 14;
+const STRING_CHAR_AT: i16 = 15;
+const STRING_SET_CHAR_AT: i16 = 16;
+const STRING_FULL: i16 = 17;
+const STRING_EMPTY: i16 = 18;
+const STRING_SET_INT: i16 = 19;
+const OUTPUT_CURSOR: i16 = 20;
+
+fn error_description(code: i16) -> &'static str {
+    match code {
+        SYS_WAIT_DURATION => "Duration must be positive",
+        ARRAY_NEW_SIZE => "Array size must be positive",
+        MATH_DIVIDE_BY_ZERO => "Division by zero",
+        MATH_SQRT_NEGATIVE => "Cannot compute square root of a negative number",
+        MEMORY_ALLOC_SIZE => "Allocated memory size must be positive",
+        MEMORY_HEAP_OVERFLOW => "Heap overflow",
+        SCREEN_PIXEL => "Illegal pixel coordinates",
+        SCREEN_LINE => "Illegal line coordinates",
+        SCREEN_RECTANGLE => "Illegal rectangle coordinates",
+        SCREEN_CIRCLE_CENTER => "Illegal center coordinates",
+        SCREEN_CIRCLE_RADIUS => "Illegal radius",
+        STRING_NEW_LENGTH => "Maximum length must be non-negative",
+        STRING_CHAR_AT | STRING_SET_CHAR_AT => "String index out of bounds",
+        STRING_FULL => "String is full",
+        STRING_EMPTY => "String is empty",
+        STRING_SET_INT => "Insufficient string capacity",
+        OUTPUT_CURSOR => "Illegal cursor location",
+        // Raised by code translated with stack checks
+        STACK_OVERFLOW_ERROR => "Stack overflow",
+        _ => "Unknown error",
+    }
+}
+
+// State kept by the native OS classes outside of the Hack RAM
+#[derive(Debug)]
+pub struct Os {
+    free_blocks: Vec<Range<usize>>,
+    // Sizes of the allocated blocks, by base address
+    allocated_blocks: HashMap<usize, usize>,
+    color: bool,
+    cursor_row: i16,
+    cursor_column: i16,
+    keyboard_input: VecDeque<i16>,
+    key_down: bool,
+}
+
+impl Default for Os {
+    fn default() -> Self {
+        Os {
+            free_blocks: vec![HEAP],
+            allocated_blocks: HashMap::new(),
+            color: true,
+            cursor_row: 0,
+            cursor_column: 0,
+            keyboard_input: VecDeque::new(),
+            key_down: false,
+        }
+    }
+}
+
+impl Os {
+    // Queues up keys for the Keyboard functions to read, with '\n' typed as the newline key
+    pub fn type_text(&mut self, text: &str) {
+        self.keyboard_input.extend(text.chars().map(|ch| match ch {
+            '\n' => NEW_LINE,
+            _ => ch as i16,
+        }));
+    }
+}
+
+// Performs the initializations of the OS Sys.init, before it calls Main.main
+pub fn init(vm: &mut VirtualMachine) -> Result<()> {
+    for name in &[
+        "Memory.init",
+        "Math.init",
+        "Screen.init",
+        "Output.init",
+        "Keyboard.init",
+    ] {
+        vm.call_function(name, &[])?;
+    }
+    Ok(())
+}
+
+// Runs the native implementation of the named OS function
+pub fn call(vm: &mut VirtualMachine, name: &str, args: &[i16]) -> Result<i16> {
+    let arg = |index: usize| args.get(index).cloned().unwrap_or(0);
+    let value = match name {
+        "Sys.init" => {
+            init(vm)?;
+            vm.call_function("Main.main", &[])?;
+            vm.halt();
+            0
+        }
+        "Sys.halt" => {
+            vm.halt();
+            0
+        }
+        "Sys.error" => {
+            vm.halt();
+            return Err(os_error(arg(0)));
+        }
+        // Returns right away, as no one is watching the screen
+        "Sys.wait" => {
+            check(vm, arg(0) >= 0, SYS_WAIT_DURATION)?;
+            0
+        }
+
+        "Memory.init" => {
+            vm.os.free_blocks = vec![HEAP];
+            vm.os.allocated_blocks.clear();
+            0
+        }
+        "Memory.peek" => vm.ram[address(arg(0))?],
+        "Memory.poke" => {
+            vm.ram[address(arg(0))?] = arg(1);
+            0
+        }
+        "Memory.alloc" => alloc(vm, arg(0))?,
+        "Memory.deAlloc" => dealloc(vm, arg(0))?,
+
+        "Math.init" => 0,
+        "Math.multiply" => arg(0).wrapping_mul(arg(1)),
+        "Math.divide" => {
+            check(vm, arg(1) != 0, MATH_DIVIDE_BY_ZERO)?;
+            arg(0).wrapping_div(arg(1))
+        }
+        "Math.min" => arg(0).min(arg(1)),
+        "Math.max" => arg(0).max(arg(1)),
+        "Math.abs" => arg(0).wrapping_abs(),
+        "Math.sqrt" => {
+            check(vm, arg(0) >= 0, MATH_SQRT_NEGATIVE)?;
+            (f64::from(arg(0)).sqrt()) as i16
+        }
+
+        "Array.new" => {
+            check(vm, arg(0) > 0, ARRAY_NEW_SIZE)?;
+            vm.call_function("Memory.alloc", &[arg(0)])?
+        }
+        "Array.dispose" => vm.call_function("Memory.deAlloc", &[arg(0)])?,
+
+        "String.new" => {
+            let this = vm.call_function("Memory.alloc", &[3])?;
+            check(vm, arg(0) >= 0, STRING_NEW_LENGTH)?;
+            let chars = if arg(0) > 0 {
+                vm.call_function("Array.new", &[arg(0)])?
+            } else {
+                0
+            };
+            let this_address = address(this)?;
+            vm.ram[this_address] = arg(0);
+            vm.ram[this_address + 1] = chars;
+            vm.ram[this_address + 2] = 0;
+            this
+        }
+        "String.dispose" => {
+            let string = string(vm, arg(0))?;
+            if string.max_length > 0 {
+                vm.call_function("Array.dispose", &[string.chars])?;
+            }
+            vm.call_function("Memory.deAlloc", &[arg(0)])?
+        }
+        "String.length" => string(vm, arg(0))?.length,
+        "String.charAt" => {
+            let string = string(vm, arg(0))?;
+            let j = arg(1);
+            check(vm, j >= 0 && j < string.length, STRING_CHAR_AT)?;
+            vm.ram[string.char_address(j)?]
+        }
+        "String.setCharAt" => {
+            let string = string(vm, arg(0))?;
+            let j = arg(1);
+            check(vm, j >= 0 && j < string.length, STRING_SET_CHAR_AT)?;
+            vm.ram[string.char_address(j)?] = arg(2);
+            0
+        }
+        "String.appendChar" => {
+            let string = string(vm, arg(0))?;
+            check(vm, string.length < string.max_length, STRING_FULL)?;
+            vm.ram[string.char_address(string.length)?] = arg(1);
+            vm.ram[string.length_address] = string.length + 1;
+            arg(0)
+        }
+        "String.eraseLastChar" => {
+            let string = string(vm, arg(0))?;
+            check(vm, string.length > 0, STRING_EMPTY)?;
+            vm.ram[string.length_address] = string.length - 1;
+            0
+        }
+        "String.intValue" => {
+            let string = string(vm, arg(0))?;
+            let mut value: i16 = 0;
+            let mut negative = false;
+            for j in 0..string.length {
+                let ch = vm.ram[string.char_address(j)?];
+                if j == 0 && ch == '-' as i16 {
+                    negative = true;
+                } else if ch >= '0' as i16 && ch <= '9' as i16 {
+                    value = value.wrapping_mul(10).wrapping_add(ch - '0' as i16);
+                } else {
+                    break;
+                }
+            }
+            if negative {
+                value.wrapping_neg()
+            } else {
+                value
+            }
+        }
+        "String.setInt" => {
+            let string = string(vm, arg(0))?;
+            let digits = arg(1).to_string();
+            check(
+                vm,
+                digits.len() <= string.max_length as usize,
+                STRING_SET_INT,
+            )?;
+            for (j, ch) in (0..).zip(digits.chars()) {
+                vm.ram[string.char_address(j)?] = ch as i16;
+            }
+            vm.ram[string.length_address] = digits.len() as i16;
+            0
+        }
+        "String.newLine" => NEW_LINE,
+        "String.backSpace" => BACKSPACE,
+        "String.doubleQuote" => DOUBLE_QUOTE,
+
+        "Screen.init" => {
+            vm.os.color = true;
+            0
+        }
+        "Screen.clearScreen" => {
+            for word in vm.ram[SCREEN..KBD].iter_mut() {
+                *word = 0;
+            }
+            0
+        }
+        "Screen.setColor" => {
+            vm.os.color = arg(0) != 0;
+            0
+        }
+        "Screen.drawPixel" => {
+            let (x, y) = (arg(0), arg(1));
+            check(vm, on_screen(x, y), SCREEN_PIXEL)?;
+            draw_pixel(vm, x, y);
+            0
+        }
+        "Screen.drawLine" => {
+            let (x1, y1, x2, y2) = (arg(0), arg(1), arg(2), arg(3));
+            check(vm, on_screen(x1, y1) && on_screen(x2, y2), SCREEN_LINE)?;
+            draw_line(vm, x1, y1, x2, y2);
+            0
+        }
+        "Screen.drawRectangle" => {
+            let (x1, y1, x2, y2) = (arg(0), arg(1), arg(2), arg(3));
+            check(
+                vm,
+                on_screen(x1, y1) && on_screen(x2, y2) && x1 <= x2 && y1 <= y2,
+                SCREEN_RECTANGLE,
+            )?;
+            for y in y1..=y2 {
+                draw_line(vm, x1, y, x2, y);
+            }
+            0
+        }
+        "Screen.drawCircle" => {
+            let (x, y, r) = (arg(0), arg(1), arg(2));
+            check(vm, on_screen(x, y), SCREEN_CIRCLE_CENTER)?;
+            check(
+                vm,
+                (0..=181).contains(&r) && on_screen(x - r, y - r) && on_screen(x + r, y + r),
+                SCREEN_CIRCLE_RADIUS,
+            )?;
+            for dy in -r..=r {
+                let dx = f64::from(r * r - dy * dy).sqrt() as i16;
+                draw_line(vm, x - dx, y + dy, x + dx, y + dy);
+            }
+            0
+        }
+
+        "Output.init" => {
+            vm.os.cursor_row = 0;
+            vm.os.cursor_column = 0;
+            0
+        }
+        "Output.moveCursor" => {
+            let (row, column) = (arg(0), arg(1));
+            check(
+                vm,
+                (0..TEXT_ROWS).contains(&row) && (0..TEXT_COLUMNS).contains(&column),
+                OUTPUT_CURSOR,
+            )?;
+            vm.os.cursor_row = row;
+            vm.os.cursor_column = column;
+            draw_char(vm, ' ' as i16);
+            0
+        }
+        "Output.printChar" => {
+            print_char(vm, arg(0));
+            0
+        }
+        "Output.printString" => {
+            let length = vm.call_function("String.length", &[arg(0)])?;
+            for j in 0..length {
+                let ch = vm.call_function("String.charAt", &[arg(0), j])?;
+                print_char(vm, ch);
+            }
+            0
+        }
+        "Output.printInt" => {
+            for ch in arg(0).to_string().chars() {
+                print_char(vm, ch as i16);
+            }
+            0
+        }
+        "Output.println" => {
+            println(vm);
+            0
+        }
+        "Output.backSpace" => {
+            back_space(vm);
+            0
+        }
+
+        "Keyboard.init" => {
+            vm.os.key_down = false;
+            0
+        }
+        "Keyboard.keyPressed" => key_pressed(vm),
+        "Keyboard.readChar" => read_char(vm)?,
+        "Keyboard.readLine" => read_line(vm, arg(0))?,
+        "Keyboard.readInt" => {
+            let line = read_line(vm, arg(0))?;
+            let value = vm.call_function("String.intValue", &[line])?;
+            vm.call_function("String.dispose", &[line])?;
+            value
+        }
+
+        _ => {
+            return Err(EmulatorError::new(format!(
+                "call to undefined function {}",
+                name
+            )))
+        }
+    };
+    Ok(value)
+}
+
+fn os_error(code: i16) -> EmulatorError {
+    EmulatorError::new(format!("ERR{}: {}", code, error_description(code)))
+}
+
+// Reports a failed OS check through Sys.error, which may itself be implemented in VM code.
+// Either way the program is halted, so the native function doesn't carry on.
+fn check(vm: &mut VirtualMachine, condition: bool, error_code: i16) -> Result<()> {
+    if condition {
+        return Ok(());
+    }
+    // Sys.error in VM code loops forever once it has displayed the error, so it runs out of
+    // steps rather than returning
+    let _ = vm.call_function("Sys.error", &[error_code]);
+    vm.halt();
+    Err(os_error(error_code))
+}
+
+fn address(address: i16) -> Result<usize> {
+    if address >= 0 {
+        Ok(address as usize)
+    } else {
+        Err(EmulatorError::new(format!(
+            "address {} is out of range",
+            address
+        )))
+    }
+}
+
+fn alloc(vm: &mut VirtualMachine, size: i16) -> Result<i16> {
+    check(vm, size > 0, MEMORY_ALLOC_SIZE)?;
+    let size = size as usize;
+    let position = vm
+        .os
+        .free_blocks
+        .iter()
+        .position(|block| block.len() >= size);
+    match position {
+        Some(position) => {
+            let block = &mut vm.os.free_blocks[position];
+            let base = block.start;
+            block.start += size;
+            if block.start == block.end {
+                vm.os.free_blocks.remove(position);
+            }
+            vm.os.allocated_blocks.insert(base, size);
+            Ok(base as i16)
+        }
+        None => check(vm, false, MEMORY_HEAP_OVERFLOW).map(|_| 0),
+    }
+}
+
+fn dealloc(vm: &mut VirtualMachine, base: i16) -> Result<i16> {
+    let base = base as u16 as usize;
+    let size = vm.os.allocated_blocks.remove(&base).ok_or_else(|| {
+        EmulatorError::new(format!(
+            "Memory.deAlloc: {} is not an allocated block",
+            base
+        ))
+    })?;
+    let free_blocks = &mut vm.os.free_blocks;
+    let position = free_blocks
+        .iter()
+        .position(|block| block.start > base)
+        .unwrap_or(free_blocks.len());
+    free_blocks.insert(position, base..base + size);
+    // Merge with the neighbouring free blocks
+    if position + 1 < free_blocks.len()
+        && free_blocks[position].end == free_blocks[position + 1].start
+    {
+        free_blocks[position].end = free_blocks.remove(position + 1).end;
+    }
+    if position > 0 && free_blocks[position - 1].end == free_blocks[position].start {
+        free_blocks[position - 1].end = free_blocks.remove(position).end;
+    }
+    Ok(0)
+}
+
+// Fields of a String object: maxLength, chars (an Array) and length
+struct JackString {
+    max_length: i16,
+    chars: i16,
+    length: i16,
+    length_address: usize,
+}
+
+impl JackString {
+    fn char_address(&self, j: i16) -> Result<usize> {
+        address(self.chars.wrapping_add(j))
+    }
+}
+
+fn string(vm: &VirtualMachine, this: i16) -> Result<JackString> {
+    let this = address(this)?;
+    if this + 2 >= vm.ram.len() {
+        return Err(EmulatorError::new(format!(
+            "address {} is out of range",
+            this
+        )));
+    }
+    Ok(JackString {
+        max_length: vm.ram[this],
+        chars: vm.ram[this + 1],
+        length: vm.ram[this + 2],
+        length_address: this + 2,
+    })
+}
+
+fn on_screen(x: i16, y: i16) -> bool {
+    (0..SCREEN_WIDTH).contains(&x) && (0..SCREEN_HEIGHT).contains(&y)
+}
+
+fn draw_pixel(vm: &mut VirtualMachine, x: i16, y: i16) {
+    let word = &mut vm.ram[SCREEN + y as usize * WORDS_PER_ROW + x as usize / 16];
+    let bit = 1 << (x % 16);
+    if vm.os.color {
+        *word |= bit;
+    } else {
+        *word &= !bit;
+    }
+}
+
+fn draw_line(vm: &mut VirtualMachine, x1: i16, y1: i16, x2: i16, y2: i16) {
+    let (dx, dy) = ((x2 - x1).abs(), (y2 - y1).abs());
+    let steps = dx.max(dy);
+    for step in 0..=steps {
+        if steps == 0 {
+            draw_pixel(vm, x1, y1);
+            break;
+        }
+        let x = x1 as i32 + (x2 - x1) as i32 * step as i32 / steps as i32;
+        let y = y1 as i32 + (y2 - y1) as i32 * step as i32 / steps as i32;
+        draw_pixel(vm, x as i16, y as i16);
+    }
+}
+
+// Draws the character at the cursor, two characters sharing each screen word. Like the
+// compiled OS, text starts one pixel row down from the top of the screen.
+fn draw_char(vm: &mut VirtualMachine, ch: i16) {
+    let map = CHAR_MAPS[if (32..=126).contains(&ch) {
+        ch as usize
+    } else {
+        0
+    }];
+    let column = vm.os.cursor_column as usize;
+    let row = vm.os.cursor_row as usize;
+    let top = SCREEN + WORDS_PER_ROW + row * CHAR_HEIGHT * WORDS_PER_ROW + column / 2;
+    for (line, &bits) in map.iter().enumerate() {
+        let word = &mut vm.ram[top + line * WORDS_PER_ROW];
+        if column.is_multiple_of(2) {
+            *word = (*word & !0xff) | bits as i16;
+        } else {
+            *word = (*word & 0xff) | (bits as i16) << 8;
+        }
+    }
+}
+
+fn print_char(vm: &mut VirtualMachine, ch: i16) {
+    match ch {
+        NEW_LINE => println(vm),
+        BACKSPACE => back_space(vm),
+        _ => {
+            draw_char(vm, ch);
+            vm.os.cursor_column += 1;
+            if vm.os.cursor_column == TEXT_COLUMNS {
+                println(vm);
+            }
+        }
+    }
+}
+
+fn println(vm: &mut VirtualMachine) {
+    vm.os.cursor_column = 0;
+    vm.os.cursor_row = (vm.os.cursor_row + 1) % TEXT_ROWS;
+}
+
+fn back_space(vm: &mut VirtualMachine) {
+    if vm.os.cursor_column > 0 {
+        vm.os.cursor_column -= 1;
+    } else if vm.os.cursor_row > 0 {
+        vm.os.cursor_row -= 1;
+        vm.os.cursor_column = TEXT_COLUMNS - 1;
+    }
+    draw_char(vm, ' ' as i16);
+}
+
+// Queued keys are held down for a single poll, so that programs waiting for a key to be
+// released see it released on the next poll
+fn key_pressed(vm: &mut VirtualMachine) -> i16 {
+    if vm.ram[KBD] != 0 {
+        return vm.ram[KBD];
+    }
+    if vm.os.key_down {
+        vm.os.key_down = false;
+        return 0;
+    }
+    match vm.os.keyboard_input.pop_front() {
+        Some(key) => {
+            vm.os.key_down = true;
+            key
+        }
+        None => 0,
+    }
+}
+
+// Takes the next queued key, instead of blocking until one is pressed
+fn read_char(vm: &mut VirtualMachine) -> Result<i16> {
+    vm.os.key_down = false;
+    let key = vm
+        .os
+        .keyboard_input
+        .pop_front()
+        .ok_or_else(|| EmulatorError::new("waiting for keyboard input".to_owned()))?;
+    vm.call_function("Output.printChar", &[key])?;
+    Ok(key)
+}
+
+fn read_line(vm: &mut VirtualMachine, message: i16) -> Result<i16> {
+    vm.call_function("Output.printString", &[message])?;
+    let line = vm.call_function("String.new", &[MAX_LINE_LENGTH])?;
+    loop {
+        match vm.call_function("Keyboard.readChar", &[])? {
+            NEW_LINE => return Ok(line),
+            BACKSPACE => {
+                if vm.call_function("String.length", &[line])? > 0 {
+                    vm.call_function("String.eraseLastChar", &[line])?;
+                }
+            }
+            key => {
+                if vm.call_function("String.length", &[line])? < MAX_LINE_LENGTH {
+                    vm.call_function("String.appendChar", &[line, key])?;
+                }
+            }
+        }
+    }
+}
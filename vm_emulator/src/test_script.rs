@@ -0,0 +1,399 @@
+use crate::vm::{VirtualMachine, ARG, LCL, RAM_SIZE, SP, THAT, THIS};
+use crate::{EmulatorError, Result};
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Subset of the test script language of the VMEmulator supplied with the course,
+// enough to run the *VME.tst scripts of projects 07 and 08
+#[derive(Debug)]
+enum Statement {
+    Load(Option<String>),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(String, String),
+    Repeat(usize, Vec<Statement>),
+    VmStep,
+    Output,
+    Echo(String),
+}
+
+// An output-list entry such as `RAM[0]%D1.6.1`
+#[derive(Clone, Debug)]
+struct Column {
+    variable: String,
+    format: char,
+    left_pad: usize,
+    width: usize,
+    right_pad: usize,
+}
+
+pub struct TestScript {
+    dir: PathBuf,
+    vm: Option<VirtualMachine>,
+    output: Option<BufWriter<File>>,
+    compare_lines: Option<Vec<String>>,
+    columns: Vec<Column>,
+    output_line_count: usize,
+    echoes: Vec<String>,
+}
+
+impl TestScript {
+    // Runs the script, failing on the first output line that differs from the compare-to file
+    pub fn run(script_path: &Path) -> Result<Vec<String>> {
+        let script = fs::read_to_string(script_path)?;
+        let statements = parse(&tokenize(&script))?;
+        let mut test_script = TestScript {
+            dir: script_path.parent().unwrap().to_owned(),
+            vm: None,
+            output: None,
+            compare_lines: None,
+            columns: vec![],
+            output_line_count: 0,
+            echoes: vec![],
+        };
+        test_script.execute(&statements)?;
+        if let Some(output) = test_script.output.as_mut() {
+            output.flush()?;
+        }
+        Ok(test_script.echoes)
+    }
+
+    fn execute(&mut self, statements: &[Statement]) -> Result<()> {
+        for statement in statements {
+            match statement {
+                Statement::Load(name) => {
+                    let path = match name {
+                        Some(name) => self.dir.join(name),
+                        None => self.dir.clone(),
+                    };
+                    self.vm = Some(crate::load(&[path])?);
+                }
+                Statement::OutputFile(name) => {
+                    self.output = Some(BufWriter::new(File::create(self.dir.join(name))?));
+                }
+                Statement::CompareTo(name) => {
+                    let compare = fs::read_to_string(self.dir.join(name))?;
+                    self.compare_lines = Some(compare.lines().map(str::to_owned).collect());
+                }
+                Statement::OutputList(columns) => {
+                    let line = columns
+                        .iter()
+                        .map(|column| {
+                            let total = column.left_pad + column.width + column.right_pad;
+                            let name: String = column.variable.chars().take(total).collect();
+                            let left = (total - name.len()) / 2;
+                            format!(
+                                "{}{}{}",
+                                " ".repeat(left),
+                                name,
+                                " ".repeat(total - left - name.len())
+                            )
+                        })
+                        .collect::<Vec<String>>();
+                    self.columns = columns.clone();
+                    self.write_line(line)?;
+                }
+                Statement::Set(variable, value) => {
+                    let value = parse_value(value)?;
+                    let address = self.address(variable)?;
+                    self.vm()?.ram[address] = value;
+                }
+                Statement::Repeat(count, statements) => {
+                    for _ in 0..*count {
+                        self.execute(statements)?;
+                    }
+                }
+                Statement::VmStep => self.vm()?.step()?,
+                Statement::Output => {
+                    let mut line = vec![];
+                    for column in self.columns.clone() {
+                        let value = self.ram(&column.variable)?;
+                        let value = match column.format {
+                            'X' => format!("{:X}", value),
+                            'B' => format!("{:016b}", value),
+                            _ => value.to_string(),
+                        };
+                        line.push(format!(
+                            "{}{:>width$}{}",
+                            " ".repeat(column.left_pad),
+                            value,
+                            " ".repeat(column.right_pad),
+                            width = column.width
+                        ));
+                    }
+                    self.write_line(line)?;
+                }
+                Statement::Echo(text) => self.echoes.push(text.clone()),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, cells: Vec<String>) -> Result<()> {
+        let line = format!("|{}|", cells.join("|"));
+        if let Some(output) = self.output.as_mut() {
+            writeln!(output, "{}", line)?;
+        }
+        if let Some(compare_lines) = self.compare_lines.as_ref() {
+            let strip = |line: &str| line.split_whitespace().collect::<String>();
+            let expected = compare_lines
+                .get(self.output_line_count)
+                .map(|line| strip(line));
+            if expected != Some(strip(&line)) {
+                return Err(EmulatorError::new(format!(
+                    "Comparison failure at line {}",
+                    self.output_line_count + 1
+                )));
+            }
+        }
+        self.output_line_count += 1;
+        Ok(())
+    }
+
+    fn vm(&mut self) -> Result<&mut VirtualMachine> {
+        self.vm
+            .as_mut()
+            .ok_or_else(|| EmulatorError::new("no program is loaded".to_owned()))
+    }
+
+    fn ram(&mut self, variable: &str) -> Result<i16> {
+        let address = self.address(variable)?;
+        Ok(self.vm()?.ram[address])
+    }
+
+    // RAM address of a script variable such as `sp`, `RAM[256]` or `argument[1]`
+    fn address(&mut self, variable: &str) -> Result<usize> {
+        let unknown = || EmulatorError::new(format!("unknown variable {}", variable));
+        let (name, index) = match variable.find('[') {
+            Some(bracket) => {
+                let index = variable[bracket + 1..]
+                    .trim_end_matches(']')
+                    .parse::<usize>()
+                    .map_err(|_| unknown())?;
+                (&variable[..bracket], Some(index))
+            }
+            None => (variable, None),
+        };
+        let ram = &self.vm()?.ram;
+        let pointer = match name {
+            "sp" => Some(SP),
+            "local" => Some(LCL),
+            "argument" => Some(ARG),
+            "this" => Some(THIS),
+            "that" => Some(THAT),
+            "RAM" | "temp" => None,
+            _ => return Err(unknown()),
+        };
+        let address = match (pointer, index) {
+            (Some(pointer), Some(index)) => (ram[pointer] as u16 as usize).saturating_add(index),
+            (Some(pointer), None) => pointer,
+            (None, Some(index)) if name == "temp" => index.saturating_add(5),
+            (None, Some(index)) => index,
+            (None, None) => return Err(unknown()),
+        };
+        if address >= RAM_SIZE {
+            return Err(EmulatorError::new(format!(
+                "address {} of {} is out of range",
+                address, variable
+            )));
+        }
+        Ok(address)
+    }
+}
+
+fn parse_value(value: &str) -> Result<i16> {
+    let invalid = || EmulatorError::new(format!("invalid value {}", value));
+    if let Some(hex) = value.strip_prefix("%X") {
+        u16::from_str_radix(hex, 16)
+            .map(|value| value as i16)
+            .map_err(|_| invalid())
+    } else if let Some(binary) = value.strip_prefix("%B") {
+        u16::from_str_radix(binary, 2)
+            .map(|value| value as i16)
+            .map_err(|_| invalid())
+    } else {
+        value
+            .trim_start_matches("%D")
+            .parse::<i16>()
+            .map_err(|_| invalid())
+    }
+}
+
+// Splits the script into words, quoted strings and the punctuation `,;{}`
+fn tokenize(script: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = script.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|&ch| ch != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for ch in chars.by_ref() {
+                    if last == '*' && ch == '/' {
+                        break;
+                    }
+                    last = ch;
+                }
+            }
+            ',' | ';' | '{' | '}' => tokens.push(ch.to_string()),
+            '"' => tokens.push(chars.by_ref().take_while(|&ch| ch != '"').collect()),
+            _ if ch.is_whitespace() => {}
+            _ => {
+                let mut token = ch.to_string();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || ",;{}\"".contains(ch) {
+                        break;
+                    }
+                    token.push(ch);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+fn parse(tokens: &[String]) -> Result<Vec<Statement>> {
+    let (statements, rest) = parse_block(tokens)?;
+    if !rest.is_empty() {
+        return Err(EmulatorError::new(format!("unexpected {}", rest[0])));
+    }
+    Ok(statements)
+}
+
+// Parses statements up to the end of `tokens` or a closing brace,
+// returning the remaining tokens (starting at the brace)
+fn parse_block(mut tokens: &[String]) -> Result<(Vec<Statement>, &[String])> {
+    let mut statements = vec![];
+    while let Some(command) = tokens.first() {
+        if command == "}" {
+            break;
+        }
+        let end = tokens
+            .iter()
+            .position(|token| token == "," || token == ";" || token == "{")
+            .unwrap_or(tokens.len());
+        let args = &tokens[1..end];
+        let arg = |position: usize| {
+            args.get(position)
+                .cloned()
+                .ok_or_else(|| EmulatorError::new(format!("missing argument to {}", command)))
+        };
+        let statement = match command.as_str() {
+            "load" => Statement::Load(args.first().cloned()),
+            "output-file" => Statement::OutputFile(arg(0)?),
+            "compare-to" => Statement::CompareTo(arg(0)?),
+            "output-list" => Statement::OutputList(
+                args.iter()
+                    .map(|column| parse_column(column))
+                    .collect::<Result<Vec<Column>>>()?,
+            ),
+            "set" => Statement::Set(arg(0)?, arg(1)?),
+            "vmstep" => Statement::VmStep,
+            "output" => Statement::Output,
+            "echo" => Statement::Echo(arg(0)?),
+            "repeat" => {
+                let count = arg(0)?
+                    .parse::<usize>()
+                    .map_err(|_| EmulatorError::new("invalid repeat count".to_owned()))?;
+                if tokens.get(end).map(String::as_str) != Some("{") {
+                    return Err(EmulatorError::new("expected { after repeat".to_owned()));
+                }
+                let (block, rest) = parse_block(&tokens[end + 1..])?;
+                if rest.is_empty() {
+                    return Err(EmulatorError::new("expected } after repeat".to_owned()));
+                }
+                statements.push(Statement::Repeat(count, block));
+                tokens = &rest[1..];
+                continue;
+            }
+            _ => {
+                return Err(EmulatorError::new(format!(
+                    "unsupported command {}",
+                    command
+                )))
+            }
+        };
+        statements.push(statement);
+        tokens = &tokens[(end + 1).min(tokens.len())..];
+    }
+    Ok((statements, tokens))
+}
+
+fn parse_column(column: &str) -> Result<Column> {
+    let invalid = || EmulatorError::new(format!("invalid output column {}", column));
+    let mut parts = column.splitn(2, '%');
+    let variable = parts.next().unwrap().to_owned();
+    let format = parts.next().unwrap_or("D1.6.1");
+    let mut chars = format.chars();
+    let format_char = chars.next().ok_or_else(invalid)?;
+    let sizes = chars
+        .as_str()
+        .split('.')
+        .map(|size| size.parse::<usize>().map_err(|_| invalid()))
+        .collect::<Result<Vec<usize>>>()?;
+    if sizes.len() != 3 {
+        return Err(invalid());
+    }
+    Ok(Column {
+        variable,
+        format: format_char,
+        left_pad: sizes[0],
+        width: sizes[1],
+        right_pad: sizes[2],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a script next to a one-function program, in a directory of its own
+    fn run_script(name: &str, script: &str) -> Result<Vec<String>> {
+        let dir = std::env::temp_dir().join(format!("test_script_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("Main.vm"),
+            "function Main.main 0\npush constant 0\nreturn\n",
+        )?;
+        let script_path = dir.join("Test.tst");
+        fs::write(&script_path, script)?;
+        let result = TestScript::run(&script_path);
+        fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    #[test]
+    fn sets_and_outputs_ram() {
+        let script = "load Main.vm, set RAM[256] 7, set sp 257, set local 300, \
+                      set local[1] %X10, echo \"done\";";
+        assert_eq!(run_script("ok", script).unwrap(), ["done"]);
+    }
+
+    #[test]
+    fn rejects_addresses_outside_ram() {
+        let error = run_script("ram", "load Main.vm, set RAM[40000] 1;").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "address 40000 of RAM[40000] is out of range"
+        );
+
+        let error = run_script("local", "load Main.vm, set local -1, set local[5] 1;").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "address 65540 of local[5] is out of range"
+        );
+
+        let script = "load Main.vm, set temp[18446744073709551615] 1;";
+        assert!(run_script("temp", script).is_err());
+    }
+}
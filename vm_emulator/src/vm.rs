@@ -0,0 +1,464 @@
+use crate::os;
+use crate::os::Os;
+use crate::{EmulatorError, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use vm_translator::parser::{Command, Operation, Segment, SourceCommand};
+
+pub const RAM_SIZE: usize = 32768;
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+const TEMP_BASE: usize = 5;
+const STATIC_BASE: usize = 16;
+// Same as the bootstrap code generated by vm_translator::code_writer::CodeWriter
+pub const STACK_BASE: i16 = 256;
+const ENTRY_FUNCTION: &str = "Sys.init";
+const MAIN_FUNCTION: &str = "Main.main";
+
+// Commands with labels and function names resolved to instruction indexes
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Alu(Operation),
+    Push(Segment, i16),
+    Pop(Segment, i16),
+    Label,
+    Goto(usize),
+    IfGoto(usize),
+    Function(i16),
+    Call {
+        target: Option<usize>,
+        callee: usize,
+        args_count: i16,
+    },
+    Return,
+}
+
+#[derive(Debug)]
+struct Location {
+    vm_file: usize,
+    line_no: usize,
+}
+
+#[derive(Debug)]
+struct VmFile {
+    path: PathBuf,
+    static_base: usize,
+}
+
+pub struct VirtualMachine {
+    pub ram: Vec<i16>,
+    // State of the native OS functions, used for functions without a VM definition
+    pub os: Os,
+    ops: Vec<Op>,
+    locations: Vec<Location>,
+    vm_files: Vec<VmFile>,
+    functions: HashMap<String, usize>,
+    // Names of called functions, indexed by `Op::Call::callee`
+    callees: Vec<String>,
+    pc: usize,
+    halted: bool,
+    step_count: usize,
+    // Bounds the steps taken by `run`, including those of functions run by `call_function`
+    step_limit: usize,
+}
+
+impl VirtualMachine {
+    // Loads the parsed VM files into a machine with zeroed RAM. Execution starts at Sys.init
+    // if it is defined, else at the first command, without any bootstrap code being run.
+    pub fn load(programs: Vec<(PathBuf, Vec<SourceCommand>)>) -> Result<Self> {
+        let mut vm = VirtualMachine {
+            ram: vec![0; RAM_SIZE],
+            os: Os::default(),
+            ops: vec![],
+            locations: vec![],
+            vm_files: vec![],
+            functions: HashMap::new(),
+            callees: vec![],
+            pc: 0,
+            halted: false,
+            step_count: 0,
+            step_limit: usize::MAX,
+        };
+
+        // Labels are scoped by their function, or by their file outside of functions
+        let mut labels: HashMap<(String, String), usize> = HashMap::new();
+        let mut commands = vec![];
+        let mut static_base = STATIC_BASE;
+        for (vm_file, (path, source_commands)) in programs.into_iter().enumerate() {
+            let mut scope = path.to_string_lossy().into_owned();
+            let mut static_count = 0;
+            for source_command in source_commands {
+                let index = commands.len();
+                match &source_command.command {
+                    Command::Function { name, .. } => {
+                        if vm.functions.insert(name.clone(), index).is_some() {
+                            return Err(EmulatorError::new(format!(
+                                "{}:{}: function {} is already defined",
+                                path.display(),
+                                source_command.line_no,
+                                name
+                            )));
+                        }
+                        scope = name.clone();
+                    }
+                    Command::Label { label } => {
+                        labels.insert((scope.clone(), label.clone()), index);
+                    }
+                    Command::Push {
+                        segment: Segment::Static,
+                        index,
+                    }
+                    | Command::Pop {
+                        segment: Segment::Static,
+                        index,
+                    } => {
+                        static_count = static_count.max(*index as usize + 1);
+                    }
+                    _ => {}
+                }
+                vm.locations.push(Location {
+                    vm_file,
+                    line_no: source_command.line_no,
+                });
+                commands.push((scope.clone(), source_command.command));
+            }
+            vm.vm_files.push(VmFile { path, static_base });
+            static_base += static_count;
+        }
+
+        let mut callee_indexes: HashMap<String, usize> = HashMap::new();
+        for (index, (scope, command)) in commands.into_iter().enumerate() {
+            let op = match command {
+                Command::Alu(operation) => Op::Alu(operation),
+                Command::Push { segment, index } => Op::Push(segment, index),
+                Command::Pop { segment, index } => Op::Pop(segment, index),
+                Command::Label { .. } => Op::Label,
+                Command::Goto { label } => Op::Goto(vm.label_target(&labels, scope, label, index)?),
+                Command::IfGoto { label } => {
+                    Op::IfGoto(vm.label_target(&labels, scope, label, index)?)
+                }
+                Command::Function { local_count, .. } => Op::Function(local_count),
+                Command::Call { name, args_count } => {
+                    let target = vm.functions.get(&name).cloned();
+                    let callees = &mut vm.callees;
+                    let callee = *callee_indexes.entry(name).or_insert_with_key(|name| {
+                        callees.push(name.clone());
+                        callees.len() - 1
+                    });
+                    Op::Call {
+                        target,
+                        callee,
+                        args_count,
+                    }
+                }
+                Command::Return => Op::Return,
+            };
+            vm.ops.push(op);
+        }
+
+        if let Some(&entry) = vm.functions.get(ENTRY_FUNCTION) {
+            vm.pc = entry;
+        }
+        Ok(vm)
+    }
+
+    fn label_target(
+        &self,
+        labels: &HashMap<(String, String), usize>,
+        scope: String,
+        label: String,
+        index: usize,
+    ) -> Result<usize> {
+        let key = (scope, label);
+        labels.get(&key).cloned().ok_or_else(|| {
+            EmulatorError::new(format!("{}: unknown label {}", self.location(index), key.1))
+        })
+    }
+
+    // Sets up the stack like the translated bootstrap code does: SP = 256, call Sys.init.
+    // Without a Sys.init in VM code, the OS is initialized natively and Main.main is called.
+    pub fn bootstrap(&mut self) -> Result<()> {
+        self.ram[SP] = STACK_BASE;
+        // Returning from the entry function ends the program
+        self.pc = self.ops.len();
+        if let Some(&entry) = self.functions.get(ENTRY_FUNCTION) {
+            return self.call(entry, 0);
+        }
+        os::init(self)?;
+        let main = *self.functions.get(MAIN_FUNCTION).ok_or_else(|| {
+            EmulatorError::new(format!("function {} is not defined", MAIN_FUNCTION))
+        })?;
+        self.call(main, 0)
+    }
+
+    // Calls a function on behalf of native code and returns its return value. Functions
+    // defined in VM code are run to completion, others are run natively.
+    pub fn call_function(&mut self, name: &str, args: &[i16]) -> Result<i16> {
+        let target = match self.functions.get(name) {
+            Some(&target) => target,
+            None => return os::call(self, name, args),
+        };
+        let saved_pc = self.pc;
+        // Past the end of the program, so it can't be mistaken for a VM return address
+        let return_address = self.ops.len() + 1;
+        self.pc = return_address;
+        for &arg in args {
+            self.push(arg)?;
+        }
+        self.call(target, args.len() as i16)?;
+        while self.pc != return_address {
+            if self.is_halted() {
+                return Ok(0);
+            }
+            if self.step_count >= self.step_limit {
+                return Err(EmulatorError::new(format!(
+                    "{} did not return within the step limit",
+                    name
+                )));
+            }
+            self.step()?;
+        }
+        self.pc = saved_pc;
+        self.pop()
+    }
+
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted || self.pc >= self.ops.len()
+    }
+
+    pub fn step_count(&self) -> usize {
+        self.step_count
+    }
+
+    // Runs until the program halts or `max_steps` commands have been executed
+    pub fn run(&mut self, max_steps: usize) -> Result<()> {
+        self.step_limit = self.step_count.saturating_add(max_steps);
+        while !self.is_halted() && self.step_count < self.step_limit {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // Executes a single command. Labels are skipped over and don't count as a step,
+    // the same as in the VMEmulator supplied with the course.
+    pub fn step(&mut self) -> Result<()> {
+        while let Some(Op::Label) = self.ops.get(self.pc) {
+            self.pc += 1;
+        }
+        if self.is_halted() {
+            return Ok(());
+        }
+        let index = self.pc;
+        self.pc += 1;
+        self.step_count += 1;
+        self.execute(index).map_err(|error| {
+            EmulatorError::new(format!("{}: {}", self.location(index), error.message))
+        })
+    }
+
+    fn execute(&mut self, index: usize) -> Result<()> {
+        match self.ops[index] {
+            Op::Alu(operation) => self.alu(operation)?,
+            Op::Push(segment, segment_index) => {
+                let value = if segment == Segment::Constant {
+                    segment_index
+                } else {
+                    let address = self.address(segment, segment_index, index)?;
+                    self.ram[address]
+                };
+                self.push(value)?;
+            }
+            Op::Pop(segment, segment_index) => {
+                if segment == Segment::Constant {
+                    return Err(EmulatorError::new("cannot pop to constant".to_owned()));
+                }
+                let address = self.address(segment, segment_index, index)?;
+                self.ram[address] = self.pop()?;
+            }
+            Op::Label => {}
+            Op::Goto(target) => {
+                // `label L, goto L` is how programs halt
+                if target <= index
+                    && self.ops[target..index]
+                        .iter()
+                        .all(|op| matches!(op, Op::Label))
+                {
+                    self.halted = true;
+                }
+                self.pc = target;
+            }
+            Op::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    self.pc = target;
+                }
+            }
+            Op::Function(local_count) => {
+                for _ in 0..local_count {
+                    self.push(0)?;
+                }
+            }
+            Op::Call {
+                target,
+                callee,
+                args_count,
+            } => match target {
+                Some(target) => self.call(target, args_count)?,
+                None => {
+                    let mut args = vec![0; args_count.max(0) as usize];
+                    for arg in args.iter_mut().rev() {
+                        *arg = self.pop()?;
+                    }
+                    let name = self.callees[callee].clone();
+                    let value = os::call(self, &name, &args)?;
+                    self.push(value)?;
+                }
+            },
+            Op::Return => self.ret()?,
+        }
+        Ok(())
+    }
+
+    fn alu(&mut self, operation: Operation) -> Result<()> {
+        let y = self.pop()?;
+        let value = match operation {
+            Operation::Neg => y.wrapping_neg(),
+            Operation::Not => !y,
+            Operation::Inc => y.wrapping_add(1),
+            Operation::Dec => y.wrapping_sub(1),
+            _ => {
+                let x = self.pop()?;
+                match operation {
+                    Operation::Add => x.wrapping_add(y),
+                    Operation::Sub => x.wrapping_sub(y),
+                    Operation::And => x & y,
+                    Operation::Or => x | y,
+                    Operation::Eq => -((x == y) as i16),
+                    Operation::Gt => -((x > y) as i16),
+                    Operation::Lt => -((x < y) as i16),
+                    Operation::Le => -((x <= y) as i16),
+                    Operation::Ge => -((x >= y) as i16),
+                    Operation::Ne => -((x != y) as i16),
+                    Operation::Mul => x.wrapping_mul(y),
+                    // Dividing by zero gives 0, as in code translated by vm_translator
+                    Operation::Div if y == 0 => 0,
+                    Operation::Div => x.wrapping_div(y),
+                    Operation::Mod if y == 0 => 0,
+                    Operation::Mod => x.wrapping_rem(y),
+                    // Shifting by a negative count or by 16 bits or more gives 0
+                    Operation::Shl if !(0..16).contains(&y) => 0,
+                    Operation::Shl => ((x as u16) << y) as i16,
+                    Operation::Shr if !(0..16).contains(&y) => 0,
+                    Operation::Shr => ((x as u16) >> y) as i16,
+                    _ => panic!("Unexpected binary operation {:?}", operation),
+                }
+            }
+        };
+        self.push(value)
+    }
+
+    fn call(&mut self, target: usize, args_count: i16) -> Result<()> {
+        self.push(self.pc as i16)?;
+        for &pointer in [LCL, ARG, THIS, THAT].iter() {
+            self.push(self.ram[pointer])?;
+        }
+        let sp = self.ram[SP];
+        self.ram[ARG] = sp.wrapping_sub(args_count).wrapping_sub(5);
+        self.ram[LCL] = sp;
+        self.pc = target;
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<()> {
+        let frame = self.ram[LCL];
+        let return_address = self.ram[Self::checked_address(frame as isize - 5)?];
+        let value = self.pop()?;
+        let arg = Self::checked_address(self.ram[ARG] as isize)?;
+        self.ram[arg] = value;
+        self.ram[SP] = self.ram[ARG].wrapping_add(1);
+        for (offset, &pointer) in [THAT, THIS, ARG, LCL].iter().enumerate() {
+            self.ram[pointer] =
+                self.ram[Self::checked_address(frame as isize - 1 - offset as isize)?];
+        }
+        self.pc = return_address as u16 as usize;
+        Ok(())
+    }
+
+    fn address(&self, segment: Segment, segment_index: i16, index: usize) -> Result<usize> {
+        let base = match segment {
+            Segment::Argument => self.ram[ARG] as isize,
+            Segment::Local => self.ram[LCL] as isize,
+            Segment::This => self.ram[THIS] as isize,
+            Segment::That => self.ram[THAT] as isize,
+            Segment::Pointer => THIS as isize,
+            Segment::Temp => TEMP_BASE as isize,
+            Segment::Static => self.vm_files[self.locations[index].vm_file].static_base as isize,
+            Segment::Constant => panic!("Constant segment has no address"),
+        };
+        Self::checked_address(base + segment_index as isize)
+    }
+
+    fn checked_address(address: isize) -> Result<usize> {
+        if address >= 0 && (address as usize) < RAM_SIZE {
+            Ok(address as usize)
+        } else {
+            Err(EmulatorError::new(format!(
+                "address {} is out of range",
+                address
+            )))
+        }
+    }
+
+    pub fn push(&mut self, value: i16) -> Result<()> {
+        let sp = Self::checked_address(self.ram[SP] as isize)?;
+        self.ram[sp] = value;
+        self.ram[SP] = self.ram[SP].wrapping_add(1);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<i16> {
+        let sp = Self::checked_address(self.ram[SP] as isize - 1)?;
+        self.ram[SP] = sp as i16;
+        Ok(self.ram[sp])
+    }
+
+    // File and line of the command that `step` executes next
+    pub fn next_location(&self) -> Option<(&Path, usize)> {
+        let index = self.pc
+            + self
+                .ops
+                .get(self.pc..)?
+                .iter()
+                .position(|op| !matches!(op, Op::Label))?;
+        let location = &self.locations[index];
+        Some((&self.vm_files[location.vm_file].path, location.line_no))
+    }
+
+    // RAM address of a static variable, given the name of its file without the extension
+    pub fn static_address(&self, vm_file_stem: &str, index: i16) -> Option<usize> {
+        self.vm_files
+            .iter()
+            .find(|vm_file| {
+                vm_file
+                    .path
+                    .file_stem()
+                    .is_some_and(|stem| stem == vm_file_stem)
+            })
+            .map(|vm_file| vm_file.static_base + index as usize)
+    }
+
+    fn location(&self, index: usize) -> String {
+        let location = &self.locations[index];
+        format!(
+            "{}:{}",
+            self.vm_files[location.vm_file].path.display(),
+            location.line_no
+        )
+    }
+}
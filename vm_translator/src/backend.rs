@@ -0,0 +1,108 @@
+use crate::parser::CommandIter;
+use crate::statics::StaticAllocation;
+use std::path::Path;
+
+// Generates code in some target language from the VM files of a program, one file after
+// the other. The Hack assembly generated by CodeWriter is the standard target; the others
+// run programs natively, laying out memory just like the Hack platform.
+pub trait Backend {
+    fn write_code(&mut self, vm_file_path: &Path, commands: CommandIter<'_>);
+    // Completes the output once all the VM files are written
+    fn finish(&mut self);
+    // Where the static variables written so far are placed in RAM
+    fn statics(&self) -> &StaticAllocation;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Hack,
+    C,
+    Wat,
+    X86_64,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hack" => Some(Target::Hack),
+            "c" => Some(Target::C),
+            "wat" => Some(Target::Wat),
+            "x86-64" => Some(Target::X86_64),
+            _ => None,
+        }
+    }
+
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            Target::Hack => "asm",
+            Target::C => "c",
+            Target::Wat => "wat",
+            Target::X86_64 => "s",
+        }
+    }
+}
+
+// A program with arithmetic, calls and returns, and labels, which leaves 6 * 7 in the first
+// word of the screen, for testing the targets
+#[cfg(test)]
+pub mod test_program {
+    use super::*;
+    use crate::code_writer::Options;
+    use crate::parser;
+    use std::path::PathBuf;
+
+    const VM_FILES: [(&str, &str); 2] = [
+        ("Main.vm", include_str!("../testdata/Main.vm")),
+        ("Sys.vm", include_str!("../testdata/Sys.vm")),
+    ];
+
+    pub fn translate<B: Backend>(backend: B) {
+        let programs = VM_FILES
+            .iter()
+            .map(|(name, vm_code)| {
+                let commands = parser::commands(vm_code.as_bytes())
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                (PathBuf::from(name), commands)
+            })
+            .collect();
+        crate::translate_programs_to(programs, false, &Options::default(), backend).unwrap();
+    }
+
+    // Builds and runs a program in a directory of its own, returning its output once the
+    // program halts. None if the tools to build it aren't installed.
+    pub fn build_and_run(name: &str, code: &str, build: &[&[&str]]) -> Option<Vec<u8>> {
+        use std::process::Command;
+
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(name), code).unwrap();
+        let mut built = true;
+        for args in build {
+            match Command::new(args[0])
+                .args(&args[1..])
+                .current_dir(&dir)
+                .status()
+            {
+                Ok(status) => assert!(status.success(), "{} failed", args[0]),
+                Err(_) => built = false,
+            }
+            if !built {
+                break;
+            }
+        }
+        let output = if built {
+            let output = Command::new(dir.join("program")).output().unwrap();
+            assert!(output.status.success());
+            Some(output.stdout)
+        } else {
+            None
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+        output
+    }
+
+    // The start of the screen image the program writes out as it halts: 42 is 0b101010, with
+    // the lowest bit as the leftmost pixel
+    pub const SCREEN_START: &[u8] = b"P4\n512 256\n\x54\x00";
+}
@@ -0,0 +1,286 @@
+use crate::backend::Backend;
+use crate::code_writer::Options;
+use crate::native::{Symbols, ENTRY_FUNCTION, HALT_FUNCTION, KBD, NEW_LINE, SCREEN, STACK_BASE};
+use crate::parser::{Command, CommandIter, Operation, Segment};
+use crate::statics::StaticAllocation;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Runtime included in every program. The keyboard reads keys from stdin, each key being held
+// down for a single read of the keyboard register, and the program halts at the end of the
+// input. The screen is written to stdout as a PBM image once the program halts.
+const RUNTIME: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+// Conversions to int16_t are assumed to wrap around, as they do with common compilers
+static int16_t ram[32768];
+
+#define M(address) ram[(uint16_t)(address) & 0x7fff]
+#define SP ram[0]
+#define LCL ram[1]
+#define ARG ram[2]
+#define THIS ram[3]
+#define THAT ram[4]
+#define PUSH(value) (M(SP) = (value), SP++)
+#define POP() (SP--, M(SP))
+#define TOP M(SP - 1)
+
+static void halt(void) {
+    printf("P4\n%d %d\n", SCREEN_WIDTH, SCREEN_HEIGHT);
+    for (int address = SCREEN; address < KBD; address++) {
+        uint16_t word = (uint16_t)ram[address];
+        for (int byte = 0; byte < 2; byte++) {
+            int pixels = 0;
+            for (int bit = 0; bit < 8; bit++) {
+                pixels |= ((word >> (byte * 8 + bit)) & 1) << (7 - bit);
+            }
+            putchar(pixels);
+        }
+    }
+    exit(0);
+}
+
+static int key_down;
+
+static int16_t keyboard(void) {
+    if (key_down) {
+        key_down = 0;
+        return 0;
+    }
+    int key = getchar();
+    if (key == EOF) {
+        halt();
+    }
+    key_down = 1;
+    return key == '\n' ? NEW_LINE : key;
+}
+
+// Reads memory through a pointer, which may point at the keyboard
+static int16_t load(int address) {
+    return (uint16_t)address == KBD ? keyboard() : M(address);
+}
+"#;
+
+// Translates to C, in a single function so that VM labels can be jumped to with goto.
+// Return addresses are call site numbers, dispatched on by a switch.
+pub struct CWriter<W: Write> {
+    writer: BufWriter<W>,
+    options: Options,
+    symbols: Symbols,
+}
+
+impl<W: Write> CWriter<W> {
+    pub fn new(out: W, options: Options) -> Self {
+        let mut c_writer = CWriter {
+            writer: BufWriter::new(out),
+            options,
+            symbols: Symbols::default(),
+        };
+        c_writer.init();
+        c_writer
+    }
+
+    fn init(&mut self) {
+        self.emit(&format!(
+            "#define SCREEN {}\n#define KBD {}\n#define NEW_LINE {}\n",
+            SCREEN, KBD, NEW_LINE
+        ));
+        self.emit("#define SCREEN_WIDTH 512\n#define SCREEN_HEIGHT 256\n");
+        self.emit(RUNTIME);
+        self.emit("\nint main(void) {\n    int16_t y, frame;\n    int return_site;\n");
+        self.emit(&format!("    SP = {};\n", STACK_BASE));
+        self.generate_call(ENTRY_FUNCTION, 0);
+        self.emit("    halt();\n");
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.writer.write_all(code.as_bytes()).unwrap();
+    }
+
+    fn statement(&mut self, statement: &str) {
+        self.emit(&format!("    {}\n", statement));
+    }
+
+    fn generate_command(&mut self, command: &Command) {
+        match command {
+            Command::Alu(operation) => self.generate_operation(*operation),
+            Command::Push { segment, index } => {
+                let value = self.read_segment(*segment, *index);
+                self.statement(&format!("PUSH({});", value));
+            }
+            Command::Pop { segment, index } => {
+                let variable = self.segment_variable(*segment, *index);
+                self.statement(&format!("y = POP(); {} = y;", variable));
+            }
+            Command::Label { label } => {
+                let id = self.symbols.label_id(label);
+                self.emit(&format!("L{}:;\n", id));
+            }
+            Command::Goto { label } => {
+                let id = self.symbols.label_id(label);
+                if self.symbols.is_halt_loop(id) {
+                    self.statement("halt();");
+                } else {
+                    self.statement(&format!("goto L{};", id));
+                }
+            }
+            Command::IfGoto { label } => {
+                let id = self.symbols.label_id(label);
+                self.statement(&format!("if (POP()) goto L{};", id));
+            }
+            Command::Function { name, local_count } => {
+                let id = self.symbols.function_id(name);
+                self.emit(&format!("L{}: // {}\n", id, name));
+                for _ in 0..*local_count {
+                    self.statement("PUSH(0);");
+                }
+            }
+            Command::Call { name, args_count } => self.generate_call(name, *args_count),
+            Command::Return => {
+                self.statement("frame = LCL;");
+                self.statement("return_site = M(frame - 5);");
+                self.statement("y = POP(); M(ARG) = y;");
+                self.statement("SP = ARG + 1;");
+                self.statement("THAT = M(frame - 1);");
+                self.statement("THIS = M(frame - 2);");
+                self.statement("ARG = M(frame - 3);");
+                self.statement("LCL = M(frame - 4);");
+                self.statement("goto dispatch;");
+            }
+        }
+    }
+
+    fn generate_operation(&mut self, operation: Operation) {
+        let statement = match operation {
+            Operation::Neg => "TOP = -TOP;",
+            Operation::Not => "TOP = ~TOP;",
+            Operation::Inc => "TOP = TOP + 1;",
+            Operation::Dec => "TOP = TOP - 1;",
+            Operation::Add => "y = POP(); TOP = TOP + y;",
+            Operation::Sub => "y = POP(); TOP = TOP - y;",
+            Operation::And => "y = POP(); TOP = TOP & y;",
+            Operation::Or => "y = POP(); TOP = TOP | y;",
+            Operation::Eq => "y = POP(); TOP = -(TOP == y);",
+            Operation::Gt => "y = POP(); TOP = -(TOP > y);",
+            Operation::Lt => "y = POP(); TOP = -(TOP < y);",
+            Operation::Le => "y = POP(); TOP = -(TOP <= y);",
+            Operation::Ge => "y = POP(); TOP = -(TOP >= y);",
+            Operation::Ne => "y = POP(); TOP = -(TOP != y);",
+            Operation::Mul => "y = POP(); TOP = TOP * y;",
+            Operation::Div => "y = POP(); TOP = y == 0 ? 0 : TOP / y;",
+            Operation::Mod => "y = POP(); TOP = y == 0 ? 0 : TOP % y;",
+            Operation::Shl => "y = POP(); TOP = (uint16_t)y < 16 ? (uint16_t)TOP << y : 0;",
+            Operation::Shr => "y = POP(); TOP = (uint16_t)y < 16 ? (uint16_t)TOP >> y : 0;",
+        };
+        self.statement(statement);
+    }
+
+    fn read_segment(&mut self, segment: Segment, index: i16) -> String {
+        match segment {
+            Segment::Constant => index.to_string(),
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                format!("load({} + {})", pointer_name(segment), index)
+            }
+            _ => self.segment_variable(segment, index),
+        }
+    }
+
+    fn segment_variable(&mut self, segment: Segment, index: i16) -> String {
+        match segment {
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                format!("M({} + {})", pointer_name(segment), index)
+            }
+            Segment::Static => format!("ram[{}]", self.symbols.static_address(index)),
+            Segment::Temp => format!("ram[{}]", 5 + index),
+            Segment::Pointer => format!("ram[{}]", 3 + index),
+            Segment::Constant => panic!("Cannot pop to constant"),
+        }
+    }
+
+    fn generate_call(&mut self, name: &str, args_count: i16) {
+        if name == HALT_FUNCTION {
+            self.statement("halt();");
+            return;
+        }
+        let return_site = self.symbols.next_return_site();
+        let id = self.symbols.function_id(name);
+        self.statement(&format!("PUSH({});", return_site));
+        self.statement("PUSH(LCL); PUSH(ARG); PUSH(THIS); PUSH(THAT);");
+        self.statement(&format!("ARG = SP - {};", args_count + 5));
+        self.statement("LCL = SP;");
+        self.statement(&format!("goto L{}; // {}", id, name));
+        self.emit(&format!("L{}:;\n", return_site));
+    }
+}
+
+impl<W: Write> Backend for CWriter<W> {
+    fn write_code(&mut self, vm_file_path: &Path, commands: CommandIter<'_>) {
+        self.symbols.start_file(vm_file_path);
+        let vm_file_name = vm_file_path.file_name().unwrap().to_string_lossy();
+        for command in commands {
+            let source_command = command.unwrap();
+            if self.options.annotate {
+                self.emit(&format!(
+                    "    // {}:{} {}\n",
+                    vm_file_name, source_command.line_no, source_command.command
+                ));
+            }
+            self.generate_command(&source_command.command);
+            self.symbols.track(&source_command.command);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.statement("halt();");
+        self.emit("dispatch:\n    switch (return_site) {\n");
+        for &return_site in self.symbols.return_sites().to_vec().iter() {
+            self.emit(&format!(
+                "    case {}:\n        goto L{};\n",
+                return_site, return_site
+            ));
+        }
+        self.emit("    }\n    halt();\n}\n");
+        self.writer.flush().unwrap();
+    }
+
+    fn statics(&self) -> &StaticAllocation {
+        self.symbols.statics()
+    }
+}
+
+fn pointer_name(segment: Segment) -> &'static str {
+    match segment {
+        Segment::Local => "LCL",
+        Segment::Argument => "ARG",
+        Segment::This => "THIS",
+        Segment::That => "THAT",
+        _ => panic!("Unexpected segment {}", segment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_program;
+
+    fn c_code() -> String {
+        let mut c_code = vec![];
+        test_program::translate(CWriter::new(&mut c_code, Options::default()));
+        String::from_utf8(c_code).unwrap()
+    }
+
+    #[test]
+    fn translates_program() {
+        assert_eq!(c_code(), include_str!("../testdata/Program.c"));
+    }
+
+    // Skipped without a C compiler
+    #[test]
+    fn runs_program() {
+        let build: &[&[&str]] = &[&["cc", "-o", "program", "program.c"]];
+        if let Some(output) = test_program::build_and_run("program.c", &c_code(), build) {
+            assert!(output.starts_with(test_program::SCREEN_START));
+        }
+    }
+}
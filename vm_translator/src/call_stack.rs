@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+
+// Recovers the call stack of a program translated with `Options::trace` from its RAM. Each
+// frame is laid out as
+//   argument 0 .. argument n-1, function id, return address, LCL, ARG, THIS, THAT
+// with LCL pointing just past it.
+
+const LCL: usize = 1;
+const ARG: usize = 2;
+// Where the bootstrap code calls Sys.init from
+const STACK_BASE: usize = 256;
+// Words from the function id up to where LCL points
+const FRAME_SIZE: usize = 6;
+
+#[derive(Debug)]
+pub struct Frame {
+    pub function: String,
+    pub args: Vec<i16>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(i16::to_string).collect();
+        write!(formatter, "{}({})", self.function, args.join(", "))
+    }
+}
+
+// The frames on the stack, innermost first, given the function names indexed by id
+pub fn call_stack(ram: &[i16], function_names: &[String]) -> Vec<Frame> {
+    let mut frames = vec![];
+    let mut lcl = ram[LCL] as u16 as usize;
+    let mut arg = ram[ARG] as u16 as usize;
+    while arg >= STACK_BASE && arg + FRAME_SIZE <= lcl && lcl <= ram.len() {
+        let frame = lcl - FRAME_SIZE;
+        let id = ram[frame];
+        let function = match function_names.get(id as u16 as usize) {
+            Some(name) => name.clone(),
+            None => format!("<function {}>", id),
+        };
+        frames.push(Frame {
+            function,
+            args: ram[arg..frame].to_vec(),
+        });
+        // Sys.init, called by the bootstrap code
+        if arg == STACK_BASE {
+            break;
+        }
+        let caller_lcl = ram[lcl - 4] as u16 as usize;
+        if caller_lcl >= lcl {
+            break;
+        }
+        lcl = caller_lcl;
+        arg = ram[frame + 3] as u16 as usize;
+    }
+    frames
+}
+
+// One tab-separated id and function name per line
+pub fn write_function_table<W: Write>(out: W, function_names: &[String]) -> io::Result<()> {
+    let mut out = io::BufWriter::new(out);
+    writeln!(out, "# id\tfunction")?;
+    for (id, name) in function_names.iter().enumerate() {
+        writeln!(out, "{}\t{}", id, name)?;
+    }
+    out.flush()
+}
+
+pub fn read_function_table<R: Read>(input: R) -> io::Result<Vec<String>> {
+    let mut function_names = vec![];
+    for line in BufReader::new(input).lines() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, line.clone());
+        let mut fields = line.split('\t');
+        let id = fields
+            .next()
+            .and_then(|id| id.parse::<usize>().ok())
+            .ok_or_else(invalid)?;
+        let name = fields.next().ok_or_else(invalid)?;
+        if id != function_names.len() {
+            return Err(invalid());
+        }
+        function_names.push(name.to_owned());
+    }
+    Ok(function_names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_written_function_table() {
+        let function_names = vec!["Sys.init".to_owned(), "Main.main".to_owned()];
+        let mut table = vec![];
+        write_function_table(&mut table, &function_names).unwrap();
+        assert_eq!(
+            String::from_utf8(table.clone()).unwrap(),
+            "# id\tfunction\n0\tSys.init\n1\tMain.main\n"
+        );
+        assert_eq!(read_function_table(&table[..]).unwrap(), function_names);
+    }
+
+    #[test]
+    fn rejects_function_tables_out_of_order() {
+        assert!(read_function_table("1\tMain.main\n".as_bytes()).is_err());
+        assert!(read_function_table("0 Main.main\n".as_bytes()).is_err());
+    }
+}
@@ -1,6 +1,9 @@
+use crate::backend::Backend;
 use crate::parser::{Command, CommandIter, Operation, Segment};
+use crate::source_map::SourceMapEntry;
+use crate::statics::StaticAllocation;
 use asm_macro::hack;
-use std::fs::File;
+use std::collections::HashMap;
 use std::io::{BufWriter, Write};
 use std::path::Path;
 
@@ -36,11 +39,49 @@ macro_rules! ASM_BINARY_OP {
     };
 }
 
+// D = x - y, or just the sign of x - y when x and y have different signs and the
+// subtraction could overflow. X is in M. Y is in D
+macro_rules! ASM_SIGNED_DIFFERENCE {
+    () => {
+        hack!(
+            "@R13",
+            M = D,
+            "@SP",
+            A = M,
+            D = M,
+            "@{xneg}",
+            "D;JLT",
+            "@R13",
+            D = M,
+            "@{same}",
+            "D;JGE",
+            D = 1,
+            "@{done}",
+            "0;JMP",
+            "({xneg})",
+            "@R13",
+            D = M,
+            "@{same}",
+            "D;JLT",
+            D = -1,
+            "@{done}",
+            "0;JMP",
+            "({same})",
+            "@SP",
+            A = M,
+            D = M,
+            "@R13",
+            D = D - M,
+            "({done})"
+        )
+    };
+}
+
 macro_rules! ASM_LOGICAL_OP {
     () => {
         hack!(
             "// logical operation",
-            D = M - D,
+            "{difference}",
             "@{ifsuccess}",
             "D;{jmpop}",
             "@SP",
@@ -57,6 +98,333 @@ macro_rules! ASM_LOGICAL_OP {
     };
 }
 
+// Shift-and-add multiplication: R13 holds the bits of y still to test, R14 is x shifted
+// left to the bit being tested and R15 the bit. The product accumulates in x's place.
+macro_rules! ASM_MUL {
+    () => {
+        hack!(
+            "// multiplication",
+            "@R13",
+            M = D,
+            "@SP",
+            A = M,
+            D = M,
+            "@R14",
+            M = D,
+            "@SP",
+            A = M,
+            M = 0,
+            "@R15",
+            M = 1,
+            "({round})",
+            "@R15",
+            D = M,
+            "@R13",
+            D = D & M,
+            "@{skip}",
+            "D;JEQ",
+            "@R14",
+            D = M,
+            "@SP",
+            A = M,
+            M = D + M,
+            "({skip})",
+            "@R14",
+            D = M,
+            M = D + M,
+            "@R15",
+            D = M,
+            MD = D + M,
+            "@{round}",
+            "D;JNE"
+        )
+    };
+}
+
+// Long division of |x| by |y|, a bit of the quotient per round. R13 is |x| shifted left to
+// the bit being divided, R14 is |y| and R15 the remainder. The quotient is built in the
+// stack slot above y and the rounds are counted by the bit in the slot above that. x and y
+// stay in their slots for the signs of the results.
+macro_rules! ASM_DIVISION {
+    () => {
+        hack!(
+            "// division",
+            "@R14",
+            M = D,
+            "@{ypos}",
+            "D;JGE",
+            "@R14",
+            M = -M,
+            "({ypos})",
+            "@R14",
+            D = M,
+            "@{zero}",
+            "D;JEQ",
+            "@SP",
+            A = M,
+            D = M,
+            "@R13",
+            M = D,
+            "@{xpos}",
+            "D;JGE",
+            "@R13",
+            M = -M,
+            "({xpos})",
+            "@R15",
+            M = 0,
+            "@SP",
+            D = M,
+            "@2",
+            A = D + A,
+            M = 0,
+            A = A + 1,
+            M = 1,
+            "({round})",
+            "// shift the next bit of |x| into the remainder",
+            "@R15",
+            D = M,
+            M = D + M,
+            "@R13",
+            D = M,
+            M = D + M,
+            "@{nobit}",
+            "D;JGE",
+            "@R15",
+            M = M + 1,
+            "({nobit})",
+            "@SP",
+            D = M,
+            "@2",
+            A = D + A,
+            D = M,
+            M = D + M,
+            "// subtract |y| if the remainder is at least |y|, comparing unsigned values",
+            "@R15",
+            D = M,
+            "@{rneg}",
+            "D;JLT",
+            "@R14",
+            D = M,
+            "@{next}",
+            "D;JLT",
+            "@{compare}",
+            "0;JMP",
+            "({rneg})",
+            "@R14",
+            D = M,
+            "@{subtract}",
+            "D;JGE",
+            "({compare})",
+            "@R14",
+            D = M,
+            "@R15",
+            D = M - D,
+            "@{next}",
+            "D;JLT",
+            "({subtract})",
+            "@R14",
+            D = M,
+            "@R15",
+            M = M - D,
+            "@SP",
+            D = M,
+            "@2",
+            A = D + A,
+            M = M + 1,
+            "({next})",
+            "@SP",
+            D = M,
+            "@3",
+            A = D + A,
+            D = M,
+            MD = D + M,
+            "@{round}",
+            "D;JNE",
+            "{result}",
+            "@{store}",
+            "0;JMP",
+            "({zero})",
+            D = 0,
+            "({store})",
+            "@SP",
+            A = M,
+            M = D
+        )
+    };
+}
+
+// D = the quotient, negated if x and y have different signs
+macro_rules! ASM_QUOTIENT {
+    () => {
+        hack!(
+            "@SP",
+            D = M,
+            "@2",
+            A = D + A,
+            D = M,
+            "@R13",
+            M = D,
+            "@SP",
+            A = M,
+            D = M,
+            "@{xneg}",
+            "D;JLT",
+            "@SP",
+            A = M + 1,
+            D = M,
+            "@{negate}",
+            "D;JLT",
+            "@{positive}",
+            "0;JMP",
+            "({xneg})",
+            "@SP",
+            A = M + 1,
+            D = M,
+            "@{negate}",
+            "D;JGE",
+            "({positive})",
+            "@R13",
+            D = M,
+            "@{done}",
+            "0;JMP",
+            "({negate})",
+            "@R13",
+            D = -M,
+            "({done})"
+        )
+    };
+}
+
+// D = the remainder, with the sign of x
+macro_rules! ASM_REMAINDER {
+    () => {
+        hack!(
+            "@SP",
+            A = M,
+            D = M,
+            "@{negate}",
+            "D;JLT",
+            "@R15",
+            D = M,
+            "@{done}",
+            "0;JMP",
+            "({negate})",
+            "@R15",
+            D = -M,
+            "({done})"
+        )
+    };
+}
+
+// Shifts x left by y bits, doubling it y times. Shifting by a negative count or by 16 bits
+// or more gives 0.
+macro_rules! ASM_SHL {
+    () => {
+        hack!(
+            "// shift left",
+            "@R13",
+            M = D,
+            "@16",
+            D = D - A,
+            "@{zero}",
+            "D;JGE",
+            "@R13",
+            D = M,
+            "@{zero}",
+            "D;JLT",
+            "({round})",
+            "@R13",
+            D = M,
+            M = D - 1,
+            "@{done}",
+            "D;JEQ",
+            "@SP",
+            A = M,
+            D = M,
+            M = D + M,
+            "@{round}",
+            "0;JMP",
+            "({zero})",
+            "@SP",
+            A = M,
+            M = 0,
+            "({done})"
+        )
+    };
+}
+
+// Shifts x right by y bits, filling in zeros, by copying each bit of x from bit y up (R14)
+// to bit 0 up (R15). Shifting by a negative count or by 16 bits or more gives 0.
+macro_rules! ASM_SHR {
+    () => {
+        hack!(
+            "// shift right",
+            "@R13",
+            M = D,
+            "@16",
+            D = D - A,
+            "@{zero}",
+            "D;JGE",
+            "@R13",
+            D = M,
+            "@{zero}",
+            "D;JLT",
+            "@R14",
+            M = 1,
+            "({mask})",
+            "@R13",
+            D = M,
+            M = D - 1,
+            "@{shifted}",
+            "D;JEQ",
+            "@R14",
+            D = M,
+            M = D + M,
+            "@{mask}",
+            "0;JMP",
+            "({shifted})",
+            "@R15",
+            M = 1,
+            "@SP",
+            A = M,
+            D = M,
+            "@R13",
+            M = D,
+            "@SP",
+            A = M,
+            M = 0,
+            "({round})",
+            "@R14",
+            D = M,
+            "@R13",
+            D = D & M,
+            "@{skip}",
+            "D;JEQ",
+            "@R15",
+            D = M,
+            "@SP",
+            A = M,
+            M = D | M,
+            "({skip})",
+            "@R15",
+            D = M,
+            M = D + M,
+            "@R14",
+            D = M,
+            MD = D + M,
+            "@{round}",
+            "D;JNE",
+            "@{done}",
+            "0;JMP",
+            "({zero})",
+            "@SP",
+            A = M,
+            M = 0,
+            "({done})"
+        )
+    };
+}
+
 macro_rules! ASM_PUSH_SEGMENT_OP {
     () => {
         hack!("{load_val}", "@SP", A = M, M = D, "@SP", M = M + 1,)
@@ -94,27 +462,87 @@ macro_rules! ASM_SEGMENT_BASE_RESTORE {
 }
 
 lazy_static! {
-    static ref UNARY_OPERATIONS: Vec<Operation> = vec![Operation::Neg, Operation::Not];
+    static ref UNARY_OPERATIONS: Vec<Operation> =
+        vec![Operation::Neg, Operation::Not, Operation::Inc, Operation::Dec];
     // Segments where each memory location has a unique variable name
     static ref NAMED_SEGMENTS: Vec<Segment> = vec![Segment::Pointer, Segment::Static, Segment::Temp];
 }
 
-pub struct CodeWriter {
-    writer: BufWriter<File>,
-    label_counter: i16,
+// Error code the overflow handler is called with, following the codes of the Jack OS
+pub const STACK_OVERFLOW_ERROR: i16 = 21;
+// Where the heap starts
+pub const DEFAULT_STACK_LIMIT: i16 = 2048;
+// Label of the code calling the overflow handler
+const STACK_OVERFLOW: &str = "STACK_OVERFLOW";
+// Division writes scratch values in the two stack slots above the top, which the stack
+// checks make room for whenever the extended instructions are enabled
+const DIVISION_SCRATCH_SIZE: i16 = 2;
+// Kinds of the labels generated by the translator, which are named {scope}${kind}.{n}
+pub const GENERATED_LABEL_KINDS: &[&str] = &["cmp", "div", "end", "if", "mul", "ret", "shift"];
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    // Prefix the code of each command with its VM source, e.g. `// Foo.vm:42 push local 2`
+    pub annotate: bool,
+    // Translate the extended instruction set: mul, div, mod, shl, shr, inc, dec, le, ge and ne
+    pub extended: bool,
+    // Check that calls and function entries don't grow the stack up to `stack_limit`,
+    // calling `overflow_handler` with STACK_OVERFLOW_ERROR when they do. Pushes within a
+    // function aren't checked, so the stack can still run a little past the limit.
+    pub checked: bool,
+    pub stack_limit: i16,
+    pub overflow_handler: String,
+    // Push the id of the called function below the return address of each frame, so that
+    // the call stack can be recovered from RAM with the table of function names
+    pub trace: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            annotate: false,
+            extended: false,
+            checked: false,
+            stack_limit: DEFAULT_STACK_LIMIT,
+            overflow_handler: "Sys.error".to_owned(),
+            trace: false,
+        }
+    }
+}
+
+pub struct CodeWriter<W: Write> {
+    writer: BufWriter<W>,
+    options: Options,
+    label_counter: u32,
+    asm_line_count: usize,
+    rom_address: usize,
+    source_map: Vec<SourceMapEntry>,
     current_vm_file: Option<String>,
+    current_vm_file_name: Option<String>,
     current_fn_name: Option<String>,
     current_command: Option<Command>,
+    // Functions by id, in the order they are first called or defined
+    function_names: Vec<String>,
+    function_ids: HashMap<String, i16>,
+    statics: StaticAllocation,
 }
 
-impl CodeWriter {
-    pub fn new(out_file: File) -> Self {
+impl<W: Write> CodeWriter<W> {
+    pub fn new(out: W, options: Options) -> Self {
         let mut cwriter = CodeWriter {
-            writer: BufWriter::new(out_file),
+            writer: BufWriter::new(out),
+            options,
             label_counter: 0,
+            asm_line_count: 0,
+            rom_address: 0,
+            source_map: vec![],
             current_vm_file: None,
+            current_vm_file_name: None,
             current_fn_name: None,
             current_command: None,
+            function_names: vec![],
+            function_ids: HashMap::new(),
+            statics: StaticAllocation::default(),
         };
         cwriter.init_vm();
         cwriter
@@ -122,34 +550,162 @@ impl CodeWriter {
 
     pub fn init_vm(&mut self) {
         let asm = hack!("@256", D = A, "@SP", M = D,);
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(asm);
         self.generate_call("Sys.init".to_owned(), 0);
+        if self.options.checked {
+            self.generate_overflow_handler_call();
+        }
+    }
+
+    // Resets the stack, so that there is room for calling the handler, and calls it. The
+    // code is placed after the bootstrap code, so it is jumped over should Sys.init return.
+    fn generate_overflow_handler_call(&mut self) {
+        let end = self.get_next_label("end");
+        let asm = format!(
+            hack!(
+                "@{end}",
+                "0;JMP",
+                "({overflow})",
+                "@256",
+                D = A,
+                "@SP",
+                M = D,
+                "@{code}",
+                D = A,
+                "@SP",
+                A = M,
+                M = D,
+                "@SP",
+                M = M + 1,
+            ),
+            end = end,
+            overflow = STACK_OVERFLOW,
+            code = STACK_OVERFLOW_ERROR
+        );
+        self.emit(&asm);
+        self.generate_call(self.options.overflow_handler.clone(), 1);
+        // The handler isn't expected to return
+        let asm = format!(hack!("({end})", "@{end}", "0;JMP",), end = end);
+        self.emit(&asm);
     }
 
-    pub fn write_code<P: AsRef<Path>>(&mut self, vm_file_path: P, commands: CommandIter) {
+    // Jumps to the overflow handler if the stack is about to grow past the limit. Compares
+    // SP + growth with the limit, so that no constant goes negative.
+    fn generate_stack_check(&mut self, growth: i16) {
+        let growth = if self.options.extended {
+            growth + DIVISION_SCRATCH_SIZE
+        } else {
+            growth
+        };
+        let asm = format!(
+            hack!(
+                "// check for stack overflow",
+                "@SP",
+                D = M,
+                "@{growth}",
+                D = D + A,
+                "@{limit}",
+                D = D - A,
+                "@{overflow}",
+                "D;JGT",
+            ),
+            growth = growth,
+            limit = self.options.stack_limit,
+            overflow = STACK_OVERFLOW
+        );
+        self.emit(&asm);
+    }
+
+    pub fn write_code<P: AsRef<Path>>(&mut self, vm_file_path: P, commands: CommandIter<'_>) {
+        let vm_file_path = vm_file_path.as_ref();
         self.current_vm_file = Some(
             vm_file_path
-                .as_ref()
                 .file_stem()
                 .unwrap()
                 .to_str()
                 .unwrap()
                 .to_owned(),
         );
+        self.current_vm_file_name = Some(
+            vm_file_path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        );
         for command in commands {
-            self.current_command = Some(command.unwrap());
+            let source_command = command.unwrap();
+            let asm_line = self.asm_line_count;
+            let rom_address = self.rom_address;
+            let vm_file = self.current_vm_file_name.clone().unwrap();
+            if self.options.annotate {
+                let comment = format!(
+                    "// {}:{} {}\n",
+                    vm_file, source_command.line_no, source_command.command
+                );
+                self.emit(&comment);
+            }
+            self.current_command = Some(source_command.command);
             self.generate_command_code();
+            self.source_map.push(SourceMapEntry {
+                asm_lines: asm_line + 1..self.asm_line_count + 1,
+                rom_addresses: rom_address..self.rom_address,
+                vm_file,
+                vm_line_no: source_command.line_no,
+                fn_name: self.current_fn_name.clone(),
+            });
         }
         self.writer.flush().unwrap();
     }
 
-    fn get_next_label(&mut self) -> String {
+    pub fn into_source_map(self) -> Vec<SourceMapEntry> {
+        self.source_map
+    }
+
+    // Names of the functions called or defined so far, indexed by the ids pushed on calls
+    pub fn function_names(&self) -> &[String] {
+        &self.function_names
+    }
+
+    fn function_id(&mut self, name: &str) -> i16 {
+        if let Some(&id) = self.function_ids.get(name) {
+            return id;
+        }
+        let id = self.function_names.len() as i16;
+        self.function_names.push(name.to_owned());
+        self.function_ids.insert(name.to_owned(), id);
+        id
+    }
+
+    // Writes out generated code, keeping track of the asm lines and ROM addresses used so far
+    fn emit(&mut self, asm: &str) {
+        for line in asm.lines() {
+            self.asm_line_count += 1;
+            let line = line.trim();
+            if !(line.is_empty() || line.starts_with("//") || line.starts_with('(')) {
+                self.rom_address += 1;
+            }
+        }
+        self.writer.write_all(asm.as_bytes()).unwrap();
+    }
+
+    // Unique across the program thanks to the counter, the scope making it readable
+    fn get_next_label(&mut self, kind: &str) -> String {
         self.label_counter += 1;
-        format!("l{}", self.label_counter)
+        let scope = self
+            .current_fn_name
+            .as_ref()
+            .or(self.current_vm_file.as_ref())
+            .map_or("bootstrap", String::as_str);
+        format!("{}${}.{}", scope, kind, self.label_counter)
     }
 
-    fn get_static_variable(&self, index: i16) -> String {
-        format!("{}.{}", self.current_vm_file.as_ref().unwrap(), index)
+    // Also keeps track of the address the assembler will allocate the variable
+    fn get_static_variable(&mut self, index: i16) -> String {
+        let vm_file = self.current_vm_file.as_ref().unwrap();
+        self.statics.address(vm_file, index);
+        format!("{}.{}", vm_file, index)
     }
 
     fn generate_command_code(&mut self) {
@@ -196,10 +752,12 @@ impl CodeWriter {
         let operation = match operation {
             Operation::Neg => "M=-M",
             Operation::Not => "M=!M",
+            Operation::Inc => "M=M+1",
+            Operation::Dec => "M=M-1",
             _ => panic!("Unexpected unary operation!"),
         };
         let asm = format!(ASM_UNARY_OP!(), operation = operation);
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     // X op Y. X is in M. Y is in D
@@ -207,22 +765,92 @@ impl CodeWriter {
         let operation = match operation {
             Operation::Add => "M=M+D".to_string(),
             Operation::And => "M=M&D".to_string(),
-            Operation::Eq => self.generate_logical_operation("JEQ"),
-            Operation::Gt => self.generate_logical_operation("JGT"),
-            Operation::Lt => self.generate_logical_operation("JLT"),
+            Operation::Eq => self.generate_logical_operation("JEQ", false),
+            Operation::Gt => self.generate_logical_operation("JGT", true),
+            Operation::Lt => self.generate_logical_operation("JLT", true),
             Operation::Or => "M=M|D".to_owned(),
             Operation::Sub => "M=M-D".to_owned(),
+            Operation::Mul => format!(
+                ASM_MUL!(),
+                round = self.get_next_label("mul"),
+                skip = self.get_next_label("mul")
+            ),
+            Operation::Div | Operation::Mod => self.generate_division(operation),
+            Operation::Shl => format!(
+                ASM_SHL!(),
+                round = self.get_next_label("shift"),
+                zero = self.get_next_label("shift"),
+                done = self.get_next_label("shift")
+            ),
+            Operation::Shr => format!(
+                ASM_SHR!(),
+                mask = self.get_next_label("shift"),
+                shifted = self.get_next_label("shift"),
+                round = self.get_next_label("shift"),
+                skip = self.get_next_label("shift"),
+                zero = self.get_next_label("shift"),
+                done = self.get_next_label("shift")
+            ),
+            Operation::Le => self.generate_logical_operation("JLE", true),
+            Operation::Ge => self.generate_logical_operation("JGE", true),
+            Operation::Ne => self.generate_logical_operation("JNE", false),
             _ => panic!("Unexpected binary operation {:?}", operation),
         };
         let asm = format!(ASM_BINARY_OP!(), operation = operation);
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
-    fn generate_logical_operation(&mut self, jmpop: &str) -> String {
-        let ifsuccess = self.get_next_label();
-        let end = self.get_next_label();
+    // Division truncates towards zero, as Math.divide does. Dividing by zero gives 0, where
+    // Math.divide calls Sys.error with code 3.
+    fn generate_division(&mut self, operation: Operation) -> String {
+        let result = if operation == Operation::Div {
+            format!(
+                ASM_QUOTIENT!(),
+                xneg = self.get_next_label("div"),
+                negate = self.get_next_label("div"),
+                positive = self.get_next_label("div"),
+                done = self.get_next_label("div")
+            )
+        } else {
+            format!(
+                ASM_REMAINDER!(),
+                negate = self.get_next_label("div"),
+                done = self.get_next_label("div")
+            )
+        };
+        format!(
+            ASM_DIVISION!(),
+            ypos = self.get_next_label("div"),
+            zero = self.get_next_label("div"),
+            xpos = self.get_next_label("div"),
+            round = self.get_next_label("div"),
+            nobit = self.get_next_label("div"),
+            rneg = self.get_next_label("div"),
+            next = self.get_next_label("div"),
+            compare = self.get_next_label("div"),
+            subtract = self.get_next_label("div"),
+            store = self.get_next_label("div"),
+            result = result
+        )
+    }
+
+    // Equality can use x - y even when it overflows, but ordering can't
+    fn generate_logical_operation(&mut self, jmpop: &str, signed: bool) -> String {
+        let difference = if signed {
+            format!(
+                ASM_SIGNED_DIFFERENCE!(),
+                xneg = self.get_next_label("cmp"),
+                same = self.get_next_label("cmp"),
+                done = self.get_next_label("cmp")
+            )
+        } else {
+            "D=M-D".to_owned()
+        };
+        let ifsuccess = self.get_next_label("cmp");
+        let end = self.get_next_label("cmp");
         format!(
             ASM_LOGICAL_OP!(),
+            difference = difference,
             ifsuccess = ifsuccess,
             end = end,
             jmpop = jmpop
@@ -262,7 +890,7 @@ impl CodeWriter {
             load_val.push_str("\nD=M");
         }
         let asm = format!(ASM_PUSH_SEGMENT_OP!(), load_val = load_val);
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     fn generate_pop_segment(&mut self, segment: Segment, index: i16) {
@@ -284,7 +912,7 @@ impl CodeWriter {
             format!(hack!("@{variable}", A = M), variable = variable)
         };
         let asm = format!(ASM_POP_SEGMENT_OP!(), setup_m = setup_m);
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
 
         // Restore segment variable to point to the base
         if !NAMED_SEGMENTS.contains(&segment) && index > 0 {
@@ -294,18 +922,18 @@ impl CodeWriter {
 
     fn generate_label(&mut self, label: String) {
         let asm = format!("({})\n", self.get_global_label(label));
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     fn generate_goto(&mut self, label: String) {
         let global_label = self.get_global_label(label);
         let asm = format!(hack!("@{goto_label}", "0;JMP",), goto_label = global_label);
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     fn generate_if_goto(&mut self, label: String) {
         let global_label = self.get_global_label(label);
-        let next_command = self.get_next_label();
+        let next_command = self.get_next_label("if");
         let asm = format!(
             hack!(
                 "@SP",
@@ -321,23 +949,49 @@ impl CodeWriter {
             next_command = next_command,
             goto_label = global_label
         );
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     fn generate_function(&mut self, name: String, local_count: i16) {
-        let mut asm = format!("({})\n", name);
+        self.function_id(&name);
+        self.emit(&format!("({})\n", name));
+        if self.options.checked && (local_count > 0 || self.options.extended) {
+            self.generate_stack_check(local_count);
+        }
+        let mut asm = String::new();
         if local_count > 0 {
             asm.push_str("@SP\n");
             for _ in 0..local_count {
                 asm.push_str(hack!(A = M, M = 0, "@SP", M = M + 1,));
             }
         }
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
         self.current_fn_name = Some(name);
     }
 
     fn generate_call(&mut self, name: String, args_count: i16) {
-        let return_label = self.get_next_label();
+        // The return address and the saved LCL, ARG, THIS and THAT, and the function id
+        let frame_size = if self.options.trace { 6 } else { 5 };
+        if self.options.checked {
+            self.generate_stack_check(frame_size);
+        }
+        if self.options.trace {
+            let asm = format!(
+                hack!(
+                    "// push function id",
+                    "@{id}",
+                    D = A,
+                    "@SP",
+                    A = M,
+                    M = D,
+                    "@SP",
+                    M = M + 1,
+                ),
+                id = self.function_id(&name)
+            );
+            self.emit(&asm);
+        }
+        let return_label = self.get_next_label("ret");
         let asm = format!(
             hack!(
                 "// push return-address",
@@ -380,11 +1034,11 @@ impl CodeWriter {
                 M = D,
                 "@SP",
                 M = M + 1,
-                "// ARG = SP-n-5",
+                "// ARG = SP-n-frame_size",
                 D = M,
                 "@{args_count}",
                 D = D - A,
-                "@5",
+                "@{frame_size}",
                 D = D - A,
                 "@ARG",
                 M = D,
@@ -400,9 +1054,10 @@ impl CodeWriter {
             ),
             return_label = return_label,
             args_count = args_count,
+            frame_size = frame_size,
             fn_name = name
         );
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     fn generate_return(&mut self) {
@@ -465,15 +1120,15 @@ impl CodeWriter {
             A = M,
             "0;JMP",
         );
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(asm);
     }
 
     fn get_global_label(&self, label: String) -> String {
-        let prefix = if self.current_fn_name.is_some() {
-            self.current_fn_name.as_ref().unwrap()
-        } else {
-            self.current_vm_file.as_ref().unwrap()
-        };
+        let prefix = self
+            .current_fn_name
+            .as_ref()
+            .or(self.current_vm_file.as_ref())
+            .unwrap();
         format!("{}${}", prefix, label)
     }
 
@@ -505,7 +1160,7 @@ impl CodeWriter {
             segment_var = Self::get_segment_base(segment),
             index = index
         );
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
     }
 
     fn generate_segment_base_restore(&mut self, segment: Segment, index: i16) {
@@ -514,6 +1169,20 @@ impl CodeWriter {
             segment_var = Self::get_segment_base(segment),
             index = index
         );
-        self.writer.write(asm.as_bytes()).unwrap();
+        self.emit(&asm);
+    }
+}
+
+impl<W: Write> Backend for CodeWriter<W> {
+    fn write_code(&mut self, vm_file_path: &Path, commands: CommandIter<'_>) {
+        CodeWriter::write_code(self, vm_file_path, commands);
+    }
+
+    fn finish(&mut self) {
+        self.writer.flush().unwrap();
+    }
+
+    fn statics(&self) -> &StaticAllocation {
+        &self.statics
     }
 }
@@ -0,0 +1,193 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate asm_macro;
+
+pub mod backend;
+pub mod c_writer;
+pub mod call_stack;
+pub mod code_writer;
+pub mod linker;
+mod native;
+pub mod optimizer;
+pub mod parser;
+pub mod source_map;
+pub mod statics;
+pub mod wat_writer;
+pub mod x86_64_writer;
+
+use backend::Backend;
+use code_writer::{CodeWriter, Options};
+use parser::{Command, SourceCommand};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, TranslateError>;
+
+#[derive(Debug)]
+pub struct TranslateError {
+    pub messages: Vec<String>,
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.messages.join("\n"))
+    }
+}
+
+impl Error for TranslateError {}
+
+impl From<io::Error> for TranslateError {
+    fn from(error: io::Error) -> Self {
+        TranslateError {
+            messages: vec![error.to_string()],
+        }
+    }
+}
+
+// Translates in-memory VM code, given as (file name, code) pairs, to Hack assembly
+pub fn translate(inputs: &[(&str, &str)]) -> Result<String> {
+    let mut programs = vec![];
+    for (name, vm_code) in inputs {
+        let commands = parser::commands(vm_code.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|error| located(Path::new(name), error))?;
+        programs.push((PathBuf::from(name), commands));
+    }
+    let mut asm = vec![];
+    translate_programs(programs, false, Options::default(), &mut asm)?;
+    Ok(String::from_utf8(asm).unwrap())
+}
+
+// Links, optionally optimizes and writes out the parsed VM files as a single asm program.
+// The returned writer holds the source map of the generated code.
+pub fn translate_programs<W: Write>(
+    programs: Vec<(PathBuf, Vec<SourceCommand>)>,
+    optimize: bool,
+    options: Options,
+    out: W,
+) -> Result<CodeWriter<W>> {
+    let programs = link_programs(programs, optimize, &options)?;
+    let writer = CodeWriter::new(out, options);
+    Ok(write_programs(programs, writer))
+}
+
+// Like translate_programs, for any target
+pub fn translate_programs_to<B: Backend>(
+    programs: Vec<(PathBuf, Vec<SourceCommand>)>,
+    optimize: bool,
+    options: &Options,
+    backend: B,
+) -> Result<B> {
+    let programs = link_programs(programs, optimize, options)?;
+    Ok(write_programs(programs, backend))
+}
+
+fn link_programs(
+    mut programs: Vec<(PathBuf, Vec<SourceCommand>)>,
+    optimize: bool,
+    options: &Options,
+) -> Result<Vec<(PathBuf, Vec<SourceCommand>)>> {
+    let mut messages = linker::check(&programs);
+    if !options.extended {
+        messages.extend(extended_operations(&programs));
+    }
+    // Functions called by the generated code rather than by the VM code
+    let mut required = vec![];
+    if options.checked {
+        if !linker::defines(&programs, &options.overflow_handler) {
+            messages.push(format!(
+                "stack overflow handler {} is not defined",
+                options.overflow_handler
+            ));
+        }
+        required.push(options.overflow_handler.as_str());
+    }
+    if !messages.is_empty() {
+        return Err(TranslateError { messages });
+    }
+
+    if optimize {
+        optimizer::optimize(&mut programs, &required);
+    }
+    Ok(programs)
+}
+
+fn write_programs<B: Backend>(programs: Vec<(PathBuf, Vec<SourceCommand>)>, mut backend: B) -> B {
+    for (vm_file_path, commands) in programs {
+        backend.write_code(&vm_file_path, Box::new(commands.into_iter().map(Ok)));
+    }
+    backend.finish();
+    backend
+}
+
+// Reports uses of the extended instruction set, for when it isn't enabled
+fn extended_operations(programs: &[(PathBuf, Vec<SourceCommand>)]) -> Vec<String> {
+    let mut errors = vec![];
+    for (vm_file_path, commands) in programs {
+        for source_command in commands {
+            if let Command::Alu(operation) = source_command.command {
+                if operation.is_extended() {
+                    errors.push(format!(
+                        "{}:{}: {} is an extended instruction, which needs --extended",
+                        vm_file_path.display(),
+                        source_command.line_no,
+                        operation
+                    ));
+                }
+            }
+        }
+    }
+    errors
+}
+
+// The .vm files of a directory in sorted order, or the path itself if it is a file
+pub fn vm_file_paths_in(input_path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !fs::metadata(input_path)?.is_dir() {
+        return Ok(vec![input_path.to_owned()]);
+    }
+    let mut vm_file_paths: Vec<PathBuf> = fs::read_dir(input_path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            if let Some(ext) = path.extension() {
+                ext == "vm"
+            } else {
+                false
+            }
+        })
+        .collect();
+    vm_file_paths.sort();
+    Ok(vm_file_paths)
+}
+
+pub fn parse_vm_files(
+    vm_file_paths: Vec<PathBuf>,
+) -> io::Result<Vec<(PathBuf, Vec<SourceCommand>)>> {
+    let mut programs = vec![];
+    for vm_file_path in vm_file_paths {
+        let vm_file = File::open(&vm_file_path)?;
+        let commands = parser::commands(vm_file)
+            .collect::<io::Result<Vec<SourceCommand>>>()
+            .map_err(|error| located(&vm_file_path, error))?;
+        programs.push((vm_file_path, commands));
+    }
+    Ok(programs)
+}
+
+// Prefixes the path to errors, whose messages start with the line number for parse errors
+fn located(path: &Path, error: io::Error) -> io::Error {
+    let separator = if error.kind() == io::ErrorKind::InvalidData {
+        ":"
+    } else {
+        ": "
+    };
+    io::Error::new(
+        error.kind(),
+        format!("{}{}{}", path.display(), separator, error),
+    )
+}
@@ -0,0 +1,288 @@
+use crate::code_writer::GENERATED_LABEL_KINDS;
+use crate::parser::{Command, SourceCommand};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// Function the bootstrap code calls into
+const ENTRY_FUNCTION: &str = "Sys.init";
+
+struct Site<'a> {
+    vm_file_path: &'a Path,
+    line_no: usize,
+}
+
+struct CallSite<'a> {
+    site: Site<'a>,
+    args_count: i16,
+}
+
+struct Symbols<'a> {
+    definitions: HashMap<&'a str, Vec<Site<'a>>>,
+    calls: HashMap<&'a str, Vec<CallSite<'a>>>,
+}
+
+impl<'a> Symbols<'a> {
+    fn collect(programs: &'a [(PathBuf, Vec<SourceCommand>)]) -> Self {
+        let mut definitions: HashMap<&str, Vec<Site>> = HashMap::new();
+        let mut calls: HashMap<&str, Vec<CallSite>> = HashMap::new();
+        for (vm_file_path, commands) in programs {
+            for source_command in commands {
+                let site = Site {
+                    vm_file_path,
+                    line_no: source_command.line_no,
+                };
+                match &source_command.command {
+                    Command::Function { name, .. } => {
+                        definitions.entry(name).or_default().push(site);
+                    }
+                    Command::Call { name, args_count } => {
+                        calls.entry(name).or_default().push(CallSite {
+                            site,
+                            args_count: *args_count,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Symbols { definitions, calls }
+    }
+
+    fn undefined_functions(&self) -> HashSet<&'a str> {
+        self.calls
+            .keys()
+            .filter(|name| !self.definitions.contains_key(*name))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<'a> Site<'a> {
+    fn location(&self) -> String {
+        format!("{}:{}", self.vm_file_path.display(), self.line_no)
+    }
+}
+
+// Reports calls to undefined functions, functions defined more than once, functions
+// called with different argument counts and names that can't be used as labels
+pub fn check(programs: &[(PathBuf, Vec<SourceCommand>)]) -> Vec<String> {
+    let symbols = Symbols::collect(programs);
+    let mut errors = vec![];
+
+    for (vm_file_path, commands) in programs {
+        for source_command in commands {
+            let error = match &source_command.command {
+                Command::Label { label } | Command::Goto { label } | Command::IfGoto { label } => {
+                    label_error(label)
+                }
+                Command::Function { name, .. } | Command::Call { name, .. } => {
+                    if is_symbol(name) {
+                        None
+                    } else {
+                        Some(format!("function name {} is not a legal Hack symbol", name))
+                    }
+                }
+                _ => None,
+            };
+            if let Some(error) = error {
+                errors.push(format!(
+                    "{}:{}: {}",
+                    vm_file_path.display(),
+                    source_command.line_no,
+                    error
+                ));
+            }
+        }
+    }
+
+    let mut undefined: Vec<&str> = symbols.undefined_functions().into_iter().collect();
+    undefined.sort_unstable();
+    for name in undefined {
+        for call_site in symbols.calls[name].iter() {
+            errors.push(format!(
+                "{}: call to undefined function {}",
+                call_site.site.location(),
+                name
+            ));
+        }
+    }
+
+    let mut names: Vec<&&str> = symbols.definitions.keys().collect();
+    names.sort_unstable();
+    for name in names {
+        let sites = &symbols.definitions[name];
+        for site in sites.iter().skip(1) {
+            errors.push(format!(
+                "{}: function {} is already defined at {}",
+                site.location(),
+                name,
+                sites[0].location()
+            ));
+        }
+    }
+
+    let mut names: Vec<&&str> = symbols.calls.keys().collect();
+    names.sort_unstable();
+    for name in names {
+        let call_sites = &symbols.calls[name];
+        let first = &call_sites[0];
+        for call_site in call_sites
+            .iter()
+            .filter(|c| c.args_count != first.args_count)
+        {
+            errors.push(format!(
+                "{}: function {} is called with {} arguments, but with {} at {}",
+                call_site.site.location(),
+                name,
+                call_site.args_count,
+                first.args_count,
+                first.site.location()
+            ));
+        }
+    }
+    errors
+}
+
+// Labels end up as {function}${label} in the asm code, alongside the generated
+// {function}${kind}.{n} labels. So `$` is left out of them, and the generated forms are
+// reserved.
+fn label_error(label: &str) -> Option<String> {
+    if !is_symbol(label) {
+        return Some(format!("label {} is not a legal Hack symbol", label));
+    }
+    if label.contains('$') {
+        return Some(format!("label {} contains '$', which scopes labels", label));
+    }
+    let mut parts = label.splitn(2, '.');
+    let kind = parts.next().unwrap();
+    let number = parts.next().unwrap_or("");
+    if GENERATED_LABEL_KINDS.contains(&kind)
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+    {
+        return Some(format!("label {} is reserved for generated labels", label));
+    }
+    None
+}
+
+// Letters, digits, '_', '.', '$' and ':', not starting with a digit
+fn is_symbol(name: &str) -> bool {
+    match name.chars().next() {
+        Some(first) if !first.is_ascii_digit() => name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c)),
+        _ => false,
+    }
+}
+
+pub fn defines(programs: &[(PathBuf, Vec<SourceCommand>)], name: &str) -> bool {
+    Symbols::collect(programs).definitions.contains_key(name)
+}
+
+// Moves the OS files that define functions called but not defined by `programs` (or
+// `required` by the generated code) over to `programs`, along with whatever those files
+// need in turn
+pub fn include_os_functions(
+    programs: &mut Vec<(PathBuf, Vec<SourceCommand>)>,
+    mut os_programs: Vec<(PathBuf, Vec<SourceCommand>)>,
+    required: &[&str],
+) {
+    loop {
+        let symbols = Symbols::collect(programs);
+        let mut wanted = symbols.undefined_functions();
+        for &name in [ENTRY_FUNCTION].iter().chain(required) {
+            if !symbols.definitions.contains_key(name) {
+                wanted.insert(name);
+            }
+        }
+        let position = os_programs.iter().position(|(_, commands)| {
+            commands
+                .iter()
+                .any(|source_command| match &source_command.command {
+                    Command::Function { name, .. } => wanted.contains(name.as_str()),
+                    _ => false,
+                })
+        });
+        match position {
+            Some(position) => {
+                let os_program = os_programs.remove(position);
+                programs.push(os_program);
+            }
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn program(file_name: &str, vm_code: &str) -> (PathBuf, Vec<SourceCommand>) {
+        let commands = parser::commands(vm_code.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        (PathBuf::from(file_name), commands)
+    }
+
+    #[test]
+    fn accepts_consistent_programs() {
+        let programs = vec![
+            program("Sys.vm", "function Sys.init 0\ncall Main.main 1\nreturn"),
+            program("Main.vm", "function Main.main 0\nlabel LOOP\ngoto LOOP"),
+        ];
+        assert!(check(&programs).is_empty());
+    }
+
+    #[test]
+    fn reports_undefined_functions() {
+        let programs = vec![program(
+            "Main.vm",
+            "function Main.main 0\ncall Main.missing 0\nreturn",
+        )];
+        assert_eq!(
+            check(&programs),
+            ["Main.vm:2: call to undefined function Main.missing"]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_functions() {
+        let programs = vec![
+            program("A.vm", "function Main.main 0\nreturn"),
+            program("B.vm", "\nfunction Main.main 0\nreturn"),
+        ];
+        assert_eq!(
+            check(&programs),
+            ["B.vm:2: function Main.main is already defined at A.vm:1"]
+        );
+    }
+
+    #[test]
+    fn reports_inconsistent_argument_counts() {
+        let programs = vec![program(
+            "Main.vm",
+            "function Main.f 0\nreturn\nfunction Main.main 0\ncall Main.f 1\ncall Main.f 2\nreturn",
+        )];
+        assert_eq!(
+            check(&programs),
+            ["Main.vm:5: function Main.f is called with 2 arguments, but with 1 at Main.vm:4"]
+        );
+    }
+
+    #[test]
+    fn reports_illegal_and_reserved_labels() {
+        let programs = vec![program(
+            "Main.vm",
+            "function Main.main 0\nlabel 1ST\ngoto A$B\nif-goto cmp.3\nlabel cmp.x\nreturn",
+        )];
+        assert_eq!(
+            check(&programs),
+            [
+                "Main.vm:2: label 1ST is not a legal Hack symbol",
+                "Main.vm:3: label A$B contains '$', which scopes labels",
+                "Main.vm:4: label cmp.3 is reserved for generated labels",
+            ]
+        );
+    }
+}
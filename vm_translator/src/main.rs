@@ -1,59 +1,296 @@
-#[macro_use]
-extern crate lazy_static;
-extern crate asm_macro;
+extern crate vm_translator;
 
-mod code_writer;
-mod parser;
-
-use code_writer::CodeWriter;
 use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use vm_translator::backend::{Backend, Target};
+use vm_translator::c_writer::CWriter;
+use vm_translator::code_writer::Options;
+use vm_translator::wat_writer::WatWriter;
+use vm_translator::x86_64_writer::X86_64Writer;
+use vm_translator::{call_stack, linker, source_map, statics};
+
+struct Config {
+    options: Options,
+    optimize: bool,
+    write_source_map: bool,
+    write_static_report: bool,
+    target: Target,
+    asm_file_path: Option<PathBuf>,
+    os_dir: Option<PathBuf>,
+    input_paths: Vec<PathBuf>,
+}
 
 fn main() -> io::Result<()> {
-    let args: Vec<OsString> = std::env::args_os().collect();
-    if args.len() != 2 {
-        writeln!(io::stderr(), "Usage: vm_translator <vm code path>")?;
-    }
-    let vm_file_path = Path::new(&args[1]);
-    let meta = fs::metadata(vm_file_path).unwrap();
-    let vm_files = if meta.is_dir() {
-        fs::read_dir(vm_file_path)
-            .unwrap()
-            .filter_map(Result::ok)
-            .filter(|entry| {
-                if let Some(ext) = entry.path().extension() {
-                    ext == "vm"
-                } else {
-                    false
-                }
-            })
-            .map(|entry| (entry.path(), File::open(entry.path()).unwrap()))
-            .collect()
-    } else {
-        vec![(vm_file_path.to_owned(), File::open(vm_file_path).unwrap())]
+    let mut config = Config {
+        options: Options::default(),
+        optimize: false,
+        write_source_map: false,
+        write_static_report: false,
+        target: Target::Hack,
+        asm_file_path: None,
+        os_dir: None,
+        input_paths: vec![],
     };
+    let mut watch_mode = false;
+    let options = &mut config.options;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-O") | Some("--optimize") => config.optimize = true,
+            Some("--annotate") => options.annotate = true,
+            Some("--extended") => options.extended = true,
+            Some("--checked") => options.checked = true,
+            Some("--trace") => options.trace = true,
+            Some("--stack-limit") => {
+                options.stack_limit = args
+                    .next()
+                    .and_then(|arg| arg.to_str().and_then(|arg| arg.parse().ok()))
+                    .filter(|&limit| limit > 256)
+                    .unwrap_or_else(|| usage())
+            }
+            Some("--overflow-handler") => {
+                options.overflow_handler = args
+                    .next()
+                    .and_then(|arg| arg.into_string().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            Some("--source-map") => config.write_source_map = true,
+            Some("--static-report") => config.write_static_report = true,
+            Some("--target") => {
+                config.target = args
+                    .next()
+                    .and_then(|arg| arg.to_str().and_then(Target::from_name))
+                    .unwrap_or_else(|| usage())
+            }
+            Some("--watch") => watch_mode = true,
+            Some("-o") => {
+                config.asm_file_path = Some(args.next().unwrap_or_else(|| usage()).into())
+            }
+            Some("--os-dir") => config.os_dir = Some(args.next().unwrap_or_else(|| usage()).into()),
+            Some(flag) if flag.starts_with('-') => usage(),
+            _ => config.input_paths.push(arg.into()),
+        }
+    }
+    if config.input_paths.is_empty() {
+        usage();
+    }
+    // These rely on the layout of the Hack code
+    if config.target != Target::Hack
+        && (config.write_source_map || config.options.trace || config.options.checked)
+    {
+        eprintln!("error: --source-map, --trace and --checked are only supported for hack");
+        std::process::exit(1);
+    }
 
-    let asm_file_path = if meta.is_dir() {
-        let mut name = vm_file_path.file_name().unwrap().to_owned();
-        name.push(".asm");
-        vm_file_path.to_owned().join(name)
-    } else {
-        let mut name = vm_file_path.file_stem().unwrap().to_owned();
-        name.push(".asm");
-        vm_file_path.parent().unwrap().join(name)
+    if watch_mode {
+        return watch(&config);
+    }
+    if !translate(&config)? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Translates the input files, reporting errors in the VM code. Returns whether there were none.
+fn translate(config: &Config) -> io::Result<bool> {
+    let options = &config.options;
+    let asm_file_path = match &config.asm_file_path {
+        Some(asm_file_path) => asm_file_path.clone(),
+        None => default_asm_file_path(&config.input_paths[0], config.target.file_extension())?,
     };
 
-    let asm_file = File::create(asm_file_path).unwrap();
-    let mut writer = CodeWriter::new(asm_file);
+    let mut programs = vm_translator::parse_vm_files(vm_file_paths(&config.input_paths)?)?;
+    if let Some(os_dir) = &config.os_dir {
+        let os_programs = vm_translator::parse_vm_files(vm_translator::vm_file_paths_in(os_dir)?)?;
+        let mut required = vec![];
+        if options.checked {
+            required.push(options.overflow_handler.as_str());
+        }
+        linker::include_os_functions(&mut programs, os_programs, &required);
+    }
 
-    for (vm_file_path, vm_file) in vm_files {
+    for (vm_file_path, _) in programs.iter() {
         println!("Translating {:?}...", vm_file_path);
-        let commands = parser::commands(vm_file);
-        writer.write_code(vm_file_path, commands);
     }
-    Ok(())
+    let mut asm = vec![];
+    let optimize = config.optimize;
+    let translation = match config.target {
+        Target::Hack => {
+            vm_translator::translate_programs(programs, optimize, options.clone(), &mut asm).map(
+                |writer| {
+                    let function_names = writer.function_names().to_vec();
+                    let statics = writer.statics().clone();
+                    (writer.into_source_map(), function_names, statics)
+                },
+            )
+        }
+        Target::C => {
+            let writer = CWriter::new(&mut asm, options.clone());
+            vm_translator::translate_programs_to(programs, optimize, options, writer)
+                .map(|writer| (vec![], vec![], writer.statics().clone()))
+        }
+        Target::Wat => {
+            let writer = WatWriter::new(&mut asm, options.clone());
+            vm_translator::translate_programs_to(programs, optimize, options, writer)
+                .map(|writer| (vec![], vec![], writer.statics().clone()))
+        }
+        Target::X86_64 => {
+            let writer = X86_64Writer::new(&mut asm, options.clone());
+            vm_translator::translate_programs_to(programs, optimize, options, writer)
+                .map(|writer| (vec![], vec![], writer.statics().clone()))
+        }
+    };
+    let (source_map, function_names, static_allocation) = match translation {
+        Ok(translation) => translation,
+        Err(error) => {
+            for message in error.messages {
+                eprintln!("error: {}", message);
+            }
+            return Ok(false);
+        }
+    };
+    if let Some(warning) = static_allocation.overflow_warning() {
+        eprintln!("warning: {}", warning);
+    }
+
+    fs::write(&asm_file_path, asm)?;
+
+    if config.write_source_map {
+        let map_file = File::create(asm_file_path.with_extension("map"))?;
+        source_map::write_source_map(map_file, &source_map)?;
+    }
+    if config.write_static_report {
+        let report_file = File::create(asm_file_path.with_extension("statics"))?;
+        statics::write_static_report(report_file, &static_allocation)?;
+    }
+    if options.trace {
+        let table_file = File::create(asm_file_path.with_extension("functions"))?;
+        call_stack::write_function_table(table_file, &function_names)?;
+    }
+    Ok(true)
+}
+
+// Files within a directory are sorted so that the output (including the generated labels)
+// doesn't depend on the file system
+fn vm_file_paths(input_paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut vm_file_paths: Vec<PathBuf> = vec![];
+    for input_path in input_paths.iter() {
+        for vm_file_path in vm_translator::vm_file_paths_in(input_path)? {
+            if !vm_file_paths.contains(&vm_file_path) {
+                vm_file_paths.push(vm_file_path);
+            }
+        }
+    }
+    Ok(vm_file_paths)
+}
+
+// Translates, then again whenever the .vm files of the inputs or the OS directory change,
+// until killed. Errors are reported without exiting.
+#[cfg(target_os = "linux")]
+fn watch(config: &Config) -> io::Result<()> {
+    use inotify::{Inotify, WatchMask};
+
+    let mut inotify = Inotify::init()?;
+    let mask = WatchMask::CLOSE_WRITE
+        | WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO;
+    let mut watched_paths = config.input_paths.clone();
+    watched_paths.extend(config.os_dir.clone());
+    for path in watched_paths.iter() {
+        // Files are watched through their directory, as editors often replace them
+        if fs::metadata(path)?.is_dir() {
+            inotify.add_watch(path, mask)?;
+        } else {
+            let parent = path.parent().filter(|parent| parent != &Path::new(""));
+            inotify.add_watch(parent.unwrap_or_else(|| Path::new(".")), mask)?;
+        }
+    }
+
+    let mut buffer = [0; 4096];
+    let mut translated: Option<Vec<(PathBuf, Vec<u8>)>> = None;
+    loop {
+        // Only translates when the contents of the files have changed
+        match read_vm_files(&watched_paths) {
+            Ok(vm_files) if translated.as_ref() == Some(&vm_files) => {}
+            Ok(vm_files) => {
+                if let Err(error) = translate(config) {
+                    eprintln!("error: {}", error);
+                }
+                translated = Some(vm_files);
+                println!("Watching for changes...");
+            }
+            Err(error) => eprintln!("error: {}", error),
+        }
+
+        let mut vm_file_changed = false;
+        while !vm_file_changed {
+            let events = inotify.read_events_blocking(&mut buffer)?;
+            vm_file_changed = events.into_iter().any(|event| {
+                event
+                    .name
+                    .is_some_and(|name| Path::new(name).extension() == Some("vm".as_ref()))
+            });
+        }
+        // Lets the rest of the changes, such as those to the other files written by the
+        // compiler, happen before translating
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        while let Ok(events) = inotify.read_events(&mut buffer) {
+            if events.count() == 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(_config: &Config) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "--watch is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn read_vm_files(paths: &[PathBuf]) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut vm_files = vec![];
+    for vm_file_path in vm_file_paths(paths)? {
+        let contents = fs::read(&vm_file_path)?;
+        vm_files.push((vm_file_path, contents));
+    }
+    Ok(vm_files)
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: vm_translator [-O|--optimize] [--annotate] [--extended] [--source-map] \
+         [--static-report] [--trace] [--checked [--stack-limit <address>] [--overflow-handler <function>]] \
+         [--target <hack|c|wat|x86-64>] [-o <output file>] [--os-dir <dir>] [--watch] \
+         <vm file or directory>...\n\n\
+         --extended translates mul, div, mod, shl, shr, inc, dec, le, ge and ne. div and mod by \
+         zero give 0, where Math.divide calls Sys.error 3."
+    );
+    std::process::exit(1);
+}
+
+// Foo/Foo.asm for a directory Foo, Foo.asm next to the file for Foo.vm (or with the
+// extension of another target)
+fn default_asm_file_path(input_path: &Path, extension: &str) -> io::Result<PathBuf> {
+    let input_path = input_path.canonicalize()?;
+    let asm_file_path = if fs::metadata(&input_path)?.is_dir() {
+        let mut name = input_path.file_name().unwrap().to_owned();
+        name.push(".");
+        name.push(extension);
+        input_path.join(name)
+    } else {
+        let mut name: OsString = input_path.file_stem().unwrap().to_owned();
+        name.push(".");
+        name.push(extension);
+        input_path.parent().unwrap().join(name)
+    };
+    Ok(asm_file_path)
 }
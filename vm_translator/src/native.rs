@@ -0,0 +1,115 @@
+use crate::parser::Command;
+use crate::statics::StaticAllocation;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Bookkeeping shared by the backends that don't go through Hack assembly. VM labels and
+// functions are numbered instead of named, return addresses are numbered call sites, and
+// statics get the addresses the Hack assembler would give them.
+
+pub const ENTRY_FUNCTION: &str = "Sys.init";
+// Calls to this function halt the program, rather than loop forever
+pub const HALT_FUNCTION: &str = "Sys.halt";
+pub const STACK_BASE: i16 = 256;
+pub const SCREEN: i16 = 16384;
+pub const KBD: i16 = 24576;
+pub const SCREEN_WIDTH: usize = 512;
+pub const SCREEN_HEIGHT: usize = 256;
+// Keyboard code of the newline key
+pub const NEW_LINE: u8 = 128;
+
+#[derive(Default)]
+pub struct Symbols {
+    label_ids: HashMap<String, usize>,
+    return_sites: Vec<usize>,
+    statics: StaticAllocation,
+    vm_file_stem: String,
+    fn_name: Option<String>,
+    // Labels defined since the last command other than a label
+    preceding_labels: Vec<usize>,
+}
+
+impl Symbols {
+    pub fn start_file(&mut self, vm_file_path: &Path) {
+        self.vm_file_stem = vm_file_path
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        self.fn_name = None;
+        self.preceding_labels.clear();
+    }
+
+    // Must be called after translating each command
+    pub fn track(&mut self, command: &Command) {
+        match command {
+            Command::Label { label } => {
+                let id = self.label_id(label);
+                self.preceding_labels.push(id);
+            }
+            Command::Function { name, .. } => {
+                self.fn_name = Some(name.clone());
+                self.preceding_labels.clear();
+            }
+            _ => self.preceding_labels.clear(),
+        }
+    }
+
+    // Id of a label of the current function (or file, outside of functions)
+    pub fn label_id(&mut self, label: &str) -> usize {
+        let scope = self.fn_name.as_ref().unwrap_or(&self.vm_file_stem);
+        let name = format!("{}${}", scope, label);
+        self.id(name)
+    }
+
+    pub fn function_id(&mut self, name: &str) -> usize {
+        self.id(name.to_owned())
+    }
+
+    fn id(&mut self, name: String) -> usize {
+        let next_id = self.label_ids.len();
+        *self.label_ids.entry(name).or_insert(next_id)
+    }
+
+    // Id of the bootstrap code, for backends that can only jump to numbered code
+    pub fn entry_id(&mut self) -> usize {
+        self.id("bootstrap code".to_owned())
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.label_ids.len()
+    }
+
+    // Call sites are numbered along with the labels, the number being pushed as the
+    // return address. The names given to them can't clash with VM names.
+    pub fn next_return_site(&mut self) -> usize {
+        let id = self.id(format!("return {}", self.return_sites.len()));
+        self.return_sites.push(id);
+        id
+    }
+
+    pub fn return_sites(&self) -> &[usize] {
+        &self.return_sites
+    }
+
+    pub fn static_address(&mut self, index: i16) -> i16 {
+        self.statics.address(&self.vm_file_stem, index)
+    }
+
+    pub fn statics(&self) -> &StaticAllocation {
+        &self.statics
+    }
+
+    // Whether a `goto` to this label is a loop doing nothing, by which programs halt
+    pub fn is_halt_loop(&self, label_id: usize) -> bool {
+        self.preceding_labels.contains(&label_id)
+    }
+}
+
+// The byte with its bits in reverse order, as pixels are ordered from the least
+// significant bit in Hack words, but from the most significant bit in PBM images
+pub fn reverse_bits(byte: u8) -> u8 {
+    (0..8).fold(0, |reversed, bit| {
+        reversed | ((byte >> bit) & 1) << (7 - bit)
+    })
+}
@@ -0,0 +1,363 @@
+use crate::parser::{Command, Operation, Segment, SourceCommand};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+// Function the bootstrap code calls into. Everything else must be reachable from here,
+// or from the `required` functions the generated code calls.
+const ENTRY_FUNCTION: &str = "Sys.init";
+
+pub fn optimize(programs: &mut [(PathBuf, Vec<SourceCommand>)], required: &[&str]) {
+    for (_, commands) in programs.iter_mut() {
+        let folded = fold_constants(std::mem::take(commands));
+        *commands = remove_dead_code(folded);
+    }
+    remove_unused_functions(programs, required);
+}
+
+// Replaces arithmetic on constants with the computed constant,
+// e.g. `push constant 3, push constant 4, add` becomes `push constant 7`
+fn fold_constants(commands: Vec<SourceCommand>) -> Vec<SourceCommand> {
+    let mut folded: Vec<SourceCommand> = vec![];
+    for source_command in commands {
+        if let Command::Alu(operation) = source_command.command {
+            if let Some(value) = fold_operation(&mut folded, operation) {
+                let line_no = source_command.line_no;
+                folded.extend(
+                    constant_commands(value)
+                        .into_iter()
+                        .map(|command| SourceCommand { line_no, command }),
+                );
+                continue;
+            }
+        }
+        folded.push(source_command);
+    }
+    folded
+}
+
+// Removes the operands of `operation` from the end of `folded` and returns the result,
+// if all the operands are constants
+fn fold_operation(folded: &mut Vec<SourceCommand>, operation: Operation) -> Option<i16> {
+    let (y, y_len) = trailing_constant(folded, 0)?;
+    let (value, operands_len) = match operation {
+        Operation::Neg => (y.wrapping_neg(), y_len),
+        Operation::Not => (!y, y_len),
+        Operation::Inc => (y.wrapping_add(1), y_len),
+        Operation::Dec => (y.wrapping_sub(1), y_len),
+        _ => {
+            let (x, x_len) = trailing_constant(folded, y_len)?;
+            let value = match operation {
+                Operation::Add => x.wrapping_add(y),
+                Operation::Sub => x.wrapping_sub(y),
+                Operation::And => x & y,
+                Operation::Or => x | y,
+                Operation::Eq => bool_value(x == y),
+                Operation::Gt => bool_value(x > y),
+                Operation::Lt => bool_value(x < y),
+                Operation::Le => bool_value(x <= y),
+                Operation::Ge => bool_value(x >= y),
+                Operation::Ne => bool_value(x != y),
+                Operation::Mul => x.wrapping_mul(y),
+                // Dividing by zero gives 0, as in the translated code
+                Operation::Div if y == 0 => 0,
+                Operation::Div => x.wrapping_div(y),
+                Operation::Mod if y == 0 => 0,
+                Operation::Mod => x.wrapping_rem(y),
+                Operation::Shl => shift(x, y, |x, y| ((x as u16) << y) as i16),
+                Operation::Shr => shift(x, y, |x, y| ((x as u16) >> y) as i16),
+                _ => panic!("Unexpected binary operation {:?}", operation),
+            };
+            (value, x_len + y_len)
+        }
+    };
+    folded.truncate(folded.len() - operands_len);
+    Some(value)
+}
+
+// Value and length of the constant expression ending `skip` commands before the end
+fn trailing_constant(commands: &[SourceCommand], skip: usize) -> Option<(i16, usize)> {
+    let end = commands.len().checked_sub(skip)?;
+    let commands: Vec<&Command> = commands[end.saturating_sub(2)..end]
+        .iter()
+        .map(|source_command| &source_command.command)
+        .collect();
+    match commands.as_slice() {
+        [.., Command::Push {
+            segment: Segment::Constant,
+            index,
+        }, Command::Alu(Operation::Neg)] => Some((index.wrapping_neg(), 2)),
+        [.., Command::Push {
+            segment: Segment::Constant,
+            index,
+        }, Command::Alu(Operation::Not)] => Some((!index, 2)),
+        [.., Command::Push {
+            segment: Segment::Constant,
+            index,
+        }] => Some((*index, 1)),
+        _ => None,
+    }
+}
+
+// Shifting by a negative count or by 16 bits or more gives 0, as in the translated code
+fn shift(x: i16, y: i16, shift: fn(i16, i16) -> i16) -> i16 {
+    if (0..16).contains(&y) {
+        shift(x, y)
+    } else {
+        0
+    }
+}
+
+fn bool_value(value: bool) -> i16 {
+    if value {
+        -1
+    } else {
+        0
+    }
+}
+
+// `push constant` only takes non-negative values, so negative ones need a trailing `neg` or `not`
+fn constant_commands(value: i16) -> Vec<Command> {
+    let push = |index| Command::Push {
+        segment: Segment::Constant,
+        index,
+    };
+    if value >= 0 {
+        vec![push(value)]
+    } else if value == i16::MIN {
+        vec![push(i16::MAX), Command::Alu(Operation::Not)]
+    } else {
+        vec![push(-value), Command::Alu(Operation::Neg)]
+    }
+}
+
+// Drops commands following a `goto` or `return` that no label makes reachable again
+fn remove_dead_code(commands: Vec<SourceCommand>) -> Vec<SourceCommand> {
+    let mut live = vec![];
+    let mut reachable = true;
+    for source_command in commands {
+        if let Command::Label { .. } | Command::Function { .. } = source_command.command {
+            reachable = true;
+        }
+        let ends_block = matches!(
+            source_command.command,
+            Command::Goto { .. } | Command::Return
+        );
+        if reachable {
+            live.push(source_command);
+        }
+        if ends_block {
+            reachable = false;
+        }
+    }
+    live
+}
+
+// Drops functions that cannot be called starting from Sys.init.
+// Programs without a Sys.init (e.g. the project 07 tests) are left untouched.
+fn remove_unused_functions(programs: &mut [(PathBuf, Vec<SourceCommand>)], required: &[&str]) {
+    let mut callees: HashMap<String, Vec<String>> = HashMap::new();
+    let mut pending = vec![ENTRY_FUNCTION.to_owned()];
+    pending.extend(required.iter().map(|name| name.to_string()));
+    for (_, commands) in programs.iter() {
+        let mut current_fn_name: Option<&String> = None;
+        for source_command in commands {
+            match &source_command.command {
+                Command::Function { name, .. } => {
+                    callees.entry(name.clone()).or_default();
+                    current_fn_name = Some(name);
+                }
+                Command::Call { name, .. } => match current_fn_name {
+                    Some(fn_name) => callees.get_mut(fn_name).unwrap().push(name.clone()),
+                    // Code outside of any function is always kept, and so are its callees
+                    None => pending.push(name.clone()),
+                },
+                _ => {}
+            }
+        }
+    }
+    if !callees.contains_key(ENTRY_FUNCTION) {
+        return;
+    }
+
+    let mut used = HashSet::new();
+    while let Some(name) = pending.pop() {
+        if let Some(fn_callees) = callees.get(&name) {
+            if used.insert(name) {
+                pending.extend(fn_callees.iter().cloned());
+            }
+        }
+    }
+
+    for (_, commands) in programs.iter_mut() {
+        let mut keep = true;
+        commands.retain(|source_command| {
+            if let Command::Function { name, .. } = &source_command.command {
+                keep = used.contains(name);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn parse(vm_code: &str) -> Vec<SourceCommand> {
+        parser::commands(vm_code.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn lines(commands: &[SourceCommand]) -> Vec<String> {
+        commands
+            .iter()
+            .map(|source_command| source_command.command.to_string())
+            .collect()
+    }
+
+    fn folded(vm_code: &str) -> Vec<String> {
+        lines(&fold_constants(parse(vm_code)))
+    }
+
+    #[test]
+    fn folds_binary_operations() {
+        assert_eq!(
+            folded("push constant 3\npush constant 4\nadd"),
+            ["push constant 7"]
+        );
+        assert_eq!(
+            folded("push constant 2\npush constant 3\nsub\npush constant 6\nand"),
+            ["push constant 6"]
+        );
+        assert_eq!(
+            folded("push constant 2\npush constant 3\nlt"),
+            ["push constant 1", "neg"]
+        );
+        assert_eq!(
+            folded("push constant 2\npush constant 3\ngt"),
+            ["push constant 0"]
+        );
+    }
+
+    #[test]
+    fn folding_wraps_around() {
+        assert_eq!(
+            folded("push constant 32767\npush constant 1\nadd"),
+            ["push constant 32767", "not"]
+        );
+        assert_eq!(
+            folded("push constant 32767\nnot\npush constant 1\nsub"),
+            ["push constant 32767"]
+        );
+        assert_eq!(
+            folded("push constant 32767\nnot\nneg"),
+            ["push constant 32767", "not"]
+        );
+    }
+
+    #[test]
+    fn folds_neg_and_not_chains() {
+        assert_eq!(folded("push constant 5\nneg\nneg"), ["push constant 5"]);
+        assert_eq!(folded("push constant 1\nnot\nnot"), ["push constant 1"]);
+        assert_eq!(
+            folded("push constant 5\nneg\nnot\nneg"),
+            ["push constant 4", "neg"]
+        );
+        assert_eq!(
+            folded("push constant 0\nnot\npush constant 1\nadd"),
+            ["push constant 0"]
+        );
+    }
+
+    #[test]
+    fn keeps_operations_on_variables() {
+        let vm_code =
+            "push local 0\npush constant 1\nadd\npush constant 2\nneg\npush argument 1\nsub";
+        assert_eq!(
+            folded(vm_code),
+            [
+                "push local 0",
+                "push constant 1",
+                "add",
+                "push constant 2",
+                "neg",
+                "push argument 1",
+                "sub"
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_code_after_goto_and_return_up_to_label_or_function() {
+        let vm_code = "function f 0\npush constant 0\nreturn\npush constant 1\nlabel L\n\
+                       push constant 2\ngoto L\npop temp 0\nfunction g 0\nreturn";
+        assert_eq!(
+            lines(&remove_dead_code(parse(vm_code))),
+            [
+                "function f 0",
+                "push constant 0",
+                "return",
+                "label L",
+                "push constant 2",
+                "goto L",
+                "function g 0",
+                "return"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_if_goto_fall_through() {
+        let vm_code = "function f 0\npush constant 0\nif-goto L\npush constant 1\nreturn";
+        assert_eq!(
+            lines(&remove_dead_code(parse(vm_code))),
+            lines(&parse(vm_code))
+        );
+    }
+
+    #[test]
+    fn removes_functions_unreachable_from_sys_init() {
+        let mut programs = vec![
+            (
+                PathBuf::from("Sys.vm"),
+                parse("function Sys.init 0\ncall Main.main 0\nreturn"),
+            ),
+            (
+                PathBuf::from("Main.vm"),
+                parse(
+                    "function Main.main 0\ncall Main.helper 0\nreturn\n\
+                     function Main.helper 0\nreturn\nfunction Main.unused 0\ncall Math.abs 1\nreturn",
+                ),
+            ),
+            (
+                PathBuf::from("Math.vm"),
+                parse("function Math.abs 0\nreturn\nfunction Math.multiply 0\nreturn"),
+            ),
+        ];
+        remove_unused_functions(&mut programs, &["Math.multiply"]);
+        let functions: Vec<String> = programs
+            .iter()
+            .flat_map(|(_, commands)| lines(commands))
+            .filter(|line| line.starts_with("function"))
+            .collect();
+        assert_eq!(
+            functions,
+            [
+                "function Sys.init 0",
+                "function Main.main 0",
+                "function Main.helper 0",
+                "function Math.multiply 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_all_functions_without_sys_init() {
+        let commands = parse("function Main.main 0\nreturn\nfunction Main.unused 0\nreturn");
+        let mut programs = vec![(PathBuf::from("Main.vm"), commands.clone())];
+        remove_unused_functions(&mut programs, &[]);
+        assert_eq!(programs[0].1, commands);
+    }
+}
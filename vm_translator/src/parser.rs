@@ -1,16 +1,17 @@
 use std::collections::HashMap;
-use std::fs::File;
+use std::fmt;
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 
-pub type CommandResult = Result<Command, io::Error>;
-pub type CommandIter = Box<dyn Iterator<Item = CommandResult>>;
+pub type CommandResult = Result<SourceCommand, io::Error>;
+pub type CommandIter<'a> = Box<dyn Iterator<Item = CommandResult> + 'a>;
 
-pub fn commands(vm_file: File) -> CommandIter {
-    let iter = BufReader::new(vm_file)
+pub fn commands<'a, R: Read + 'a>(vm_code: R) -> CommandIter<'a> {
+    let iter = BufReader::new(vm_code)
         .lines()
+        .zip(1..)
         // Filter-out comment lines and blank lines
-        .filter(|line_result| {
+        .filter(|(line_result, _)| {
             if let Ok(line) = line_result {
                 !(line.starts_with("//") || line.trim().is_empty())
             } else {
@@ -18,18 +19,17 @@ pub fn commands(vm_file: File) -> CommandIter {
             }
         })
         //  Remove in-line comments and trim white spaces
-        .map(|line_result| {
-            if let Ok(line) = line_result {
-                Ok(line.split("//").nth(0).unwrap().trim().to_owned())
-            } else {
-                line_result
-            }
+        .map(|(line_result, line_no)| {
+            line_result.map(|line| (line.split("//").next().unwrap().trim().to_owned(), line_no))
         })
         .map(|line_result| {
-            if let Ok(line) = line_result {
-                Ok(Command::new(line))
-            } else {
-                Result::Err(line_result.unwrap_err())
+            let (line, line_no) = line_result?;
+            match Command::new(line.clone()) {
+                Some(command) => Ok(SourceCommand { line_no, command }),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: invalid command {}", line_no, line),
+                )),
             }
         });
     Box::new(iter)
@@ -47,6 +47,16 @@ lazy_static! {
             ("and".to_owned(), Operation::And),
             ("or".to_owned(), Operation::Or),
             ("not".to_owned(), Operation::Not),
+            ("mul".to_owned(), Operation::Mul),
+            ("div".to_owned(), Operation::Div),
+            ("mod".to_owned(), Operation::Mod),
+            ("shl".to_owned(), Operation::Shl),
+            ("shr".to_owned(), Operation::Shr),
+            ("inc".to_owned(), Operation::Inc),
+            ("dec".to_owned(), Operation::Dec),
+            ("le".to_owned(), Operation::Le),
+            ("ge".to_owned(), Operation::Ge),
+            ("ne".to_owned(), Operation::Ne),
         ]
         .iter()
         .cloned()
@@ -80,6 +90,34 @@ pub enum Operation {
     And,
     Or,
     Not,
+    // Extended instruction set, only translated when enabled
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    Inc,
+    Dec,
+    Le,
+    Ge,
+    Ne,
+}
+
+impl Operation {
+    pub fn is_extended(self) -> bool {
+        !matches!(
+            self,
+            Operation::Add
+                | Operation::Sub
+                | Operation::Neg
+                | Operation::Eq
+                | Operation::Gt
+                | Operation::Lt
+                | Operation::And
+                | Operation::Or
+                | Operation::Not
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -94,7 +132,14 @@ pub enum Segment {
     Temp,
 }
 
-#[derive(Debug)]
+// A command along with the (1-based) line of the .vm file it was parsed from
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceCommand {
+    pub line_no: usize,
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     Alu(Operation),
     Push { segment: Segment, index: i16 },
@@ -108,45 +153,188 @@ pub enum Command {
 }
 
 impl Command {
-    pub fn new(command: String) -> Self {
-        if command.starts_with("push") {
-            let mut tokens = command.split(" ");
-            let segment = SEGMENT_MAP[tokens.nth(1).unwrap()];
-            let index = tokens.nth(0).unwrap().parse::<i16>().unwrap();
-            Command::Push { segment, index }
-        } else if command.starts_with("pop") {
-            let mut tokens = command.split(" ");
-            let segment = SEGMENT_MAP[tokens.nth(1).unwrap()];
-            let index = tokens.nth(0).unwrap().parse::<i16>().unwrap();
-            Command::Pop { segment, index }
-        } else if command.starts_with("label") {
-            let mut tokens = command.split(" ");
-            let label = tokens.nth(1).unwrap().to_owned();
-            Command::Label { label }
-        } else if command.starts_with("goto") {
-            let mut tokens = command.split(" ");
-            let label = tokens.nth(1).unwrap().to_owned();
-            Command::Goto { label }
-        } else if command.starts_with("if-goto") {
-            let mut tokens = command.split(" ");
-            let label = tokens.nth(1).unwrap().to_owned();
-            Command::IfGoto { label }
-        } else if command.starts_with("function") {
-            let mut tokens = command.split(" ");
-            let name = tokens.nth(1).unwrap().to_owned();
-            let local_count = tokens.nth(0).unwrap().parse::<i16>().unwrap();
-            Command::Function { name, local_count }
-        } else if command.starts_with("call") {
-            let mut tokens = command.split(" ");
-            let name = tokens.nth(1).unwrap().to_owned();
-            let args_count = tokens.nth(0).unwrap().parse::<i16>().unwrap();
-            Command::Call { name, args_count }
-        } else if command.starts_with("return") {
-            Command::Return
-        } else if OPERATION_MAP.contains_key(&command) {
-            Command::Alu(OPERATION_MAP[&command])
-        } else {
-            panic!("Cannot parse {}", command);
+    // None if the line isn't a valid command
+    pub fn new(command: String) -> Option<Self> {
+        let mut tokens = command.split_whitespace();
+        let keyword = tokens.next()?;
+        let mut argument = || tokens.next();
+        let command = match keyword {
+            "push" => Command::Push {
+                segment: *SEGMENT_MAP.get(argument()?)?,
+                index: argument()?.parse::<i16>().ok()?,
+            },
+            "pop" => Command::Pop {
+                segment: match *SEGMENT_MAP.get(argument()?)? {
+                    Segment::Constant => return None,
+                    segment => segment,
+                },
+                index: argument()?.parse::<i16>().ok()?,
+            },
+            "label" => Command::Label {
+                label: argument()?.to_owned(),
+            },
+            "goto" => Command::Goto {
+                label: argument()?.to_owned(),
+            },
+            "if-goto" => Command::IfGoto {
+                label: argument()?.to_owned(),
+            },
+            "function" => Command::Function {
+                name: argument()?.to_owned(),
+                local_count: argument()?.parse::<i16>().ok()?,
+            },
+            "call" => Command::Call {
+                name: argument()?.to_owned(),
+                args_count: argument()?.parse::<i16>().ok()?,
+            },
+            "return" => Command::Return,
+            _ => Command::Alu(*OPERATION_MAP.get(keyword)?),
+        };
+        if argument().is_some() {
+            return None;
+        }
+        // temp and pointer are made of 8 and 2 registers
+        if let Command::Push { segment, index } | Command::Pop { segment, index } = &command {
+            let last_index = match segment {
+                Segment::Temp => 7,
+                Segment::Pointer => 1,
+                _ => i16::MAX,
+            };
+            if !(0..=last_index).contains(index) {
+                return None;
+            }
+        }
+        Some(command)
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Operation::Add => "add",
+            Operation::Sub => "sub",
+            Operation::Neg => "neg",
+            Operation::Eq => "eq",
+            Operation::Gt => "gt",
+            Operation::Lt => "lt",
+            Operation::And => "and",
+            Operation::Or => "or",
+            Operation::Not => "not",
+            Operation::Mul => "mul",
+            Operation::Div => "div",
+            Operation::Mod => "mod",
+            Operation::Shl => "shl",
+            Operation::Shr => "shr",
+            Operation::Inc => "inc",
+            Operation::Dec => "dec",
+            Operation::Le => "le",
+            Operation::Ge => "ge",
+            Operation::Ne => "ne",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::Constant => "constant",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Alu(operation) => write!(formatter, "{}", operation),
+            Command::Push { segment, index } => write!(formatter, "push {} {}", segment, index),
+            Command::Pop { segment, index } => write!(formatter, "pop {} {}", segment, index),
+            Command::Label { label } => write!(formatter, "label {}", label),
+            Command::Goto { label } => write!(formatter, "goto {}", label),
+            Command::IfGoto { label } => write!(formatter, "if-goto {}", label),
+            Command::Function { name, local_count } => {
+                write!(formatter, "function {} {}", name, local_count)
+            }
+            Command::Call { name, args_count } => write!(formatter, "call {} {}", name, args_count),
+            Command::Return => write!(formatter, "return"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(vm_code: &str) -> Result<Vec<Command>, String> {
+        commands(vm_code.as_bytes())
+            .map(|result| {
+                result
+                    .map(|source_command| source_command.command)
+                    .map_err(|error| error.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("// comment\npush constant 7 // seven\n\npop temp 7\nadd\ncall Main.f 2"),
+            Ok(vec![
+                Command::Push {
+                    segment: Segment::Constant,
+                    index: 7
+                },
+                Command::Pop {
+                    segment: Segment::Temp,
+                    index: 7
+                },
+                Command::Alu(Operation::Add),
+                Command::Call {
+                    name: "Main.f".to_owned(),
+                    args_count: 2
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_pop_to_constant() {
+        assert_eq!(
+            parse("push constant 1\npop constant 1"),
+            Err("2: invalid command pop constant 1".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_temp_and_pointer_indices_out_of_range() {
+        assert_eq!(
+            parse("push temp 9"),
+            Err("1: invalid command push temp 9".to_owned())
+        );
+        assert_eq!(
+            parse("pop temp -1"),
+            Err("1: invalid command pop temp -1".to_owned())
+        );
+        assert_eq!(
+            parse("push pointer 1\npop pointer 2"),
+            Err("2: invalid command pop pointer 2".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert_eq!(
+            parse("push local"),
+            Err("1: invalid command push local".to_owned())
+        );
+        assert_eq!(parse("add 1"), Err("1: invalid command add 1".to_owned()));
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+
+// Code generated for a single VM command
+#[derive(Debug)]
+pub struct SourceMapEntry {
+    // 1-based lines of the .asm file
+    pub asm_lines: Range<usize>,
+    // Instructions in the assembled program, i.e. the asm lines without comments and labels
+    pub rom_addresses: Range<usize>,
+    pub vm_file: String,
+    pub vm_line_no: usize,
+    pub fn_name: Option<String>,
+}
+
+// One tab-separated entry per line. Ranges are half-open, commands outside of any function
+// have "-" as their function name.
+pub fn write_source_map<W: Write>(out: W, entries: &[SourceMapEntry]) -> io::Result<()> {
+    let mut out = io::BufWriter::new(out);
+    writeln!(
+        out,
+        "# asm_start\tasm_end\trom_start\trom_end\tvm_file\tvm_line\tfunction"
+    )?;
+    for entry in entries {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            entry.asm_lines.start,
+            entry.asm_lines.end,
+            entry.rom_addresses.start,
+            entry.rom_addresses.end,
+            entry.vm_file,
+            entry.vm_line_no,
+            entry.fn_name.as_deref().unwrap_or("-")
+        )?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_writer::Options;
+    use crate::parser;
+    use std::path::PathBuf;
+
+    const MAIN_VM: &str = "\
+// Computes 3 + 4 - 2
+function Main.main 0
+push constant 3
+push constant 4
+call Main.double 2
+
+return
+function Main.double 0
+push argument 0
+push argument 1
+add
+push constant 2
+sub
+return
+";
+
+    // Translates Main.vm and returns the source map with the asm lines of the instructions,
+    // indexed by ROM address
+    fn translate(options: Options) -> (Vec<SourceMapEntry>, Vec<usize>) {
+        let commands = parser::commands(MAIN_VM.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let mut asm = vec![];
+        let source_map = crate::translate_programs(
+            vec![(PathBuf::from("Main.vm"), commands)],
+            false,
+            options,
+            &mut asm,
+        )
+        .unwrap()
+        .into_source_map();
+        let instruction_lines = String::from_utf8(asm)
+            .unwrap()
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| {
+                let line = line.trim();
+                !(line.is_empty() || line.starts_with("//") || line.starts_with('('))
+            })
+            .map(|(index, _)| index + 1)
+            .collect();
+        (source_map, instruction_lines)
+    }
+
+    fn vm_line_at(source_map: &[SourceMapEntry], rom_address: usize) -> Option<usize> {
+        source_map
+            .iter()
+            .find(|entry| entry.rom_addresses.contains(&rom_address))
+            .map(|entry| entry.vm_line_no)
+    }
+
+    #[test]
+    fn maps_rom_addresses_to_vm_lines() {
+        for annotate in &[false, true] {
+            let (source_map, instruction_lines) = translate(Options {
+                annotate: *annotate,
+                ..Options::default()
+            });
+            let vm_lines: Vec<usize> = source_map.iter().map(|entry| entry.vm_line_no).collect();
+            assert_eq!(vm_lines, [2, 3, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14]);
+
+            for entry in &source_map {
+                // The instructions of a command are the ones among its asm lines
+                let lines = &instruction_lines[entry.rom_addresses.clone()];
+                assert!(lines.iter().all(|line| entry.asm_lines.contains(line)));
+                assert_eq!(entry.vm_file, "Main.vm");
+            }
+            for pair in source_map.windows(2) {
+                assert_eq!(pair[0].rom_addresses.end, pair[1].rom_addresses.start);
+            }
+
+            // Declaring a function without locals only takes a label, so its first instruction
+            // is that of the command after the declaration
+            let (main, double) = (&source_map[0], &source_map[5]);
+            assert!(main.rom_addresses.is_empty() && double.rom_addresses.is_empty());
+            assert_eq!(double.fn_name.as_deref(), Some("Main.double"));
+            assert_eq!(vm_line_at(&source_map, main.rom_addresses.start), Some(3));
+            assert_eq!(
+                vm_line_at(&source_map, double.rom_addresses.start - 1),
+                Some(7)
+            );
+            assert_eq!(vm_line_at(&source_map, double.rom_addresses.start), Some(9));
+            let end = source_map.last().unwrap().rom_addresses.end;
+            assert_eq!(vm_line_at(&source_map, end), None);
+        }
+    }
+
+    #[test]
+    fn writes_entries_as_tab_separated_lines() {
+        let entries = [
+            SourceMapEntry {
+                asm_lines: 1..4,
+                rom_addresses: 0..2,
+                vm_file: "Main.vm".to_owned(),
+                vm_line_no: 1,
+                fn_name: None,
+            },
+            SourceMapEntry {
+                asm_lines: 4..9,
+                rom_addresses: 2..6,
+                vm_file: "Main.vm".to_owned(),
+                vm_line_no: 2,
+                fn_name: Some("Main.main".to_owned()),
+            },
+        ];
+        let mut out = vec![];
+        write_source_map(&mut out, &entries).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# asm_start\tasm_end\trom_start\trom_end\tvm_file\tvm_line\tfunction\n\
+             1\t4\t0\t2\tMain.vm\t1\t-\n\
+             4\t9\t2\t6\tMain.vm\t2\tMain.main\n"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+// Static variables are the assembler symbols Foo.0, Foo.1, ... of Foo.vm, which the Hack
+// assembler allocates from RAM 16 upward in the order they first appear. Only RAM 16..255
+// is set aside for them, the stack starting at 256.
+pub const STATIC_BASE: i16 = 16;
+pub const STATIC_END: i16 = 256;
+
+// The statics of a VM file, as (index, address) pairs in allocation order
+#[derive(Clone, Debug)]
+pub struct FileStatics {
+    pub vm_file: String,
+    pub statics: Vec<(i16, i16)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StaticAllocation {
+    files: Vec<FileStatics>,
+    addresses: HashMap<String, i16>,
+}
+
+impl StaticAllocation {
+    // The address of a static variable, allocating it on first use
+    pub fn address(&mut self, vm_file: &str, index: i16) -> i16 {
+        let symbol = format!("{}.{}", vm_file, index);
+        if let Some(&address) = self.addresses.get(&symbol) {
+            return address;
+        }
+        let address = STATIC_BASE + self.addresses.len() as i16;
+        self.addresses.insert(symbol, address);
+        match self.files.iter_mut().find(|file| file.vm_file == vm_file) {
+            Some(file) => file.statics.push((index, address)),
+            None => self.files.push(FileStatics {
+                vm_file: vm_file.to_owned(),
+                statics: vec![(index, address)],
+            }),
+        }
+        address
+    }
+
+    pub fn files(&self) -> &[FileStatics] {
+        &self.files
+    }
+
+    pub fn count(&self) -> usize {
+        self.addresses.len()
+    }
+
+    // Names the files whose statics spill into the stack, if any do
+    pub fn overflow_warning(&self) -> Option<String> {
+        let capacity = (STATIC_END - STATIC_BASE) as usize;
+        if self.count() <= capacity {
+            return None;
+        }
+        let spilling: Vec<&str> = self
+            .files
+            .iter()
+            .filter(|file| {
+                file.statics
+                    .iter()
+                    .any(|&(_, address)| address >= STATIC_END)
+            })
+            .map(|file| file.vm_file.as_str())
+            .collect();
+        Some(format!(
+            "{} static variables don't fit in RAM {}..{}, so those of {} overlap the stack",
+            self.count(),
+            STATIC_BASE,
+            STATIC_END - 1,
+            spilling.join(", ")
+        ))
+    }
+}
+
+// One tab-separated line per file, with its address ranges such as "16-18,25"
+pub fn write_static_report<W: Write>(out: W, allocation: &StaticAllocation) -> io::Result<()> {
+    let mut out = io::BufWriter::new(out);
+    writeln!(out, "# file\tstatics\taddresses")?;
+    for file in allocation.files() {
+        let mut addresses: Vec<i16> = file.statics.iter().map(|&(_, address)| address).collect();
+        addresses.sort_unstable();
+        writeln!(
+            out,
+            "{}\t{}\t{}",
+            file.vm_file,
+            addresses.len(),
+            address_ranges(&addresses)
+        )?;
+    }
+    writeln!(out, "# total\t{}", allocation.count())?;
+    out.flush()
+}
+
+fn address_ranges(addresses: &[i16]) -> String {
+    let mut ranges: Vec<(i16, i16)> = vec![];
+    for &address in addresses {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == address => *end = address,
+            _ => ranges.push((address, address)),
+        }
+    }
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect();
+    ranges.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(allocation: &StaticAllocation) -> String {
+        let mut out = vec![];
+        write_static_report(&mut out, allocation).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn allocates_statics_in_order_of_first_use() {
+        let mut allocation = StaticAllocation::default();
+        assert_eq!(allocation.address("Main", 3), 16);
+        assert_eq!(allocation.address("Sys", 0), 17);
+        assert_eq!(allocation.address("Main", 0), 18);
+        assert_eq!(allocation.address("Main", 3), 16);
+        assert_eq!(allocation.count(), 3);
+        assert_eq!(allocation.files()[0].statics, [(3, 16), (0, 18)]);
+    }
+
+    #[test]
+    fn reports_address_ranges_per_file() {
+        let mut allocation = StaticAllocation::default();
+        for index in 0..3 {
+            allocation.address("Main", index);
+        }
+        allocation.address("Sys", 0);
+        allocation.address("Main", 5);
+        allocation.address("Main", 6);
+        assert_eq!(
+            report(&allocation),
+            "# file\tstatics\taddresses\nMain\t5\t16-18,20-21\nSys\t1\t19\n# total\t6\n"
+        );
+        assert_eq!(
+            report(&StaticAllocation::default()),
+            "# file\tstatics\taddresses\n# total\t0\n"
+        );
+    }
+
+    #[test]
+    fn warns_when_statics_overlap_the_stack() {
+        let mut allocation = StaticAllocation::default();
+        for index in 0..200 {
+            allocation.address("Main", index);
+        }
+        for index in 0..40 {
+            allocation.address("Sys", index);
+        }
+        // RAM 16..255 holds exactly 240 words
+        assert_eq!(allocation.overflow_warning(), None);
+
+        allocation.address("Screen", 0);
+        assert_eq!(
+            allocation.overflow_warning().unwrap(),
+            "241 static variables don't fit in RAM 16..255, so those of Screen overlap the stack"
+        );
+    }
+}
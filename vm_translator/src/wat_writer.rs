@@ -0,0 +1,462 @@
+use crate::backend::Backend;
+use crate::code_writer::Options;
+use crate::native::{Symbols, ENTRY_FUNCTION, HALT_FUNCTION, KBD, STACK_BASE};
+use crate::parser::{Command, CommandIter, Operation, Segment};
+use crate::statics::StaticAllocation;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Memory access in words, the RAM taking the first 64KB of the exported memory. Reading
+// the keyboard register calls the host, which returns the key currently pressed.
+const RUNTIME: &str = r#"  (import "env" "keyboard" (func $keyboard (result i32)))
+  (memory (export "memory") 1)
+  (func $peek (param $address i32) (result i32)
+    local.get $address
+    i32.const 0x7fff
+    i32.and
+    i32.const 1
+    i32.shl
+    i32.load16_s)
+  (func $poke (param $address i32) (param $value i32)
+    local.get $address
+    i32.const 0x7fff
+    i32.and
+    i32.const 1
+    i32.shl
+    local.get $value
+    i32.store16)
+  (func $load (param $address i32) (result i32)
+    local.get $address
+    i32.const 0xffff
+    i32.and
+    i32.const KBD
+    i32.eq
+    if (result i32)
+      call $keyboard
+    else
+      local.get $address
+      call $peek
+    end)
+  (func $push (param $value i32)
+    i32.const 0
+    call $peek
+    local.get $value
+    call $poke
+    i32.const 0
+    i32.const 0
+    call $peek
+    i32.const 1
+    i32.add
+    call $poke)
+  (func $pop (result i32)
+    i32.const 0
+    i32.const 0
+    call $peek
+    i32.const 1
+    i32.sub
+    call $poke
+    i32.const 0
+    call $peek
+    call $peek)
+"#;
+
+// Translates to the WebAssembly text format. Wasm has no goto, so the code is split into
+// blocks at every label, function and call site, and jumps set the number of the block to
+// run next ($pc) and go back to a br_table dispatching on it. The exported run function
+// returns once the program halts, leaving the screen in memory.
+pub struct WatWriter<W: Write> {
+    writer: BufWriter<W>,
+    options: Options,
+    symbols: Symbols,
+    // Code of each block, in program order
+    blocks: Vec<(usize, String)>,
+}
+
+impl<W: Write> WatWriter<W> {
+    pub fn new(out: W, options: Options) -> Self {
+        let mut wat_writer = WatWriter {
+            writer: BufWriter::new(out),
+            options,
+            symbols: Symbols::default(),
+            blocks: vec![],
+        };
+        let entry_id = wat_writer.symbols.entry_id();
+        wat_writer.start_block(entry_id);
+        wat_writer.instructions(&[
+            "i32.const 0",
+            &format!("i32.const {}", STACK_BASE),
+            "call $poke",
+        ]);
+        wat_writer.generate_call(ENTRY_FUNCTION, 0);
+        wat_writer.instruction("return");
+        wat_writer
+    }
+
+    fn start_block(&mut self, id: usize) {
+        self.blocks.push((id, String::new()));
+    }
+
+    fn instruction(&mut self, instruction: &str) {
+        let code = &mut self.blocks.last_mut().unwrap().1;
+        code.push_str("      ");
+        code.push_str(instruction);
+        code.push('\n');
+    }
+
+    fn instructions(&mut self, instructions: &[&str]) {
+        for instruction in instructions {
+            self.instruction(instruction);
+        }
+    }
+
+    fn jump(&mut self, id: usize) {
+        self.instructions(&[
+            &format!("i32.const {}", id),
+            "local.set $pc",
+            "br $dispatch",
+        ]);
+    }
+
+    fn generate_command(&mut self, command: &Command) {
+        match command {
+            Command::Alu(operation) => self.generate_operation(*operation),
+            Command::Push { segment, index } => {
+                self.generate_read_segment(*segment, *index);
+                self.instruction("call $push");
+            }
+            Command::Pop { segment, index } => {
+                self.generate_segment_address(*segment, *index);
+                self.instructions(&["call $pop", "call $poke"]);
+            }
+            Command::Label { label } => {
+                let id = self.symbols.label_id(label);
+                self.start_block(id);
+            }
+            Command::Goto { label } => {
+                let id = self.symbols.label_id(label);
+                if self.symbols.is_halt_loop(id) {
+                    self.instruction("return");
+                } else {
+                    self.jump(id);
+                }
+            }
+            Command::IfGoto { label } => {
+                let id = self.symbols.label_id(label);
+                self.instructions(&["call $pop", "if"]);
+                self.jump(id);
+                self.instruction("end");
+            }
+            Command::Function { name, local_count } => {
+                let id = self.symbols.function_id(name);
+                self.start_block(id);
+                self.instruction(&format!(";; function {}", name));
+                for _ in 0..*local_count {
+                    self.instructions(&["i32.const 0", "call $push"]);
+                }
+            }
+            Command::Call { name, args_count } => self.generate_call(name, *args_count),
+            Command::Return => self.generate_return(),
+        }
+    }
+
+    fn generate_operation(&mut self, operation: Operation) {
+        let instructions: &[&str] = match operation {
+            Operation::Neg => &["i32.const 0", "local.get $x", "i32.sub"],
+            Operation::Not => &["local.get $x", "i32.const -1", "i32.xor"],
+            Operation::Inc => &["local.get $x", "i32.const 1", "i32.add"],
+            Operation::Dec => &["local.get $x", "i32.const 1", "i32.sub"],
+            Operation::Add => &["local.get $x", "local.get $y", "i32.add"],
+            Operation::Sub => &["local.get $x", "local.get $y", "i32.sub"],
+            Operation::And => &["local.get $x", "local.get $y", "i32.and"],
+            Operation::Or => &["local.get $x", "local.get $y", "i32.or"],
+            Operation::Mul => &["local.get $x", "local.get $y", "i32.mul"],
+            Operation::Eq
+            | Operation::Gt
+            | Operation::Lt
+            | Operation::Le
+            | Operation::Ge
+            | Operation::Ne => &[
+                "i32.const 0",
+                "local.get $x",
+                "local.get $y",
+                comparison(operation),
+                "i32.sub",
+            ],
+            // Wrapping on overflow, as only the low 16 bits are stored
+            Operation::Div | Operation::Mod => &[
+                "local.get $y",
+                "if (result i32)",
+                "local.get $x",
+                "local.get $y",
+                if operation == Operation::Div {
+                    "i32.div_s"
+                } else {
+                    "i32.rem_s"
+                },
+                "else",
+                "i32.const 0",
+                "end",
+            ],
+            Operation::Shl | Operation::Shr => &[
+                "local.get $x",
+                "i32.const 0xffff",
+                "i32.and",
+                "local.get $y",
+                if operation == Operation::Shl {
+                    "i32.shl"
+                } else {
+                    "i32.shr_u"
+                },
+                "i32.const 0",
+                "local.get $y",
+                "i32.const 0xffff",
+                "i32.and",
+                "i32.const 16",
+                "i32.lt_u",
+                "select",
+            ],
+        };
+        if !matches!(
+            operation,
+            Operation::Neg | Operation::Not | Operation::Inc | Operation::Dec
+        ) {
+            self.instructions(&["call $pop", "local.set $y"]);
+        }
+        self.instructions(&["call $pop", "local.set $x"]);
+        self.instructions(instructions);
+        self.instruction("call $push");
+    }
+
+    fn generate_read_segment(&mut self, segment: Segment, index: i16) {
+        match segment {
+            Segment::Constant => self.instruction(&format!("i32.const {}", index)),
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                self.generate_segment_address(segment, index);
+                self.instruction("call $load");
+            }
+            _ => {
+                self.generate_segment_address(segment, index);
+                self.instruction("call $peek");
+            }
+        }
+    }
+
+    fn generate_segment_address(&mut self, segment: Segment, index: i16) {
+        let address = match segment {
+            Segment::Local => 1,
+            Segment::Argument => 2,
+            Segment::This => 3,
+            Segment::That => 4,
+            Segment::Static => self.symbols.static_address(index),
+            Segment::Temp => 5 + index,
+            Segment::Pointer => 3 + index,
+            Segment::Constant => panic!("Cannot pop to constant"),
+        };
+        self.instruction(&format!("i32.const {}", address));
+        if let Segment::Local | Segment::Argument | Segment::This | Segment::That = segment {
+            self.instructions(&["call $peek", &format!("i32.const {}", index), "i32.add"]);
+        }
+    }
+
+    fn generate_call(&mut self, name: &str, args_count: i16) {
+        if name == HALT_FUNCTION {
+            self.instruction("return");
+            return;
+        }
+        let return_site = self.symbols.next_return_site();
+        let id = self.symbols.function_id(name);
+        self.instruction(&format!(";; call {}", name));
+        self.instructions(&[&format!("i32.const {}", return_site), "call $push"]);
+        for pointer in 1..=4 {
+            self.instructions(&[
+                &format!("i32.const {}", pointer),
+                "call $peek",
+                "call $push",
+            ]);
+        }
+        self.instructions(&[
+            "i32.const 2",
+            "i32.const 0",
+            "call $peek",
+            &format!("i32.const {}", args_count + 5),
+            "i32.sub",
+            "call $poke",
+            "i32.const 1",
+            "i32.const 0",
+            "call $peek",
+            "call $poke",
+        ]);
+        self.jump(id);
+        self.start_block(return_site);
+    }
+
+    fn generate_return(&mut self) {
+        self.instructions(&[
+            "i32.const 1",
+            "call $peek",
+            "local.set $frame",
+            "local.get $frame",
+            "i32.const 5",
+            "i32.sub",
+            "call $peek",
+            "local.set $pc",
+            "i32.const 2",
+            "call $peek",
+            "call $pop",
+            "call $poke",
+            "i32.const 0",
+            "i32.const 2",
+            "call $peek",
+            "i32.const 1",
+            "i32.add",
+            "call $poke",
+        ]);
+        // THAT, THIS, ARG and LCL, from the end of the frame
+        for offset in 1..=4 {
+            self.instructions(&[
+                &format!("i32.const {}", 5 - offset),
+                "local.get $frame",
+                &format!("i32.const {}", offset),
+                "i32.sub",
+                "call $peek",
+                "call $poke",
+            ]);
+        }
+        self.instruction("br $dispatch");
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.writer.write_all(code.as_bytes()).unwrap();
+    }
+}
+
+impl<W: Write> Backend for WatWriter<W> {
+    fn write_code(&mut self, vm_file_path: &Path, commands: CommandIter<'_>) {
+        self.symbols.start_file(vm_file_path);
+        let vm_file_name = vm_file_path.file_name().unwrap().to_string_lossy();
+        for command in commands {
+            let source_command = command.unwrap();
+            if self.options.annotate {
+                self.instruction(&format!(
+                    ";; {}:{} {}",
+                    vm_file_name, source_command.line_no, source_command.command
+                ));
+            }
+            self.generate_command(&source_command.command);
+            self.symbols.track(&source_command.command);
+        }
+    }
+
+    // Block i of the program ends with the ith innermost `end`, so that `br $b{i}` runs it.
+    // Ids of labels that are never defined, or of anything other than a return site being
+    // returned to, stop the program.
+    fn finish(&mut self) {
+        self.instruction("return");
+        let blocks = std::mem::take(&mut self.blocks);
+        let positions: HashMap<usize, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(position, &(id, _))| (id, position))
+            .collect();
+        let targets: Vec<String> = (0..self.symbols.label_count())
+            .map(|id| match positions.get(&id) {
+                Some(position) => format!("$b{}", position),
+                None => "$halt".to_owned(),
+            })
+            .collect();
+
+        self.emit("(module\n");
+        self.emit(&RUNTIME.replace("KBD", &KBD.to_string()));
+        self.emit("  (func (export \"run\")\n");
+        self.emit("    (local $pc i32) (local $x i32) (local $y i32) (local $frame i32)\n");
+        self.emit(&format!(
+            "    i32.const {}\n    local.set $pc\n",
+            blocks[0].0
+        ));
+        self.emit("    loop $dispatch\n    block $halt\n");
+        for position in (0..blocks.len()).rev() {
+            self.emit(&format!("    block $b{}\n", position));
+        }
+        self.emit(&format!(
+            "      local.get $pc\n      br_table {} $halt\n",
+            targets.join(" ")
+        ));
+        for (position, (_, code)) in blocks.iter().enumerate() {
+            self.emit(&format!("    end ;; $b{}\n", position));
+            self.emit(code);
+        }
+        self.emit("    end ;; $halt\n    end ;; $dispatch\n  )\n)\n");
+        self.writer.flush().unwrap();
+    }
+
+    fn statics(&self) -> &StaticAllocation {
+        self.symbols.statics()
+    }
+}
+
+// Comparisons give 1 for true, which is negated to get the VM's true
+fn comparison(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Eq => "i32.eq",
+        Operation::Gt => "i32.gt_s",
+        Operation::Lt => "i32.lt_s",
+        Operation::Le => "i32.le_s",
+        Operation::Ge => "i32.ge_s",
+        Operation::Ne => "i32.ne",
+        _ => panic!("Unexpected operation {}", operation),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_program;
+
+    fn wat() -> String {
+        let mut wat = vec![];
+        test_program::translate(WatWriter::new(&mut wat, Options::default()));
+        String::from_utf8(wat).unwrap()
+    }
+
+    #[test]
+    fn translates_program() {
+        assert_eq!(wat(), include_str!("../testdata/Program.wat"));
+    }
+
+    // Checks the nesting of the s-expressions, and of the blocks within function bodies
+    #[test]
+    fn nests_program_properly() {
+        let wat = wat();
+        let code: String = wat
+            .lines()
+            .map(|line| line.split(";;").next().unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut depth = 0;
+        for ch in code.chars() {
+            match ch {
+                '(' => depth += 1,
+                ')' => {
+                    assert!(depth > 0, "unbalanced ')'");
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(depth, 0);
+
+        let mut blocks = 0;
+        for word in code.split(|ch: char| ch.is_whitespace() || ch == '(' || ch == ')') {
+            match word {
+                "block" | "loop" | "if" => blocks += 1,
+                "end" => {
+                    assert!(blocks > 0, "unmatched end");
+                    blocks -= 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(blocks, 0);
+    }
+}
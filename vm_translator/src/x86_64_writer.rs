@@ -0,0 +1,480 @@
+use crate::backend::Backend;
+use crate::code_writer::Options;
+use crate::native::{
+    reverse_bits, Symbols, ENTRY_FUNCTION, HALT_FUNCTION, KBD, NEW_LINE, SCREEN, SCREEN_HEIGHT,
+    SCREEN_WIDTH, STACK_BASE,
+};
+use crate::parser::{Command, CommandIter, Operation, Segment};
+use crate::statics::StaticAllocation;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Runtime included in every program, for Linux without libc. rbx holds the address of the
+// RAM throughout. The keyboard and the screen work as with the C target.
+const RUNTIME: &str = r#"    .intel_syntax noprefix
+
+    .macro PUSH_EAX
+    movzx ecx, word ptr [rbx]
+    and ecx, 0x7fff
+    mov [rbx + rcx * 2], ax
+    inc word ptr [rbx]
+    .endm
+
+    .macro POP_EAX
+    dec word ptr [rbx]
+    movzx ecx, word ptr [rbx]
+    and ecx, 0x7fff
+    movsx eax, word ptr [rbx + rcx * 2]
+    .endm
+
+    # r9 = address of the word on top of the stack
+    .macro TOP_R9
+    movzx ecx, word ptr [rbx]
+    dec ecx
+    and ecx, 0x7fff
+    lea r9, [rbx + rcx * 2]
+    .endm
+
+    # eax = RAM[eax], clobbering ecx
+    .macro PEEK_EAX
+    and eax, 0x7fff
+    movsx eax, word ptr [rbx + rax * 2]
+    .endm
+
+    .bss
+ram:
+    .skip 65536
+screen_buffer:
+    .skip SCREEN_BYTES
+key_buffer:
+    .skip 1
+key_down:
+    .skip 1
+
+    .section .rodata
+pbm_header:
+    .ascii "P4\n"
+    .ascii "SCREEN_WIDTH SCREEN_HEIGHT\n"
+pbm_header_end:
+
+    .text
+# Reads the memory at eax, which may be the keyboard register, into eax
+vm_load:
+    cmp ax, KBD
+    je vm_keyboard
+    PEEK_EAX
+    ret
+
+vm_keyboard:
+    cmp byte ptr [rip + key_down], 0
+    je 1f
+    mov byte ptr [rip + key_down], 0
+    xor eax, eax
+    ret
+1:
+    xor eax, eax
+    xor edi, edi
+    lea rsi, [rip + key_buffer]
+    mov edx, 1
+    syscall
+    cmp rax, 1
+    jne vm_halt
+    mov byte ptr [rip + key_down], 1
+    movzx eax, byte ptr [rip + key_buffer]
+    cmp eax, 10
+    jne 2f
+    mov eax, NEW_LINE
+2:
+    ret
+
+# Writes the screen to stdout as a PBM image and exits
+vm_halt:
+    lea rsi, [rip + pbm_header]
+    mov edx, offset pbm_header_end - pbm_header
+    call write_all
+    lea rsi, [rbx + SCREEN * 2]
+    lea rdi, [rip + screen_buffer]
+    lea r8, [rip + reversed_bits]
+    xor ecx, ecx
+1:
+    movzx eax, byte ptr [rsi + rcx]
+    mov al, [r8 + rax]
+    mov [rdi + rcx], al
+    inc ecx
+    cmp ecx, SCREEN_BYTES
+    jb 1b
+    mov rsi, rdi
+    mov edx, SCREEN_BYTES
+    call write_all
+    mov eax, 60
+    xor edi, edi
+    syscall
+
+# Writes rdx bytes from rsi to stdout
+write_all:
+    test rdx, rdx
+    jz 1f
+    mov r12, rdx
+    mov eax, 1
+    mov edi, 1
+    syscall
+    test rax, rax
+    jle 1f
+    add rsi, rax
+    mov rdx, r12
+    sub rdx, rax
+    jmp write_all
+1:
+    ret
+"#;
+
+// Translates to x86-64 assembly for the GNU assembler, to be linked into a static
+// executable with `as` and `ld`. Return addresses are call site numbers, looked up in a
+// table of code addresses.
+pub struct X86_64Writer<W: Write> {
+    writer: BufWriter<W>,
+    options: Options,
+    symbols: Symbols,
+}
+
+impl<W: Write> X86_64Writer<W> {
+    pub fn new(out: W, options: Options) -> Self {
+        let mut x86_64_writer = X86_64Writer {
+            writer: BufWriter::new(out),
+            options,
+            symbols: Symbols::default(),
+        };
+        x86_64_writer.init();
+        x86_64_writer
+    }
+
+    fn init(&mut self) {
+        let screen_bytes = SCREEN_WIDTH * SCREEN_HEIGHT / 8;
+        let runtime = RUNTIME
+            .replace("SCREEN_BYTES", &screen_bytes.to_string())
+            .replace("SCREEN_WIDTH", &SCREEN_WIDTH.to_string())
+            .replace("SCREEN_HEIGHT", &SCREEN_HEIGHT.to_string())
+            .replace("SCREEN", &SCREEN.to_string())
+            .replace("KBD", &KBD.to_string())
+            .replace("NEW_LINE", &NEW_LINE.to_string());
+        self.emit(&runtime);
+        self.emit("\n    .globl _start\n_start:\n");
+        self.instructions(&[
+            "lea rbx, [rip + ram]",
+            &format!("mov word ptr [rbx], {}", STACK_BASE),
+        ]);
+        self.generate_call(ENTRY_FUNCTION, 0);
+        self.instruction("jmp vm_halt");
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.writer.write_all(code.as_bytes()).unwrap();
+    }
+
+    fn instruction(&mut self, instruction: &str) {
+        self.emit(&format!("    {}\n", instruction));
+    }
+
+    fn instructions(&mut self, instructions: &[&str]) {
+        for instruction in instructions {
+            self.instruction(instruction);
+        }
+    }
+
+    fn generate_command(&mut self, command: &Command) {
+        match command {
+            Command::Alu(operation) => self.generate_operation(*operation),
+            Command::Push { segment, index } => {
+                self.generate_read_segment(*segment, *index);
+                self.instruction("PUSH_EAX");
+            }
+            Command::Pop { segment, index } => {
+                let address = self.segment_address(*segment, *index);
+                self.instructions(&["POP_EAX", &format!("mov {}, ax", address)]);
+            }
+            Command::Label { label } => {
+                let id = self.symbols.label_id(label);
+                self.emit(&format!(".L{}:\n", id));
+            }
+            Command::Goto { label } => {
+                let id = self.symbols.label_id(label);
+                if self.symbols.is_halt_loop(id) {
+                    self.instruction("jmp vm_halt");
+                } else {
+                    self.instruction(&format!("jmp .L{}", id));
+                }
+            }
+            Command::IfGoto { label } => {
+                let id = self.symbols.label_id(label);
+                self.instructions(&["POP_EAX", "test eax, eax", &format!("jnz .L{}", id)]);
+            }
+            Command::Function { name, local_count } => {
+                let id = self.symbols.function_id(name);
+                self.emit(&format!(".L{}: # {}\n", id, name));
+                for _ in 0..*local_count {
+                    self.instructions(&["xor eax, eax", "PUSH_EAX"]);
+                }
+            }
+            Command::Call { name, args_count } => self.generate_call(name, *args_count),
+            Command::Return => self.generate_return(),
+        }
+    }
+
+    fn generate_operation(&mut self, operation: Operation) {
+        let unary = matches!(
+            operation,
+            Operation::Neg | Operation::Not | Operation::Inc | Operation::Dec
+        );
+        if !unary {
+            self.instructions(&["POP_EAX", "mov edx, eax"]);
+        }
+        self.instruction("TOP_R9");
+        let condition = match operation {
+            Operation::Eq => "e",
+            Operation::Gt => "g",
+            Operation::Lt => "l",
+            Operation::Le => "le",
+            Operation::Ge => "ge",
+            Operation::Ne => "ne",
+            _ => "",
+        };
+        if !condition.is_empty() {
+            self.instructions(&[
+                "cmp [r9], dx",
+                &format!("set{} al", condition),
+                "movzx eax, al",
+                "neg eax",
+                "mov [r9], ax",
+            ]);
+            return;
+        }
+        let instructions: &[&str] = match operation {
+            Operation::Neg => &["neg word ptr [r9]"],
+            Operation::Not => &["not word ptr [r9]"],
+            Operation::Inc => &["inc word ptr [r9]"],
+            Operation::Dec => &["dec word ptr [r9]"],
+            Operation::Add => &["add [r9], dx"],
+            Operation::Sub => &["sub [r9], dx"],
+            Operation::And => &["and [r9], dx"],
+            Operation::Or => &["or [r9], dx"],
+            Operation::Mul => &["movsx eax, word ptr [r9]", "imul eax, edx", "mov [r9], ax"],
+            // Wrapping on overflow, as only the low 16 bits are stored
+            Operation::Div => &[
+                "mov ecx, edx",
+                "xor eax, eax",
+                "test ecx, ecx",
+                "jz 1f",
+                "movsx eax, word ptr [r9]",
+                "cdq",
+                "idiv ecx",
+                "1:",
+                "mov [r9], ax",
+            ],
+            Operation::Mod => &[
+                "mov ecx, edx",
+                "xor edx, edx",
+                "test ecx, ecx",
+                "jz 1f",
+                "movsx eax, word ptr [r9]",
+                "cdq",
+                "idiv ecx",
+                "1:",
+                "mov [r9], dx",
+            ],
+            Operation::Shl | Operation::Shr => &[
+                "movzx ecx, dx",
+                "movzx eax, word ptr [r9]",
+                if operation == Operation::Shl {
+                    "shl eax, cl"
+                } else {
+                    "shr eax, cl"
+                },
+                "cmp ecx, 16",
+                "jb 1f",
+                "xor eax, eax",
+                "1:",
+                "mov [r9], ax",
+            ],
+            _ => unreachable!(),
+        };
+        self.instructions(instructions);
+    }
+
+    // Into eax
+    fn generate_read_segment(&mut self, segment: Segment, index: i16) {
+        match segment {
+            Segment::Constant => self.instruction(&format!("mov eax, {}", index)),
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                self.instructions(&[
+                    &format!("movzx eax, word ptr [rbx + {}]", pointer_offset(segment)),
+                    &format!("add eax, {}", index),
+                    "call vm_load",
+                ]);
+            }
+            _ => {
+                let address = self.segment_address(segment, index);
+                self.instruction(&format!("movsx eax, {}", address));
+            }
+        }
+    }
+
+    // The memory operand of a segment entry, computing the address into edx if needed
+    fn segment_address(&mut self, segment: Segment, index: i16) -> String {
+        let address = match segment {
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                self.instructions(&[
+                    &format!("movzx edx, word ptr [rbx + {}]", pointer_offset(segment)),
+                    &format!("add edx, {}", index),
+                    "and edx, 0x7fff",
+                ]);
+                return "word ptr [rbx + rdx * 2]".to_owned();
+            }
+            Segment::Static => self.symbols.static_address(index),
+            Segment::Temp => 5 + index,
+            Segment::Pointer => 3 + index,
+            Segment::Constant => panic!("Cannot pop to constant"),
+        };
+        format!("word ptr [rbx + {}]", address * 2)
+    }
+
+    fn generate_call(&mut self, name: &str, args_count: i16) {
+        if name == HALT_FUNCTION {
+            self.instruction("jmp vm_halt");
+            return;
+        }
+        let return_site = self.symbols.next_return_site();
+        let id = self.symbols.function_id(name);
+        self.instruction(&format!("# call {}", name));
+        self.instructions(&[&format!("mov eax, {}", return_site), "PUSH_EAX"]);
+        for pointer in 1..=4 {
+            self.instructions(&[
+                &format!("movzx eax, word ptr [rbx + {}]", pointer * 2),
+                "PUSH_EAX",
+            ]);
+        }
+        self.instructions(&[
+            "movzx eax, word ptr [rbx]",
+            &format!("sub eax, {}", args_count + 5),
+            "mov [rbx + 4], ax",
+            "mov ax, [rbx]",
+            "mov [rbx + 2], ax",
+            &format!("jmp .L{}", id),
+        ]);
+        self.emit(&format!(".L{}:\n", return_site));
+    }
+
+    fn generate_return(&mut self) {
+        self.instructions(&[
+            "# return",
+            "movzx r8d, word ptr [rbx + 2]",
+            "lea eax, [r8 - 5]",
+            "PEEK_EAX",
+            "mov r10d, eax",
+            "POP_EAX",
+            "movzx ecx, word ptr [rbx + 4]",
+            "and ecx, 0x7fff",
+            "mov [rbx + rcx * 2], ax",
+            "movzx eax, word ptr [rbx + 4]",
+            "inc eax",
+            "mov [rbx], ax",
+        ]);
+        // THAT, THIS, ARG and LCL, from the end of the frame
+        for offset in 1..=4 {
+            self.instructions(&[
+                &format!("lea eax, [r8 - {}]", offset),
+                "PEEK_EAX",
+                &format!("mov [rbx + {}], ax", (5 - offset) * 2),
+            ]);
+        }
+        self.instructions(&["mov eax, r10d", "jmp vm_return"]);
+    }
+}
+
+impl<W: Write> Backend for X86_64Writer<W> {
+    fn write_code(&mut self, vm_file_path: &Path, commands: CommandIter<'_>) {
+        self.symbols.start_file(vm_file_path);
+        let vm_file_name = vm_file_path.file_name().unwrap().to_string_lossy();
+        for command in commands {
+            let source_command = command.unwrap();
+            if self.options.annotate {
+                self.instruction(&format!(
+                    "# {}:{} {}",
+                    vm_file_name, source_command.line_no, source_command.command
+                ));
+            }
+            self.generate_command(&source_command.command);
+            self.symbols.track(&source_command.command);
+        }
+    }
+
+    // Return addresses index a table with an entry for every id, only those of call sites
+    // being valid
+    fn finish(&mut self) {
+        let label_count = self.symbols.label_count();
+        self.instruction("jmp vm_halt");
+        self.emit("\nvm_return:\n");
+        self.instructions(&[
+            &format!("cmp eax, {}", label_count),
+            "jae vm_halt",
+            "lea rcx, [rip + return_sites]",
+            "jmp [rcx + rax * 8]",
+        ]);
+        self.emit("\n    .section .rodata\n    .align 8\nreturn_sites:\n");
+        let mut targets = vec!["vm_halt".to_owned(); label_count];
+        for &return_site in self.symbols.return_sites() {
+            targets[return_site] = format!(".L{}", return_site);
+        }
+        for target in targets {
+            self.instruction(&format!(".quad {}", target));
+        }
+        self.emit("reversed_bits:\n");
+        for byte in 0..=255 {
+            self.instruction(&format!(".byte {}", reverse_bits(byte)));
+        }
+        self.writer.flush().unwrap();
+    }
+
+    fn statics(&self) -> &StaticAllocation {
+        self.symbols.statics()
+    }
+}
+
+fn pointer_offset(segment: Segment) -> i16 {
+    match segment {
+        Segment::Local => 2,
+        Segment::Argument => 4,
+        Segment::This => 6,
+        Segment::That => 8,
+        _ => panic!("Unexpected segment {}", segment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_program;
+
+    fn asm() -> String {
+        let mut asm = vec![];
+        test_program::translate(X86_64Writer::new(&mut asm, Options::default()));
+        String::from_utf8(asm).unwrap()
+    }
+
+    #[test]
+    fn translates_program() {
+        assert_eq!(asm(), include_str!("../testdata/Program.s"));
+    }
+
+    // Skipped without the GNU assembler and linker
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn runs_program() {
+        let build: &[&[&str]] = &[
+            &["as", "-o", "program.o", "program.s"],
+            &["ld", "-o", "program", "program.o"],
+        ];
+        if let Some(output) = test_program::build_and_run("program.s", &asm(), build) {
+            assert!(output.starts_with(test_program::SCREEN_START));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+#define SCREEN 16384
+#define KBD 24576
+#define NEW_LINE 128
+#define SCREEN_WIDTH 512
+#define SCREEN_HEIGHT 256
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+// Conversions to int16_t are assumed to wrap around, as they do with common compilers
+static int16_t ram[32768];
+
+#define M(address) ram[(uint16_t)(address) & 0x7fff]
+#define SP ram[0]
+#define LCL ram[1]
+#define ARG ram[2]
+#define THIS ram[3]
+#define THAT ram[4]
+#define PUSH(value) (M(SP) = (value), SP++)
+#define POP() (SP--, M(SP))
+#define TOP M(SP - 1)
+
+static void halt(void) {
+    printf("P4\n%d %d\n", SCREEN_WIDTH, SCREEN_HEIGHT);
+    for (int address = SCREEN; address < KBD; address++) {
+        uint16_t word = (uint16_t)ram[address];
+        for (int byte = 0; byte < 2; byte++) {
+            int pixels = 0;
+            for (int bit = 0; bit < 8; bit++) {
+                pixels |= ((word >> (byte * 8 + bit)) & 1) << (7 - bit);
+            }
+            putchar(pixels);
+        }
+    }
+    exit(0);
+}
+
+static int key_down;
+
+static int16_t keyboard(void) {
+    if (key_down) {
+        key_down = 0;
+        return 0;
+    }
+    int key = getchar();
+    if (key == EOF) {
+        halt();
+    }
+    key_down = 1;
+    return key == '\n' ? NEW_LINE : key;
+}
+
+// Reads memory through a pointer, which may point at the keyboard
+static int16_t load(int address) {
+    return (uint16_t)address == KBD ? keyboard() : M(address);
+}
+
+int main(void) {
+    int16_t y, frame;
+    int return_site;
+    SP = 256;
+    PUSH(0);
+    PUSH(LCL); PUSH(ARG); PUSH(THIS); PUSH(THAT);
+    ARG = SP - 5;
+    LCL = SP;
+    goto L1; // Sys.init
+L0:;
+    halt();
+L2: // Main.multiply
+    PUSH(0);
+L3:;
+    PUSH(load(ARG + 1));
+    PUSH(0);
+    y = POP(); TOP = -(TOP == y);
+    if (POP()) goto L4;
+    PUSH(load(LCL + 0));
+    PUSH(load(ARG + 0));
+    y = POP(); TOP = TOP + y;
+    y = POP(); M(LCL + 0) = y;
+    PUSH(load(ARG + 1));
+    PUSH(1);
+    y = POP(); TOP = TOP - y;
+    y = POP(); M(ARG + 1) = y;
+    goto L3;
+L4:;
+    PUSH(load(LCL + 0));
+    frame = LCL;
+    return_site = M(frame - 5);
+    y = POP(); M(ARG) = y;
+    SP = ARG + 1;
+    THAT = M(frame - 1);
+    THIS = M(frame - 2);
+    ARG = M(frame - 3);
+    LCL = M(frame - 4);
+    goto dispatch;
+L1: // Sys.init
+    PUSH(16384);
+    y = POP(); ram[4] = y;
+    PUSH(6);
+    PUSH(7);
+    PUSH(5);
+    PUSH(LCL); PUSH(ARG); PUSH(THIS); PUSH(THAT);
+    ARG = SP - 7;
+    LCL = SP;
+    goto L2; // Main.multiply
+L5:;
+    y = POP(); M(THAT + 0) = y;
+    PUSH(0);
+    frame = LCL;
+    return_site = M(frame - 5);
+    y = POP(); M(ARG) = y;
+    SP = ARG + 1;
+    THAT = M(frame - 1);
+    THIS = M(frame - 2);
+    ARG = M(frame - 3);
+    LCL = M(frame - 4);
+    goto dispatch;
+    halt();
+dispatch:
+    switch (return_site) {
+    case 0:
+        goto L0;
+    case 5:
+        goto L5;
+    }
+    halt();
+}
@@ -0,0 +1,326 @@
+(module
+  (import "env" "keyboard" (func $keyboard (result i32)))
+  (memory (export "memory") 1)
+  (func $peek (param $address i32) (result i32)
+    local.get $address
+    i32.const 0x7fff
+    i32.and
+    i32.const 1
+    i32.shl
+    i32.load16_s)
+  (func $poke (param $address i32) (param $value i32)
+    local.get $address
+    i32.const 0x7fff
+    i32.and
+    i32.const 1
+    i32.shl
+    local.get $value
+    i32.store16)
+  (func $load (param $address i32) (result i32)
+    local.get $address
+    i32.const 0xffff
+    i32.and
+    i32.const 24576
+    i32.eq
+    if (result i32)
+      call $keyboard
+    else
+      local.get $address
+      call $peek
+    end)
+  (func $push (param $value i32)
+    i32.const 0
+    call $peek
+    local.get $value
+    call $poke
+    i32.const 0
+    i32.const 0
+    call $peek
+    i32.const 1
+    i32.add
+    call $poke)
+  (func $pop (result i32)
+    i32.const 0
+    i32.const 0
+    call $peek
+    i32.const 1
+    i32.sub
+    call $poke
+    i32.const 0
+    call $peek
+    call $peek)
+  (func (export "run")
+    (local $pc i32) (local $x i32) (local $y i32) (local $frame i32)
+    i32.const 0
+    local.set $pc
+    loop $dispatch
+    block $halt
+    block $b6
+    block $b5
+    block $b4
+    block $b3
+    block $b2
+    block $b1
+    block $b0
+      local.get $pc
+      br_table $b0 $b1 $b5 $b2 $b3 $b4 $b6 $halt
+    end ;; $b0
+      i32.const 0
+      i32.const 256
+      call $poke
+      ;; call Sys.init
+      i32.const 1
+      call $push
+      i32.const 1
+      call $peek
+      call $push
+      i32.const 2
+      call $peek
+      call $push
+      i32.const 3
+      call $peek
+      call $push
+      i32.const 4
+      call $peek
+      call $push
+      i32.const 2
+      i32.const 0
+      call $peek
+      i32.const 5
+      i32.sub
+      call $poke
+      i32.const 1
+      i32.const 0
+      call $peek
+      call $poke
+      i32.const 2
+      local.set $pc
+      br $dispatch
+    end ;; $b1
+      return
+    end ;; $b2
+      ;; function Main.multiply
+      i32.const 0
+      call $push
+    end ;; $b3
+      i32.const 2
+      call $peek
+      i32.const 1
+      i32.add
+      call $load
+      call $push
+      i32.const 0
+      call $push
+      call $pop
+      local.set $y
+      call $pop
+      local.set $x
+      i32.const 0
+      local.get $x
+      local.get $y
+      i32.eq
+      i32.sub
+      call $push
+      call $pop
+      if
+      i32.const 5
+      local.set $pc
+      br $dispatch
+      end
+      i32.const 1
+      call $peek
+      i32.const 0
+      i32.add
+      call $load
+      call $push
+      i32.const 2
+      call $peek
+      i32.const 0
+      i32.add
+      call $load
+      call $push
+      call $pop
+      local.set $y
+      call $pop
+      local.set $x
+      local.get $x
+      local.get $y
+      i32.add
+      call $push
+      i32.const 1
+      call $peek
+      i32.const 0
+      i32.add
+      call $pop
+      call $poke
+      i32.const 2
+      call $peek
+      i32.const 1
+      i32.add
+      call $load
+      call $push
+      i32.const 1
+      call $push
+      call $pop
+      local.set $y
+      call $pop
+      local.set $x
+      local.get $x
+      local.get $y
+      i32.sub
+      call $push
+      i32.const 2
+      call $peek
+      i32.const 1
+      i32.add
+      call $pop
+      call $poke
+      i32.const 4
+      local.set $pc
+      br $dispatch
+    end ;; $b4
+      i32.const 1
+      call $peek
+      i32.const 0
+      i32.add
+      call $load
+      call $push
+      i32.const 1
+      call $peek
+      local.set $frame
+      local.get $frame
+      i32.const 5
+      i32.sub
+      call $peek
+      local.set $pc
+      i32.const 2
+      call $peek
+      call $pop
+      call $poke
+      i32.const 0
+      i32.const 2
+      call $peek
+      i32.const 1
+      i32.add
+      call $poke
+      i32.const 4
+      local.get $frame
+      i32.const 1
+      i32.sub
+      call $peek
+      call $poke
+      i32.const 3
+      local.get $frame
+      i32.const 2
+      i32.sub
+      call $peek
+      call $poke
+      i32.const 2
+      local.get $frame
+      i32.const 3
+      i32.sub
+      call $peek
+      call $poke
+      i32.const 1
+      local.get $frame
+      i32.const 4
+      i32.sub
+      call $peek
+      call $poke
+      br $dispatch
+    end ;; $b5
+      ;; function Sys.init
+      i32.const 16384
+      call $push
+      i32.const 4
+      call $pop
+      call $poke
+      i32.const 6
+      call $push
+      i32.const 7
+      call $push
+      ;; call Main.multiply
+      i32.const 6
+      call $push
+      i32.const 1
+      call $peek
+      call $push
+      i32.const 2
+      call $peek
+      call $push
+      i32.const 3
+      call $peek
+      call $push
+      i32.const 4
+      call $peek
+      call $push
+      i32.const 2
+      i32.const 0
+      call $peek
+      i32.const 7
+      i32.sub
+      call $poke
+      i32.const 1
+      i32.const 0
+      call $peek
+      call $poke
+      i32.const 3
+      local.set $pc
+      br $dispatch
+    end ;; $b6
+      i32.const 4
+      call $peek
+      i32.const 0
+      i32.add
+      call $pop
+      call $poke
+      i32.const 0
+      call $push
+      i32.const 1
+      call $peek
+      local.set $frame
+      local.get $frame
+      i32.const 5
+      i32.sub
+      call $peek
+      local.set $pc
+      i32.const 2
+      call $peek
+      call $pop
+      call $poke
+      i32.const 0
+      i32.const 2
+      call $peek
+      i32.const 1
+      i32.add
+      call $poke
+      i32.const 4
+      local.get $frame
+      i32.const 1
+      i32.sub
+      call $peek
+      call $poke
+      i32.const 3
+      local.get $frame
+      i32.const 2
+      i32.sub
+      call $peek
+      call $poke
+      i32.const 2
+      local.get $frame
+      i32.const 3
+      i32.sub
+      call $peek
+      call $poke
+      i32.const 1
+      local.get $frame
+      i32.const 4
+      i32.sub
+      call $peek
+      call $poke
+      br $dispatch
+      return
+    end ;; $halt
+    end ;; $dispatch
+  )
+)